@@ -0,0 +1,649 @@
+use std::{
+    collections::HashMap,
+    num::NonZeroU32,
+    str::FromStr,
+    sync::PoisonError,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use ring::{
+    digest, pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::store::Store;
+
+/// On-disk store of user accounts with their sessions and API tokens
+pub(crate) struct Users {
+    store: Store,
+    /// How long a session lasts after logging in
+    session_ttl: Duration,
+    /// Recently verified passwords by their digest, so that clients sending one with every
+    /// request are not slowed down by hashing it each time
+    verified: std::sync::Mutex<HashMap<String, (User, Instant)>>,
+    /// Failed logins by user name, with the time of the last one
+    failures: std::sync::Mutex<HashMap<String, (u32, Instant)>>,
+    /// Bounds how many passwords are hashed at once, each taking a thread for a while
+    hashing: tokio::sync::Semaphore,
+}
+
+/// What a user may do, each role allowing everything the lower ones do
+#[derive(
+    serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Role {
+    /// Lists tracks and reads their tags
+    ReadOnly,
+    /// Edits, downloads and deletes tracks
+    Editor,
+    /// Manages users, the cache and the trash
+    Admin,
+}
+
+impl Role {
+    fn name(self) -> &'static str {
+        match self {
+            Role::ReadOnly => "read-only",
+            Role::Editor => "editor",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        [Role::ReadOnly, Role::Editor, Role::Admin]
+            .into_iter()
+            .find(|r| r.name() == input)
+            .ok_or_else(|| format!("Unknown role '{}'", input))
+    }
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+pub(crate) struct User {
+    pub(crate) name: String,
+    pub(crate) role: Role,
+}
+
+/// An API token as listed, without its secret
+#[derive(serde::Serialize)]
+pub(crate) struct Token {
+    id: i64,
+    name: String,
+    /// Seconds since the Unix epoch
+    created: i64,
+    used: Option<i64>,
+}
+
+/// How long a verified password is remembered
+const VERIFIED_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Failed logins of a user allowed before they are slowed down
+const FREE_FAILURES: u32 = 3;
+
+/// How long failed logins are counted after the last one
+const FAILURES_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// How long logins of a user are refused after `failures` failed ones, doubling up to a minute
+fn backoff(failures: u32) -> Duration {
+    match failures.saturating_sub(FREE_FAILURES) {
+        0 => Duration::ZERO,
+        over => Duration::from_secs(1 << (over - 1).min(6)).min(Duration::from_secs(60)),
+    }
+}
+
+/// PBKDF2-HMAC-SHA256 rounds of password hashes
+const ROUNDS: NonZeroU32 = NonZeroU32::new(100_000).unwrap();
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(input: &str) -> Option<Vec<u8>> {
+    (0..input.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(input.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn random(len: usize) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![0; len];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| "Random Source Unavailable".to_string())?;
+    Ok(bytes)
+}
+
+/// Sessions and tokens are stored by their digest, so a copy of the store cannot be used to log in
+fn secret_digest(secret: &str) -> String {
+    hex(digest::digest(&digest::SHA256, secret.as_bytes()).as_ref())
+}
+
+/// `pbkdf2-sha256$<rounds>$<salt>$<hash>`, in hex
+fn password_hash(password: &str) -> Result<String, String> {
+    let salt = random(16)?;
+    let mut hash = [0; digest::SHA256_OUTPUT_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        ROUNDS,
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    Ok(format!(
+        "pbkdf2-sha256${}${}${}",
+        ROUNDS,
+        hex(&salt),
+        hex(&hash)
+    ))
+}
+
+fn password_verify(password: &str, stored: &str) -> bool {
+    let mut parts = stored.split('$');
+    let (Some("pbkdf2-sha256"), Some(rounds), Some(salt), Some(hash), None) = (
+        parts.next(),
+        parts.next().and_then(|r| r.parse().ok()),
+        parts.next().and_then(unhex),
+        parts.next().and_then(unhex),
+        parts.next(),
+    ) else {
+        return false;
+    };
+    pbkdf2::verify(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        rounds,
+        &salt,
+        password.as_bytes(),
+        &hash,
+    )
+    .is_ok()
+}
+
+impl Users {
+    pub(crate) fn new(dbfile: String, session_ttl: Duration) -> Result<Self, String> {
+        let store = Store::new(
+            dbfile,
+            "CREATE TABLE IF NOT EXISTS users (
+    name TEXT PRIMARY KEY,
+    password TEXT NOT NULL,
+    role TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS sessions (
+    digest TEXT PRIMARY KEY,
+    user TEXT NOT NULL,
+    expires INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS tokens (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    digest TEXT NOT NULL UNIQUE,
+    user TEXT NOT NULL,
+    name TEXT NOT NULL,
+    created INTEGER NOT NULL,
+    used INTEGER
+);",
+        )?;
+        Ok(Self {
+            store,
+            session_ttl,
+            verified: Default::default(),
+            failures: Default::default(),
+            hashing: tokio::sync::Semaphore::new(
+                std::thread::available_parallelism().map_or(1, |n| n.get()),
+            ),
+        })
+    }
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn session_ttl(&self) -> Duration {
+        self.session_ttl
+    }
+
+    /// Runs `hash` off the async runtime, a few at a time
+    async fn hash<T: Send + 'static>(
+        &self,
+        hash: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<T, String> {
+        let _permit = self.hashing.acquire().await.map_err(|e| e.to_string())?;
+        tokio::task::spawn_blocking(hash)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    fn forget_verified(&self) {
+        self.verified
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// The user of `name` if the password matches
+    pub(crate) async fn verify(&self, name: &str, password: &str) -> Result<Option<User>, String> {
+        let key = secret_digest(&format!("{}\0{}", name, password));
+        {
+            let mut verified = self.verified.lock().unwrap_or_else(PoisonError::into_inner);
+            verified.retain(|_, (_, at)| at.elapsed() < VERIFIED_TTL);
+            if let Some((user, _)) = verified.get(&key) {
+                return Ok(Some(user.clone()));
+            }
+        }
+        {
+            // refused without hashing, so that guessing is slow and cannot tie up every thread
+            let mut failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+            failures.retain(|_, (_, at)| at.elapsed() < FAILURES_TTL);
+            if let Some((failed, at)) = failures.get(name)
+                && at.elapsed() < backoff(*failed)
+            {
+                return Ok(None);
+            }
+        }
+        let user = name.to_string();
+        let stored: Option<(String, String)> = self
+            .store
+            .run(move |client| {
+                client
+                    .query_row(
+                        "SELECT password, role FROM users WHERE name = ?1;",
+                        [user],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .map(Some)
+                    .or_else(|e| match e {
+                        rusqlite::Error::QueryReturnedNoRows => Ok(None),
+                        e => Err(e.to_string()),
+                    })
+            })
+            .await?;
+        let valid = match &stored {
+            Some((stored, _)) => {
+                let (password, stored) = (password.to_string(), stored.clone());
+                self.hash(move || password_verify(&password, &stored))
+                    .await?
+            }
+            None => false,
+        };
+        let mut failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+        let (true, Some((_, role))) = (valid, stored) else {
+            let failed = failures
+                .entry(name.to_string())
+                .or_insert((0, Instant::now()));
+            *failed = (failed.0 + 1, Instant::now());
+            return Ok(None);
+        };
+        failures.remove(name);
+        drop(failures);
+        let user = User {
+            name: name.to_string(),
+            role: role.parse().unwrap_or(Role::ReadOnly),
+        };
+        self.verified
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key, (user.clone(), Instant::now()));
+        Ok(Some(user))
+    }
+
+    pub(crate) async fn list(&self) -> Result<Vec<User>, String> {
+        self.store
+            .run(|client| {
+                let mut query = client
+                    .prepare("SELECT name, role FROM users ORDER BY name;")
+                    .map_err(|e| e.to_string())?;
+                query
+                    .query_map([], |row| {
+                        let role: String = row.get(1)?;
+                        Ok(User {
+                            name: row.get(0)?,
+                            role: role.parse().unwrap_or(Role::ReadOnly),
+                        })
+                    })
+                    .map_err(|e| e.to_string())?
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| e.to_string())
+            })
+            .await
+    }
+
+    /// Adds a user unless one by `name` exists, returning whether it was added
+    pub(crate) async fn create(
+        &self,
+        name: &str,
+        password: &str,
+        role: Role,
+    ) -> Result<bool, String> {
+        let password = password.to_string();
+        let hash = self.hash(move || password_hash(&password)).await??;
+        let name = name.to_string();
+        self.store
+            .run(move |client| {
+                client
+                    .execute(
+                        "INSERT INTO users (name, password, role) VALUES (?1, ?2, ?3)
+ON CONFLICT (name) DO NOTHING;",
+                        (name, hash, role.name()),
+                    )
+                    .map(|n| n > 0)
+                    .map_err(|e| e.to_string())
+            })
+            .await
+    }
+
+    /// Changes the password or role of a user, logging them out, returning whether they exist
+    pub(crate) async fn update(
+        &self,
+        name: &str,
+        password: Option<&str>,
+        role: Option<Role>,
+    ) -> Result<bool, String> {
+        let hash = match password {
+            Some(password) => {
+                let password = password.to_string();
+                Some(self.hash(move || password_hash(&password)).await??)
+            }
+            None => None,
+        };
+        let name = name.to_string();
+        let updated = self
+            .store
+            .run(move |client| {
+                let updated = client
+                    .execute(
+                        "UPDATE users SET password = COALESCE(?2, password), role = COALESCE(?3, role)
+WHERE name = ?1;",
+                        (&name, hash, role.map(Role::name)),
+                    )
+                    .map_err(|e| e.to_string())?;
+                client
+                    .execute("DELETE FROM sessions WHERE user = ?1;", [name])
+                    .map_err(|e| e.to_string())?;
+                Ok(updated)
+            })
+            .await?;
+        self.forget_verified();
+        Ok(updated > 0)
+    }
+
+    /// Removes a user with their sessions and tokens, returning whether they existed
+    pub(crate) async fn delete(&self, name: &str) -> Result<bool, String> {
+        let name = name.to_string();
+        let deleted = self
+            .store
+            .run(move |client| {
+                let transaction = client.transaction().map_err(|e| e.to_string())?;
+                for query in [
+                    "DELETE FROM sessions WHERE user = ?1;",
+                    "DELETE FROM tokens WHERE user = ?1;",
+                ] {
+                    transaction
+                        .execute(query, [&name])
+                        .map_err(|e| e.to_string())?;
+                }
+                let deleted = transaction
+                    .execute("DELETE FROM users WHERE name = ?1;", [&name])
+                    .map_err(|e| e.to_string())?;
+                transaction.commit().map_err(|e| e.to_string())?;
+                Ok(deleted)
+            })
+            .await?;
+        self.forget_verified();
+        Ok(deleted > 0)
+    }
+
+    /// Starts a session if the password matches, returning its secret
+    pub(crate) async fn login(&self, name: &str, password: &str) -> Result<Option<String>, String> {
+        if self.verify(name, password).await?.is_none() {
+            return Ok(None);
+        }
+        let session = hex(&random(32)?);
+        let (digest, name) = (secret_digest(&session), name.to_string());
+        let now = Users::now();
+        let expires = now + self.session_ttl.as_secs() as i64;
+        self.store
+            .run(move |client| {
+                client
+                    .execute("DELETE FROM sessions WHERE expires <= ?1;", [now])
+                    .map_err(|e| e.to_string())?;
+                client
+                    .execute(
+                        "INSERT INTO sessions (digest, user, expires) VALUES (?1, ?2, ?3);",
+                        (digest, name, expires),
+                    )
+                    .map_err(|e| e.to_string())
+            })
+            .await?;
+        Ok(Some(session))
+    }
+
+    pub(crate) async fn logout(&self, session: &str) -> Result<(), String> {
+        let digest = secret_digest(session);
+        self.store
+            .run(move |client| {
+                client
+                    .execute("DELETE FROM sessions WHERE digest = ?1;", [digest])
+                    .map_err(|e| e.to_string())
+            })
+            .await?;
+        Ok(())
+    }
+
+    fn user(
+        client: &rusqlite::Connection,
+        query: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Option<User>, String> {
+        client
+            .query_row(query, params, |row| {
+                let role: String = row.get(1)?;
+                Ok(User {
+                    name: row.get(0)?,
+                    role: role.parse().unwrap_or(Role::ReadOnly),
+                })
+            })
+            .map(Some)
+            .or_else(|e| match e {
+                rusqlite::Error::QueryReturnedNoRows => Ok(None),
+                e => Err(e.to_string()),
+            })
+    }
+
+    /// The user of an unexpired session
+    pub(crate) async fn session(&self, session: &str) -> Result<Option<User>, String> {
+        let digest = secret_digest(session);
+        self.store
+            .run(move |client| {
+                Users::user(
+                    client,
+                    "SELECT users.name, users.role FROM sessions JOIN users ON users.name = sessions.user
+WHERE sessions.digest = ?1 AND sessions.expires > ?2;",
+                    (digest, Users::now()),
+                )
+            })
+            .await
+    }
+
+    /// The user of an API token, marking it as used
+    pub(crate) async fn token(&self, token: &str) -> Result<Option<User>, String> {
+        let digest = secret_digest(token);
+        self.store
+            .run(move |client| {
+                client
+                    .execute(
+                        "UPDATE tokens SET used = ?2 WHERE digest = ?1;",
+                        (&digest, Users::now()),
+                    )
+                    .map_err(|e| e.to_string())?;
+                Users::user(
+                    client,
+                    "SELECT users.name, users.role FROM tokens JOIN users ON users.name = tokens.user
+WHERE tokens.digest = ?1;",
+                    [digest],
+                )
+            })
+            .await
+    }
+
+    /// Issues an API token for `user`, returning its ID and its secret, shown only this once
+    pub(crate) async fn token_create(
+        &self,
+        user: &str,
+        name: &str,
+    ) -> Result<(i64, String), String> {
+        let token = format!("rb_{}", hex(&random(32)?));
+        let (digest, user, name) = (secret_digest(&token), user.to_string(), name.to_string());
+        let id = self
+            .store
+            .run(move |client| {
+                client
+                    .execute(
+                        "INSERT INTO tokens (digest, user, name, created) VALUES (?1, ?2, ?3, ?4);",
+                        (digest, user, name, Users::now()),
+                    )
+                    .map_err(|e| e.to_string())?;
+                Ok(client.last_insert_rowid())
+            })
+            .await?;
+        Ok((id, token))
+    }
+
+    pub(crate) async fn tokens(&self, user: &str) -> Result<Vec<Token>, String> {
+        let user = user.to_string();
+        self.store
+            .run(move |client| {
+                let mut query = client
+                    .prepare(
+                        "SELECT id, name, created, used FROM tokens WHERE user = ?1 ORDER BY id;",
+                    )
+                    .map_err(|e| e.to_string())?;
+                query
+                    .query_map([user], |row| {
+                        Ok(Token {
+                            id: row.get(0)?,
+                            name: row.get(1)?,
+                            created: row.get(2)?,
+                            used: row.get(3)?,
+                        })
+                    })
+                    .map_err(|e| e.to_string())?
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| e.to_string())
+            })
+            .await
+    }
+
+    /// Revokes a token of `user`, returning whether it existed
+    pub(crate) async fn token_delete(&self, user: &str, id: i64) -> Result<bool, String> {
+        let user = user.to_string();
+        self.store
+            .run(move |client| {
+                client
+                    .execute(
+                        "DELETE FROM tokens WHERE user = ?1 AND id = ?2;",
+                        (user, id),
+                    )
+                    .map(|n| n > 0)
+                    .map_err(|e| e.to_string())
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn users() -> Users {
+        Users::new(":memory:".to_string(), Duration::from_secs(60)).unwrap()
+    }
+
+    #[test]
+    fn password() {
+        let hash = password_hash("hunter2").unwrap();
+        assert!(password_verify("hunter2", &hash));
+        assert!(!password_verify("hunter3", &hash));
+        assert!(!password_verify("hunter2", "hunter2"));
+    }
+
+    #[tokio::test]
+    async fn session() {
+        let users = users();
+        assert!(
+            users
+                .create("alice", "hunter2", Role::Editor)
+                .await
+                .unwrap()
+        );
+        assert!(!users.create("alice", "other", Role::Admin).await.unwrap());
+        assert_eq!(users.login("alice", "wrong").await.unwrap(), None);
+        assert_eq!(users.login("bob", "hunter2").await.unwrap(), None);
+        let session = users.login("alice", "hunter2").await.unwrap().unwrap();
+        let user = users.session(&session).await.unwrap().unwrap();
+        assert_eq!((user.name.as_str(), user.role), ("alice", Role::Editor));
+        users.logout(&session).await.unwrap();
+        assert!(users.session(&session).await.unwrap().is_none());
+
+        let session = users.login("alice", "hunter2").await.unwrap().unwrap();
+        users
+            .update("alice", None, Some(Role::ReadOnly))
+            .await
+            .unwrap();
+        assert!(users.session(&session).await.unwrap().is_none());
+        let user = users.verify("alice", "hunter2").await.unwrap().unwrap();
+        assert_eq!(user.role, Role::ReadOnly);
+        users.update("alice", Some("hunter3"), None).await.unwrap();
+        assert!(users.verify("alice", "hunter2").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn backoff() {
+        let users = users();
+        users
+            .create("alice", "hunter2", Role::Editor)
+            .await
+            .unwrap();
+        for _ in 0..=FREE_FAILURES {
+            assert!(users.verify("alice", "wrong").await.unwrap().is_none());
+        }
+        // refused even with the right password until the backoff ends
+        assert!(users.verify("alice", "hunter2").await.unwrap().is_none());
+        users.failures.lock().unwrap().clear();
+        assert!(users.verify("alice", "hunter2").await.unwrap().is_some());
+        assert_eq!(super::backoff(FREE_FAILURES), Duration::ZERO);
+        assert_eq!(super::backoff(FREE_FAILURES + 2), Duration::from_secs(2));
+        assert_eq!(super::backoff(u32::MAX), Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn token() {
+        let users = users();
+        users.create("alice", "hunter2", Role::Admin).await.unwrap();
+        let (id, token) = users.token_create("alice", "backup").await.unwrap();
+        assert_eq!(
+            users.token(&token).await.unwrap().unwrap().role,
+            Role::Admin
+        );
+        assert!(users.tokens("alice").await.unwrap()[0].used.is_some());
+        assert!(!users.token_delete("bob", id).await.unwrap());
+        assert!(users.token_delete("alice", id).await.unwrap());
+        assert!(users.token(&token).await.unwrap().is_none());
+
+        let (_, token) = users.token_create("alice", "backup").await.unwrap();
+        assert!(users.delete("alice").await.unwrap());
+        assert!(users.token(&token).await.unwrap().is_none());
+        assert!(users.list().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn role() {
+        assert!(Role::Admin > Role::Editor && Role::Editor > Role::ReadOnly);
+        assert_eq!("read-only".parse(), Ok(Role::ReadOnly));
+        assert!("root".parse::<Role>().is_err());
+    }
+}
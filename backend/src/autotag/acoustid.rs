@@ -0,0 +1,189 @@
+use std::{path::Path, sync::Arc, time::Duration};
+
+use crate::{
+    autotag::{AcoustIDConfig, Cache, USER_AGENT, limit::Limiter},
+    util,
+};
+
+/// Identifies recordings by their Chromaprint fingerprint
+pub(super) struct AcoustID {
+    client: reqwest::Client,
+    url: String,
+    key: String,
+    fpcalc: String,
+    score: f64,
+    limiter: Limiter,
+    cache: Arc<Cache>,
+    ttl: Duration,
+}
+
+/// Lookups per second the AcoustID API allows
+const RATELIMIT: f64 = 3.0;
+
+#[derive(serde::Deserialize)]
+pub(super) struct Fingerprint {
+    pub(super) duration: f64,
+    pub(super) fingerprint: String,
+}
+
+impl AcoustID {
+    pub(super) fn new(
+        cfg: AcoustIDConfig,
+        cache: Arc<Cache>,
+        ttl: Duration,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .user_agent(USER_AGENT)
+                .build()
+                .map_err(|e| e.to_string())?,
+            url: cfg.url.unwrap_or("https://api.acoustid.org/v2".to_string()),
+            key: cfg.key.unwrap_or_default(),
+            fpcalc: cfg.fpcalc.unwrap_or("fpcalc".to_string()),
+            score: cfg.score.unwrap_or(0.5),
+            limiter: Limiter::new(cfg.timeout, Some(cfg.ratelimit.unwrap_or(RATELIMIT))),
+            cache,
+            ttl,
+        })
+    }
+
+    /// Fails unless `fpcalc` can be run, as every fingerprint needs it
+    pub(super) fn installed(&self) -> Result<(), String> {
+        match std::process::Command::new(&self.fpcalc)
+            .arg("-version")
+            .env_clear()
+            .output()
+        {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(format!(
+                "Fingerprinting requires fpcalc, '{}' not found",
+                self.fpcalc
+            )),
+            Err(e) => Err(format!("{}: {}", self.fpcalc, e)),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    async fn fingerprint(&self, file: &Path) -> Result<Fingerprint, String> {
+        let output = tokio::process::Command::new(&self.fpcalc)
+            .arg("-json")
+            .arg(file)
+            .env_clear()
+            .output()
+            .await;
+        match output {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err("Fingerprinting requires fpcalc".to_string())
+            }
+            Err(e) => Err(e.to_string()),
+            Ok(output) if !output.status.success() => {
+                Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+            }
+            Ok(output) => serde_json::from_slice(&output.stdout).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Recordings with a fingerprint like the one of `file`, best matches first
+    pub(super) async fn identify(&self, file: &Path) -> Result<Vec<util::Metadata>, String> {
+        self.lookup(&self.fingerprint(file).await?).await
+    }
+
+    /// Recordings with `fingerprint`, answered from the cache like the queries of sources
+    pub(super) async fn lookup(
+        &self,
+        fingerprint: &Fingerprint,
+    ) -> Result<Vec<util::Metadata>, String> {
+        if self.ttl.is_zero() {
+            return self.limiter.run(self.request(fingerprint)).await;
+        }
+        // fingerprints are kilobytes long, so only their digest is part of the key
+        let query = format!(
+            "{}|{:016x}",
+            fingerprint.duration.round() as u64,
+            util::fnv(fingerprint.fingerprint.as_bytes())
+        );
+        if let Some(resp) = self.cache.get("acoustid", &query).await {
+            return Ok(resp);
+        }
+        let resp = self.limiter.run(self.request(fingerprint)).await?;
+        self.cache.put("acoustid", &query, self.ttl, &resp).await;
+        Ok(resp)
+    }
+
+    async fn request(&self, fingerprint: &Fingerprint) -> Result<Vec<util::Metadata>, String> {
+        let resp = self
+            .client
+            .post(format!("{}/lookup", self.url))
+            .form(&[
+                ("format", "json"),
+                ("client", self.key.as_str()),
+                ("meta", "recordings releasegroups"),
+                (
+                    "duration",
+                    (fingerprint.duration.round() as u64).to_string().as_str(),
+                ),
+                ("fingerprint", fingerprint.fingerprint.as_str()),
+            ])
+            .send()
+            .await
+            .map_err(|e| e.to_string())?
+            .json::<AcoustIDResp>()
+            .await
+            .map_err(|e| e.to_string())?;
+        if let Some(error) = resp.error {
+            return Err(error.message);
+        }
+        let mut results = resp.results;
+        results.sort_by(|a, b| b.score.total_cmp(&a.score));
+        Ok(results
+            .into_iter()
+            .filter(|r| r.score >= self.score)
+            .flat_map(|r| r.recordings)
+            .map(|r| util::Metadata {
+                title: r.title,
+                artists: r.artists.into_iter().map(|a| a.name).collect(),
+                album: r.releasegroups.into_iter().next().map(|g| g.title),
+                mbid: Some(r.id),
+                ..Default::default()
+            })
+            .collect())
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct AcoustIDResp {
+    #[serde(default)]
+    results: Vec<AcoustIDResult>,
+    error: Option<AcoustIDError>,
+}
+
+#[derive(serde::Deserialize)]
+struct AcoustIDError {
+    message: String,
+}
+
+#[derive(serde::Deserialize)]
+struct AcoustIDResult {
+    score: f64,
+    #[serde(default)]
+    recordings: Vec<AcoustIDRecording>,
+}
+
+#[derive(serde::Deserialize)]
+struct AcoustIDRecording {
+    id: String,
+    title: Option<String>,
+    #[serde(default)]
+    artists: Vec<AcoustIDArtist>,
+    #[serde(default)]
+    releasegroups: Vec<AcoustIDReleaseGroup>,
+}
+
+#[derive(serde::Deserialize)]
+struct AcoustIDArtist {
+    name: String,
+}
+
+#[derive(serde::Deserialize)]
+struct AcoustIDReleaseGroup {
+    title: String,
+}
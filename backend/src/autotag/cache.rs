@@ -0,0 +1,247 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+
+use crate::{autotag::MetadataSource, store::Store, util};
+
+/// On-disk store of metadata source responses
+pub(crate) struct Cache {
+    store: Store,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct CacheStats {
+    source: String,
+    entries: i64,
+    expired: i64,
+    bytes: i64,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct CacheEntry {
+    query: String,
+    fetched: i64,
+    expires: i64,
+    results: i64,
+}
+
+impl Cache {
+    pub(crate) fn new(dbfile: String) -> Result<Self, String> {
+        Ok(Self {
+            store: Store::new(
+                dbfile,
+                "CREATE TABLE IF NOT EXISTS responses (
+    source TEXT NOT NULL,
+    query TEXT NOT NULL,
+    fetched INTEGER NOT NULL,
+    expires INTEGER NOT NULL,
+    response BLOB NOT NULL,
+    PRIMARY KEY (source, query)
+);",
+            )?,
+        })
+    }
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default()
+    }
+
+    fn normalize(input: &str) -> String {
+        input
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase()
+    }
+
+    /// Only the fields sources query by are part of the key
+    fn key(meta: &util::Metadata, fuzzy: bool) -> String {
+        let field = |f: &Option<String>| f.as_deref().map(Cache::normalize).unwrap_or_default();
+        format!(
+            "{}|{}|{}|{}|{}|{}|{}|{}|{}|{}",
+            if fuzzy { "fuzzy" } else { "strict" },
+            field(&meta.title),
+            meta.artists
+                .iter()
+                .map(|a| Cache::normalize(a))
+                .collect::<Vec<_>>()
+                .join(";"),
+            field(&meta.album),
+            field(&meta.date.map(|d| d.to_string())),
+            field(&meta.isrc),
+            field(&meta.catalog),
+            field(&meta.barcode),
+            field(&meta.mbid),
+            meta.duration.map(|d| d.to_string()).unwrap_or_default(),
+        )
+    }
+
+    pub(super) async fn get(&self, source: &str, query: &str) -> Option<Vec<util::Metadata>> {
+        let (source, query) = (source.to_string(), query.to_string());
+        let resp = self
+            .store
+            .run(move |client| {
+                client
+                    .query_row(
+                        "SELECT response FROM responses WHERE source = ?1 AND query = ?2 AND expires > ?3;",
+                        (source, query, Cache::now()),
+                        |row| row.get::<_, Vec<u8>>(0),
+                    )
+                    .map_err(|e| e.to_string())
+            })
+            .await
+            .ok()?;
+        serde_sqlite_jsonb::from_slice(resp.as_slice()).ok()
+    }
+
+    pub(super) async fn put(
+        &self,
+        source: &str,
+        query: &str,
+        ttl: Duration,
+        resp: &[util::Metadata],
+    ) {
+        let Ok(resp) = serde_sqlite_jsonb::to_vec(&resp) else {
+            return;
+        };
+        let (source, query) = (source.to_string(), query.to_string());
+        let now = Cache::now();
+        // a failed write only costs a future cache miss
+        let _ = self
+            .store
+            .run(move |client| {
+                client
+                    .execute(
+                        "INSERT OR REPLACE INTO responses (source, query, fetched, expires, response)
+VALUES (?1, ?2, ?3, ?4, ?5);",
+                        (source, query, now, now + ttl.as_secs() as i64, resp),
+                    )
+                    .map_err(|e| e.to_string())
+            })
+            .await;
+    }
+
+    pub(crate) async fn stats(&self) -> Result<Vec<CacheStats>, String> {
+        self.store
+            .run(|client| {
+                let mut query = client
+                    .prepare(
+                        "SELECT
+    source,
+    COUNT(*),
+    SUM(expires <= ?1),
+    SUM(LENGTH(response))
+FROM responses
+GROUP BY source
+ORDER BY source;",
+                    )
+                    .map_err(|e| e.to_string())?;
+                query
+                    .query_map([Cache::now()], |row| {
+                        Ok(CacheStats {
+                            source: row.get(0)?,
+                            entries: row.get(1)?,
+                            expired: row.get(2)?,
+                            bytes: row.get(3)?,
+                        })
+                    })
+                    .map_err(|e| e.to_string())?
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| e.to_string())
+            })
+            .await
+    }
+
+    pub(crate) async fn entries(&self, source: &str) -> Result<Vec<CacheEntry>, String> {
+        let source = source.to_string();
+        self.store
+            .run(move |client| {
+                let mut query = client
+                    .prepare(
+                        "SELECT query, fetched, expires, json_array_length(response)
+FROM responses
+WHERE source = ?1
+ORDER BY fetched DESC;",
+                    )
+                    .map_err(|e| e.to_string())?;
+                query
+                    .query_map([source], |row| {
+                        Ok(CacheEntry {
+                            query: row.get(0)?,
+                            fetched: row.get(1)?,
+                            expires: row.get(2)?,
+                            results: row.get(3)?,
+                        })
+                    })
+                    .map_err(|e| e.to_string())?
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| e.to_string())
+            })
+            .await
+    }
+
+    /// Removes the entries of `source` (or all sources), returning how many were removed
+    pub(crate) async fn purge(&self, source: Option<&str>, expired: bool) -> Result<usize, String> {
+        let source = source.map(str::to_string);
+        self.store
+            .run(move |client| {
+                client
+                    .execute(
+                        "DELETE FROM responses
+WHERE (?1 IS NULL OR source = ?1) AND (NOT ?2 OR expires <= ?3);",
+                        (source, expired, Cache::now()),
+                    )
+                    .map_err(|e| e.to_string())
+            })
+            .await
+    }
+}
+
+/// Wraps a source so identical queries are answered from the [Cache] until they expire
+pub(super) struct Cached<S> {
+    name: &'static str,
+    ttl: Duration,
+    cache: Arc<Cache>,
+    source: S,
+}
+
+impl<S> Cached<S> {
+    pub(super) fn new(name: &'static str, ttl: Duration, cache: Arc<Cache>, source: S) -> Self {
+        Self {
+            name,
+            ttl,
+            cache,
+            source,
+        }
+    }
+}
+
+#[async_trait]
+impl<S: MetadataSource> MetadataSource for Cached<S> {
+    async fn get_track(
+        &self,
+        meta: &util::Metadata,
+        fuzzy: bool,
+    ) -> Result<Vec<util::Metadata>, String> {
+        if self.ttl.is_zero() {
+            return self.source.get_track(meta, fuzzy).await;
+        }
+        let query = Cache::key(meta, fuzzy);
+        if let Some(resp) = self.cache.get(self.name, &query).await {
+            return Ok(resp);
+        }
+        let resp = self.source.get_track(meta, fuzzy).await?;
+        self.cache.put(self.name, &query, self.ttl, &resp).await;
+        Ok(resp)
+    }
+
+    fn fuzzy(&self) -> bool {
+        self.source.fuzzy()
+    }
+}
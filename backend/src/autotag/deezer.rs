@@ -1,39 +1,127 @@
-use crate::{autotag::MetadataSource, util};
+use async_trait::async_trait;
+use futures_util::future::join_all;
+
+use crate::{
+    autotag::{MetadataSource, SourceConfig, rmparens},
+    credit::Credit,
+    util,
+};
 
-#[derive(Default)]
 pub(super) struct Deezer {
     client: reqwest::Client,
+    url: String,
+    details: usize,
+    pages: usize,
 }
 
 impl Deezer {
-    fn rmparens(input: &str) -> String {
-        let mut result = String::new();
-        let mut segment = String::new();
-        let mut depth = 0;
-
-        for ch in input.chars() {
-            match ch {
-                '(' | '[' => {
-                    if depth == 0 {
-                        result.push_str(&segment);
-                        segment.clear();
-                    }
-                    depth += 1;
-                }
-                ')' | ']' => depth -= 1,
-                _ => {
-                    if depth == 0 {
-                        segment.push(ch);
-                    }
-                }
+    pub(super) fn new(cfg: &SourceConfig) -> Result<Self, String> {
+        Ok(Self {
+            client: cfg.client()?,
+            url: cfg
+                .url
+                .clone()
+                .unwrap_or("https://api.deezer.com".to_string()),
+            details: cfg.details.unwrap_or(0),
+            pages: cfg.pages.unwrap_or(1).max(1),
+        })
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> Result<T, String> {
+        let resp = self
+            .client
+            .get(format!("{}{}", self.url, path))
+            .query(query)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?
+            .json::<DeezerResult<T>>()
+            .await
+            .map_err(|e| e.to_string())?;
+        match resp {
+            DeezerResult::Ok(value) => Ok(value),
+            DeezerResult::Err { error } => Err(error.message),
+        }
+    }
+
+    /// Whether `track` is the one described by `meta`
+    fn matches(meta: &util::Metadata, track: &DeezerTrack) -> bool {
+        if let Some(isrc) = &meta.isrc {
+            return track.isrc.eq_ignore_ascii_case(isrc);
+        }
+        Credit::of(meta).matches(&Credit::parse(
+            &track.title,
+            std::slice::from_ref(&track.artist.name),
+        ))
+    }
+
+    /// The `index` parameter of the page after `resp`, if there is one
+    fn next(resp: &DeezerResp) -> Option<String> {
+        let next = reqwest::Url::parse(resp.next.as_ref()?).ok()?;
+        next.query_pairs()
+            .find(|(k, _)| k == "index")
+            .map(|(_, v)| v.into_owned())
+    }
+
+    /// Details of `track` and its album, or its search result if they are unavailable
+    async fn detail(&self, track: DeezerTrack) -> util::Metadata {
+        let (track_path, album_path) = (
+            format!("/track/{}", track.id),
+            format!("/album/{}", track.album.id),
+        );
+        let (detail, album) = tokio::join!(
+            self.get::<DeezerTrackDetail>(&track_path, &[]),
+            self.get::<DeezerAlbumDetail>(&album_path, &[])
+        );
+        let mut meta = Deezer::metadata(track);
+        if let Ok(detail) = detail {
+            let contributors = detail
+                .contributors
+                .into_iter()
+                .filter(|c| c.role.as_deref().is_none_or(|r| r == "Main"))
+                .map(|c| c.name)
+                .collect::<Vec<_>>();
+            if !contributors.is_empty() {
+                meta.artists = contributors;
             }
+            meta.track = detail.track_position;
+            meta.date = detail.release_date.and_then(|d| d.parse().ok());
+            meta.bpm = detail.bpm.filter(|b| *b > 0.0).map(|b| b.round() as u16);
         }
-        result.push_str(&segment);
+        if let Ok(album) = album {
+            meta.album_artists = album.artist.into_iter().map(|a| a.name).collect();
+            meta.tracks = album.nb_tracks;
+            let released = album.release_date.and_then(|d| d.parse().ok());
+            // tracks released before their album keep that date as the original one
+            if meta.date.is_some_and(|d| released.is_some_and(|r| d < r)) {
+                meta.original_date = meta.date;
+            }
+            meta.date = released.or(meta.date);
+            meta.genres = album.genres.data.into_iter().map(|g| g.name).collect();
+            meta.label = album.label;
+            meta.barcode = album.upc;
+            meta.artwork = album.cover_xl;
+        }
+        meta
+    }
 
-        result.trim().to_string()
+    fn metadata(track: DeezerTrack) -> util::Metadata {
+        util::Metadata {
+            title: Some(track.title_short),
+            artists: vec![track.artist.name],
+            album: Some(track.album.title),
+            isrc: Some(track.isrc),
+            duration: u32::try_from(track.duration).ok(),
+            ..Default::default()
+        }
     }
 }
 
+#[async_trait]
 impl MetadataSource for Deezer {
     async fn get_track(
         &self,
@@ -44,7 +132,7 @@ impl MetadataSource for Deezer {
             if let Some(isrc) = &meta.isrc {
                 format!("isrc: \"{}\"", isrc)
             } else if let Some(title) = &meta.title {
-                let title = Deezer::rmparens(title);
+                let title = rmparens(title);
                 if let Some(artist) = meta.artists.first() {
                     format!("{} - {}", artist, title)
                 } else {
@@ -74,30 +162,45 @@ impl MetadataSource for Deezer {
             return Err("Required Fields: title or isrc".to_string());
         }
 
-        let resp = self
-            .client
-            .get("https://api.deezer.com/search/track")
-            .query(&[("q", query)])
-            .send()
-            .await
-            .map_err(|e| e.to_string())?
-            .json::<DeezerResp>()
-            .await
-            .map_err(|e| e.to_string())?;
-        Ok(resp
-            .data
-            .into_iter()
-            .map(|t| util::Metadata {
-                title: Some(t.title_short),
-                artists: vec![t.artist.name],
-                album: Some(t.album.title),
-                isrc: Some(t.isrc),
-                ..Default::default()
-            })
-            .collect())
+        let mut resp = self
+            .get::<DeezerResp>("/search/track", &[("q", query.clone())])
+            .await?;
+        let mut tracks = std::mem::take(&mut resp.data);
+        // later pages are only worth their request while nothing matches
+        for _ in 1..self.pages {
+            if tracks.iter().any(|t| Deezer::matches(meta, t)) {
+                break;
+            }
+            let Some(index) = Deezer::next(&resp) else {
+                break;
+            };
+            resp = self
+                .get::<DeezerResp>("/search/track", &[("q", query.clone()), ("index", index)])
+                .await?;
+            tracks.append(&mut resp.data);
+        }
+        // good matches are the ones worth looking up in detail
+        tracks.sort_by_key(|t| !Deezer::matches(meta, t));
+
+        let rest = tracks.split_off(self.details.min(tracks.len()));
+        let mut results = join_all(tracks.into_iter().map(|t| self.detail(t))).await;
+        results.extend(rest.into_iter().map(Deezer::metadata));
+        Ok(results)
     }
 }
 
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum DeezerResult<T> {
+    Err { error: DeezerError },
+    Ok(T),
+}
+
+#[derive(serde::Deserialize)]
+struct DeezerError {
+    message: String,
+}
+
 #[derive(serde::Deserialize)]
 #[allow(unused)]
 struct DeezerResp {
@@ -175,3 +278,40 @@ struct Album {
     #[serde(rename = "type")]
     album_type: String,
 }
+
+#[derive(serde::Deserialize)]
+struct DeezerTrackDetail {
+    track_position: Option<u16>,
+    release_date: Option<String>,
+    bpm: Option<f64>,
+    #[serde(default)]
+    contributors: Vec<Contributor>,
+}
+
+#[derive(serde::Deserialize)]
+struct Contributor {
+    name: String,
+    role: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct DeezerAlbumDetail {
+    upc: Option<String>,
+    cover_xl: Option<String>,
+    #[serde(default)]
+    genres: Genres,
+    label: Option<String>,
+    nb_tracks: Option<u16>,
+    release_date: Option<String>,
+    artist: Option<Contributor>,
+}
+
+#[derive(serde::Deserialize, Default)]
+struct Genres {
+    data: Vec<Genre>,
+}
+
+#[derive(serde::Deserialize)]
+struct Genre {
+    name: String,
+}
@@ -0,0 +1,280 @@
+use async_trait::async_trait;
+
+use crate::{
+    autotag::{MetadataSource, SourceConfig, limit::Limiter},
+    credit::Credit,
+    date::Date,
+    util,
+};
+
+pub(super) struct Discogs {
+    client: reqwest::Client,
+    url: String,
+    token: String,
+    /// Spaces out each request, as a lookup searches and then fetches several releases
+    limiter: Limiter,
+}
+
+impl Discogs {
+    pub(super) fn new(cfg: &SourceConfig, token: &str) -> Result<Self, String> {
+        Ok(Self {
+            client: cfg.client()?,
+            url: cfg
+                .url
+                .clone()
+                .unwrap_or("https://api.discogs.com".to_string()),
+            token: token.to_string(),
+            limiter: Limiter::new(None, cfg.ratelimit),
+        })
+    }
+
+    /// Discogs disambiguates equally named artists and labels as `Name (2)`
+    fn name(name: String) -> String {
+        match name.rsplit_once(" (") {
+            Some((stripped, n))
+                if n.strip_suffix(')')
+                    .is_some_and(|n| n.chars().all(|c| c.is_ascii_digit())) =>
+            {
+                stripped.to_string()
+            }
+            _ => name,
+        }
+    }
+
+    /// Unknown parts of a date are zeroed, e.g. `2001-00-00`
+    fn date(release: &DiscogsRelease) -> Option<Date> {
+        release
+            .released
+            .as_deref()
+            .and_then(|d| d.parse().ok())
+            .or_else(|| Date::new(u16::try_from(release.year?).ok()?, None, None).ok())
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> Result<T, String> {
+        self.limiter
+            .run(async {
+                self.client
+                    .get(format!("{}{}", self.url, path))
+                    .header(
+                        reqwest::header::AUTHORIZATION,
+                        format!("Discogs token={}", self.token),
+                    )
+                    .query(query)
+                    .send()
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .json::<T>()
+                    .await
+                    .map_err(|e| e.to_string())
+            })
+            .await
+    }
+
+    /// The medium, i.e. disc or side, and number of a track from its position, e.g. `2-3` or `B2`
+    fn position(position: &str) -> Option<(&str, u16)> {
+        let medium = position.trim_end_matches(|c: char| c.is_ascii_digit());
+        let number = position[medium.len()..].parse().ok()?;
+        Some((medium.trim_end_matches(['-', '.']), number))
+    }
+
+    /// Every track of `release`, or only those titled like `title`
+    fn tracks(release: DiscogsRelease, title: Option<&str>) -> Vec<util::Metadata> {
+        let date = Discogs::date(&release);
+        let label = release.labels.first();
+        let barcode = release
+            .identifiers
+            .iter()
+            .find(|i| i.identifier_type == "Barcode")
+            .map(|i| i.value.clone());
+        let artists = release
+            .artists
+            .iter()
+            .map(|a| Discogs::name(a.name.clone()))
+            .collect::<Vec<_>>();
+        let genres = release
+            .genres
+            .iter()
+            .chain(release.styles.iter())
+            .cloned()
+            .collect::<Vec<_>>();
+        let tracklist = release
+            .tracklist
+            .into_iter()
+            .filter(|t| t.track_type == "track")
+            .collect::<Vec<_>>();
+        // numbered by position within their disc or side, or in order if positions are unknown
+        let numbers = tracklist
+            .iter()
+            .enumerate()
+            .map(|(i, t)| match Discogs::position(&t.position) {
+                Some((medium, number)) => (
+                    number,
+                    tracklist
+                        .iter()
+                        .filter(|t| Discogs::position(&t.position).is_some_and(|p| p.0 == medium))
+                        .count() as u16,
+                ),
+                None => (i as u16 + 1, tracklist.len() as u16),
+            })
+            .collect::<Vec<_>>();
+        tracklist
+            .into_iter()
+            .zip(numbers)
+            .filter(|(t, _)| {
+                title.is_none_or(|title| {
+                    Credit::parse(&t.title, &[]).matches(&Credit::parse(title, &[]))
+                })
+            })
+            .map(|(t, (track, total))| util::Metadata {
+                title: Some(t.title),
+                artists: if t.artists.is_empty() {
+                    artists.clone()
+                } else {
+                    t.artists
+                        .into_iter()
+                        .map(|a| Discogs::name(a.name))
+                        .collect()
+                },
+                album: Some(release.title.clone()),
+                track: Some(track),
+                tracks: Some(total),
+                date,
+                genres: genres.clone(),
+                label: label.map(|l| Discogs::name(l.name.clone())),
+                catalog: label.map(|l| l.catno.clone()).filter(|c| c != "none"),
+                barcode: barcode.clone(),
+                ..Default::default()
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl MetadataSource for Discogs {
+    async fn get_track(
+        &self,
+        meta: &util::Metadata,
+        fuzzy: bool,
+    ) -> Result<Vec<util::Metadata>, String> {
+        let mut query = vec![
+            ("type", "release".to_string()),
+            ("per_page", "5".to_string()),
+        ];
+        if fuzzy {
+            if let Some(title) = &meta.title {
+                query.push((
+                    "q",
+                    match meta.artists.first() {
+                        Some(artist) => format!("{} {}", artist, title),
+                        None => title.to_string(),
+                    },
+                ));
+            }
+        } else {
+            if let Some(title) = &meta.title {
+                query.push(("track", title.to_string()));
+            }
+            if let Some(artist) = meta.artists.first() {
+                query.push(("artist", artist.to_string()));
+            }
+            if let Some(album) = &meta.album {
+                query.push(("release_title", album.to_string()));
+            }
+        }
+        if let Some(barcode) = &meta.barcode {
+            query.push(("barcode", barcode.to_string()));
+        }
+        if let Some(catalog) = &meta.catalog {
+            query.push(("catno", catalog.to_string()));
+        }
+        if meta.title.is_none() && meta.barcode.is_none() && meta.catalog.is_none() {
+            return Err("Required Fields: title, barcode or catalog".to_string());
+        }
+
+        let resp = self
+            .get::<DiscogsSearch>("/database/search", &query)
+            .await?;
+        let mut tracks = Vec::new();
+        let (mut loaded, mut failure) = (false, None);
+        // a release that fails to load only drops its own candidates, unless all of them fail
+        for result in resp.results {
+            match self
+                .get::<DiscogsRelease>(&format!("/releases/{}", result.id), &[])
+                .await
+            {
+                Ok(release) => {
+                    loaded = true;
+                    tracks.extend(Discogs::tracks(release, meta.title.as_deref()));
+                }
+                Err(e) => failure = Some(e),
+            }
+        }
+        match failure {
+            Some(e) if !loaded => Err(e),
+            _ => Ok(tracks),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct DiscogsSearch {
+    results: Vec<DiscogsResult>,
+}
+
+#[derive(serde::Deserialize)]
+struct DiscogsResult {
+    id: i64,
+}
+
+#[derive(serde::Deserialize)]
+struct DiscogsRelease {
+    title: String,
+    #[serde(default)]
+    artists: Vec<DiscogsArtist>,
+    year: Option<i64>,
+    released: Option<String>,
+    #[serde(default)]
+    labels: Vec<DiscogsLabel>,
+    #[serde(default)]
+    genres: Vec<String>,
+    #[serde(default)]
+    styles: Vec<String>,
+    #[serde(default)]
+    tracklist: Vec<DiscogsTrack>,
+    #[serde(default)]
+    identifiers: Vec<DiscogsIdentifier>,
+}
+
+#[derive(serde::Deserialize)]
+struct DiscogsArtist {
+    name: String,
+}
+
+#[derive(serde::Deserialize)]
+struct DiscogsLabel {
+    name: String,
+    catno: String,
+}
+
+#[derive(serde::Deserialize)]
+struct DiscogsTrack {
+    title: String,
+    /// Such as `3`, `2-3` or `B2`, empty for headings
+    #[serde(default)]
+    position: String,
+    #[serde(rename = "type_")]
+    track_type: String,
+    #[serde(default)]
+    artists: Vec<DiscogsArtist>,
+}
+
+#[derive(serde::Deserialize)]
+struct DiscogsIdentifier {
+    #[serde(rename = "type")]
+    identifier_type: String,
+    value: String,
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+/// Built-in taxonomy of `genre > parent` lines and `alias = genre` lines
+const DEFAULT: &str = include_str!("genres.txt");
+
+/// Options of the `genres:` configuration
+#[derive(serde::Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct GenreConfig {
+    /// Genres known besides the built-in ones, each with an optional parent
+    genres: HashMap<String, Option<String>>,
+    /// Other names of known genres
+    aliases: HashMap<String, String>,
+    /// Whether to add the parents of each genre
+    parents: Option<bool>,
+    /// Most genres kept for a track
+    max: Option<usize>,
+}
+
+/// Known genres, to which the genres reported by sources are reduced
+pub(crate) struct Genres {
+    /// Every known genre and its parent
+    genres: HashMap<String, Option<String>>,
+    aliases: HashMap<String, String>,
+    parents: bool,
+    max: usize,
+}
+
+impl Genres {
+    pub(crate) fn new(cfg: GenreConfig) -> Result<Self, String> {
+        let mut genres = HashMap::new();
+        let mut aliases = HashMap::new();
+        for line in DEFAULT.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some((alias, genre)) = line.split_once(" = ") {
+                aliases.insert(alias.to_string(), genre.to_string());
+            } else if let Some((genre, parent)) = line.split_once(" > ") {
+                genres.insert(genre.to_string(), Some(parent.to_string()));
+            } else {
+                genres.insert(line.to_string(), None);
+            }
+        }
+        genres.extend(
+            cfg.genres
+                .into_iter()
+                .map(|(genre, parent)| (Genres::key(&genre), parent.map(|p| Genres::key(&p)))),
+        );
+        aliases.extend(
+            cfg.aliases
+                .into_iter()
+                .map(|(alias, genre)| (Genres::key(&alias), Genres::key(&genre))),
+        );
+
+        if let Some((genre, parent)) = genres.iter().find_map(|(genre, parent)| {
+            parent
+                .as_ref()
+                .filter(|p| !genres.contains_key(*p))
+                .map(|p| (genre, p))
+        }) {
+            return Err(format!("Genre '{}': Unknown parent '{}'", genre, parent));
+        }
+        if let Some((alias, genre)) = aliases.iter().find(|(_, g)| !genres.contains_key(*g)) {
+            return Err(format!("Alias '{}': Unknown genre '{}'", alias, genre));
+        }
+        Ok(Self {
+            genres,
+            aliases,
+            parents: cfg.parents.unwrap_or(true),
+            max: cfg.max.unwrap_or(usize::MAX),
+        })
+    }
+
+    /// Genres are compared by their lowercase words
+    fn key(name: &str) -> String {
+        name.split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase()
+    }
+
+    /// The known genres among `names`, each followed by its parents if enabled
+    pub(crate) fn normalize(&self, names: Vec<String>) -> Vec<String> {
+        let mut result: Vec<String> = Vec::new();
+        // sources combine genres like `Hip-Hop/Rap`
+        for name in names.iter().flat_map(|n| n.split('/')) {
+            let key = Genres::key(name);
+            let key = self.aliases.get(&key).unwrap_or(&key);
+            let mut genre = self.genres.get_key_value(key);
+            while let Some((name, parent)) = genre {
+                // its parents were added with it
+                if result.contains(name) {
+                    break;
+                }
+                result.push(name.clone());
+                genre = parent
+                    .as_ref()
+                    .filter(|_| self.parents)
+                    .and_then(|p| self.genres.get_key_value(p));
+            }
+        }
+        result.truncate(self.max);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn genres(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn normalize() {
+        let taxonomy = Genres::new(GenreConfig::default()).unwrap();
+        assert_eq!(
+            taxonomy.normalize(genres(&["French House", "seen live", "Electronica"])),
+            ["french house", "house", "electronic"]
+        );
+        assert_eq!(taxonomy.normalize(genres(&["Hip-Hop/Rap"])), ["hip hop"]);
+    }
+
+    #[test]
+    fn configured() {
+        let taxonomy = Genres::new(GenreConfig {
+            genres: HashMap::from([("Filter House".to_string(), Some("french house".to_string()))]),
+            aliases: HashMap::from([("french touch".to_string(), "french house".to_string())]),
+            parents: Some(false),
+            max: Some(2),
+        })
+        .unwrap();
+        assert_eq!(
+            taxonomy.normalize(genres(&["filter house", "French Touch", "disco"])),
+            ["filter house", "french house"]
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let error = Genres::new(GenreConfig {
+            aliases: HashMap::from([("french touch".to_string(), "filter house".to_string())]),
+            ..Default::default()
+        });
+        assert_eq!(
+            error.err().as_deref(),
+            Some("Alias 'french touch': Unknown genre 'filter house'")
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use async_trait::async_trait;
+
+use crate::{
+    autotag::{MetadataSource, SourceConfig},
+    credit::Credit,
+    util,
+};
+
+pub(super) struct ITunes {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl ITunes {
+    pub(super) fn new(cfg: &SourceConfig) -> Result<Self, String> {
+        Ok(Self {
+            client: cfg.client()?,
+            url: cfg
+                .url
+                .clone()
+                .unwrap_or("https://itunes.apple.com".to_string()),
+        })
+    }
+
+    /// Artwork URLs name their size, and larger ones are served on request
+    fn artwork(url: String) -> String {
+        match url.rsplit_once('/') {
+            Some((base, file)) if file.starts_with("100x100") => {
+                format!("{}/3000x3000bb.jpg", base)
+            }
+            _ => url,
+        }
+    }
+}
+
+#[async_trait]
+impl MetadataSource for ITunes {
+    async fn get_track(
+        &self,
+        meta: &util::Metadata,
+        fuzzy: bool,
+    ) -> Result<Vec<util::Metadata>, String> {
+        let (path, mut query) = if let Some(isrc) = &meta.isrc {
+            ("lookup", vec![("isrc", isrc.to_string())])
+        } else if let Some(upc) = &meta.barcode {
+            ("lookup", vec![("upc", upc.to_string())])
+        } else if let Some(title) = &meta.title {
+            let term = match meta.artists.first() {
+                Some(artist) => format!("{} {}", artist, title),
+                None => title.to_string(),
+            };
+            (
+                "search",
+                vec![("term", term), ("media", "music".to_string())],
+            )
+        } else {
+            return Err("Required Fields: title, isrc or barcode".to_string());
+        };
+        query.push(("entity", "song".to_string()));
+
+        let resp = self
+            .client
+            .get(format!("{}/{}", self.url, path))
+            .query(&query)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?
+            .json::<ITunesResp>()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(resp
+            .results
+            .into_iter()
+            .filter(|t| t.kind.as_deref() == Some("song"))
+            .filter(|t| {
+                // searches by term alone are fuzzy
+                fuzzy
+                    || path == "lookup"
+                    || Credit::of(meta).matches(&Credit::parse(
+                        t.track_name.as_deref().unwrap_or_default(),
+                        &t.artist_name.iter().cloned().collect::<Vec<_>>(),
+                    ))
+            })
+            .map(|t| util::Metadata {
+                title: t.track_name,
+                artists: t.artist_name.into_iter().collect(),
+                album: t.collection_name,
+                track: t.track_number,
+                tracks: t.track_count,
+                date: t.release_date.and_then(|d| d.parse().ok()),
+                genres: t.primary_genre_name.into_iter().collect(),
+                artwork: t.artwork_url100.map(ITunes::artwork),
+                ..Default::default()
+            })
+            .collect())
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ITunesResp {
+    results: Vec<ITunesTrack>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ITunesTrack {
+    kind: Option<String>,
+    track_name: Option<String>,
+    artist_name: Option<String>,
+    collection_name: Option<String>,
+    track_number: Option<u16>,
+    track_count: Option<u16>,
+    release_date: Option<String>,
+    primary_genre_name: Option<String>,
+    artwork_url100: Option<String>,
+}
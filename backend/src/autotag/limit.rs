@@ -0,0 +1,76 @@
+use std::{future::Future, time::Duration};
+
+use async_trait::async_trait;
+use tokio::{sync::Mutex, time::Instant};
+
+use crate::{
+    autotag::{MetadataSource, SourceConfig},
+    util,
+};
+
+/// Spaces out queries and abandons slow ones
+pub(super) struct Limiter {
+    timeout: Option<Duration>,
+    interval: Option<Duration>,
+    next: Mutex<Instant>,
+}
+
+impl Limiter {
+    /// Abandons queries after `timeout` seconds and sends at most `ratelimit` per second
+    pub(super) fn new(timeout: Option<f64>, ratelimit: Option<f64>) -> Self {
+        Self {
+            timeout: timeout.filter(|t| *t > 0.0).map(Duration::from_secs_f64),
+            interval: ratelimit
+                .filter(|r| *r > 0.0)
+                .map(|r| Duration::from_secs_f64(1.0 / r)),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub(super) async fn run<T>(
+        &self,
+        query: impl Future<Output = Result<T, String>>,
+    ) -> Result<T, String> {
+        if let Some(interval) = self.interval {
+            let mut next = self.next.lock().await;
+            tokio::time::sleep_until(*next).await;
+            *next = Instant::now() + interval;
+        }
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, query)
+                .await
+                .map_err(|_| "Timed Out".to_string())?,
+            None => query.await,
+        }
+    }
+}
+
+/// Wraps a source to space out its queries and abandon slow ones
+pub(super) struct Limited<S> {
+    limiter: Limiter,
+    source: S,
+}
+
+impl<S> Limited<S> {
+    pub(super) fn new(cfg: &SourceConfig, source: S) -> Self {
+        Self {
+            limiter: Limiter::new(cfg.timeout, cfg.ratelimit),
+            source,
+        }
+    }
+}
+
+#[async_trait]
+impl<S: MetadataSource> MetadataSource for Limited<S> {
+    async fn get_track(
+        &self,
+        meta: &util::Metadata,
+        fuzzy: bool,
+    ) -> Result<Vec<util::Metadata>, String> {
+        self.limiter.run(self.source.get_track(meta, fuzzy)).await
+    }
+
+    fn fuzzy(&self) -> bool {
+        self.source.fuzzy()
+    }
+}
@@ -1,48 +1,155 @@
-use crate::{autotag::MetadataSource, util};
+use async_trait::async_trait;
+
+use crate::{
+    autotag::{MetadataSource, SourceConfig},
+    util,
+};
+
+/// Which of the lyrics of a record are taken
+#[derive(Clone, Copy, PartialEq)]
+enum Lyrics {
+    Synced,
+    Plain,
+    /// Synced where available, plain otherwise
+    Any,
+}
 
-#[derive(Default)]
 pub(super) struct LRCLib {
     client: reqwest::Client,
+    url: String,
+    lyrics: Lyrics,
 }
 
-impl MetadataSource for LRCLib {
-    async fn get_track(
-        &self,
-        meta: &util::Metadata,
-        _fuzzy: bool, // not supported
-    ) -> Result<Vec<util::Metadata>, String> {
+impl LRCLib {
+    /// LRCLib only matches records within this many seconds of the queried duration
+    const TOLERANCE: f32 = 2.0;
+
+    pub(super) fn new(cfg: &SourceConfig) -> Result<Self, String> {
+        Ok(Self {
+            client: cfg.client()?,
+            url: cfg.url.clone().unwrap_or("https://lrclib.net".to_string()),
+            lyrics: match cfg.lyrics.as_deref() {
+                None | Some("any") => Lyrics::Any,
+                Some("synced") => Lyrics::Synced,
+                Some("plain") => Lyrics::Plain,
+                Some(other) => return Err(format!("Unknown lyrics '{}'", other)),
+            },
+        })
+    }
+
+    /// The record matching `query` exactly, if there is one
+    async fn get(&self, query: &[(&str, String)]) -> Result<Option<LRCResp>, String> {
         let resp = self
             .client
-            .get("https://lrclib.net/api/get")
-            .query(&[
-                (
-                    "track_name",
-                    meta.title
-                        .clone()
-                        .ok_or("Required Field: title")?
-                        .replace(" ", "+"),
-                ),
-                (
-                    "artist_name",
-                    meta.artists
-                        .first()
-                        .ok_or("Required Field: artists")?
-                        .replace(" ", "+"),
-                ),
-            ])
+            .get(format!("{}/api/get", self.url))
+            .query(query)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        resp.json::<LRCResp>()
+            .await
+            .map(Some)
+            .map_err(|e| e.to_string())
+    }
+
+    async fn search(&self, query: &[(&str, String)]) -> Result<Vec<LRCResp>, String> {
+        self.client
+            .get(format!("{}/api/search", self.url))
+            .query(query)
             .send()
             .await
             .map_err(|e| e.to_string())?
-            .json::<LRCResp>()
+            .json::<Vec<LRCResp>>()
             .await
-            .map_err(|e| e.to_string())?;
-        Ok(vec![util::Metadata {
-            title: Some(resp.track_name),
-            artists: vec![resp.artist_name],
-            album: Some(resp.album_name),
-            lyrics: resp.synced_lyrics.or(resp.plain_lyrics),
+            .map_err(|e| e.to_string())
+    }
+
+    /// Search results close to the duration of `meta`, those on its album and nearest first
+    fn select(meta: &util::Metadata, mut records: Vec<LRCResp>) -> Vec<LRCResp> {
+        let distance = |r: &LRCResp| meta.duration.map_or(0.0, |d| (r.duration - d as f32).abs());
+        records.retain(|r| distance(r) <= LRCLib::TOLERANCE);
+        records.sort_by(|a, b| {
+            let album = |r: &LRCResp| {
+                meta.album
+                    .as_ref()
+                    .is_some_and(|album| r.album_name.eq_ignore_ascii_case(album))
+            };
+            album(b)
+                .cmp(&album(a))
+                .then(distance(a).total_cmp(&distance(b)))
+        });
+        records
+    }
+
+    /// Instrumental records are often missing the flag, but never have words
+    fn instrumental(record: &LRCResp) -> bool {
+        record.instrumental
+            || [&record.plain_lyrics, &record.synced_lyrics]
+                .into_iter()
+                .all(|l| l.as_deref().is_none_or(|l| l.trim().is_empty()))
+    }
+
+    fn metadata(&self, record: LRCResp) -> util::Metadata {
+        let lyrics = if LRCLib::instrumental(&record) {
+            None
+        } else {
+            match self.lyrics {
+                Lyrics::Synced => record.synced_lyrics,
+                Lyrics::Plain => record.plain_lyrics,
+                Lyrics::Any => record.synced_lyrics.or(record.plain_lyrics),
+            }
+        };
+        util::Metadata {
+            title: Some(record.track_name),
+            artists: vec![record.artist_name],
+            album: Some(record.album_name),
+            lyrics,
+            duration: Some(record.duration.round() as u32),
             ..Default::default()
-        }])
+        }
+    }
+}
+
+#[async_trait]
+impl MetadataSource for LRCLib {
+    async fn get_track(
+        &self,
+        meta: &util::Metadata,
+        _fuzzy: bool, // not supported
+    ) -> Result<Vec<util::Metadata>, String> {
+        let title = meta.title.clone().ok_or("Required Field: title")?;
+        let artist = meta
+            .artists
+            .first()
+            .cloned()
+            .ok_or("Required Field: artists")?;
+        let mut query = vec![
+            ("track_name", title.clone()),
+            ("artist_name", artist.clone()),
+        ];
+        if let Some(album) = &meta.album {
+            query.push(("album_name", album.to_string()));
+        }
+        if let Some(duration) = meta.duration {
+            query.push(("duration", duration.to_string()));
+        }
+
+        if let Some(record) = self.get(&query).await? {
+            return Ok(vec![self.metadata(record)]);
+        }
+        // differently spelled titles are still found by a keyword search
+        let query = [("q", format!("{} {}", artist, title))];
+        Ok(LRCLib::select(meta, self.search(&query).await?)
+            .into_iter()
+            .map(|r| self.metadata(r))
+            .collect())
+    }
+
+    fn fuzzy(&self) -> bool {
+        false
     }
 }
 
@@ -0,0 +1,483 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    autotag::{
+        MetadataSource, fts_match, normalize,
+        pool::{Pool, Schema},
+    },
+    credit::Credit,
+    date::Date,
+    util,
+};
+
+/// A local copy of the MusicBrainz database, created by `import`
+pub(super) struct MBMirror {
+    pool: Arc<Pool>,
+    /// Votes a tag needs to count as a genre
+    votes: i64,
+}
+
+/// Dump files with the columns taken from each, the table they fill, and whether they must exist
+const TABLES: [(&str, &[usize], &str, bool); 12] = [
+    (
+        "artist_credit_name",
+        &[0, 1, 3],
+        "artist_credit_name (artist_credit, position, name)",
+        true,
+    ),
+    (
+        "recording",
+        &[0, 1, 2, 3, 4],
+        "recording (id, gid, name, artist_credit, length)",
+        true,
+    ),
+    ("isrc", &[1, 2], "isrc (recording, isrc)", true),
+    ("release", &[0, 2, 9], "release (id, name, barcode)", true),
+    (
+        "release_country",
+        &[0, 2, 3, 4],
+        "release_date (release, year, month, day)",
+        true,
+    ),
+    (
+        "release_unknown_country",
+        &[0, 1, 2, 3],
+        "release_date (release, year, month, day)",
+        false,
+    ),
+    (
+        "medium",
+        &[0, 1, 7],
+        "medium (id, release, track_count)",
+        true,
+    ),
+    (
+        "track",
+        &[2, 3, 4],
+        "track (recording, medium, position)",
+        true,
+    ),
+    ("label", &[0, 2], "label (id, name)", false),
+    (
+        "release_label",
+        &[1, 2, 3],
+        "release_label (release, label, catalog_number)",
+        false,
+    ),
+    // part of the derived dump
+    ("tag", &[0, 1], "tag (id, name)", false),
+    (
+        "recording_tag",
+        &[0, 1, 2],
+        "recording_tag (recording, tag, count)",
+        false,
+    ),
+];
+
+const CREATE: &str = "CREATE TABLE artist_credit_name (artist_credit INTEGER, position INTEGER, name TEXT);
+CREATE TABLE recording (id INTEGER PRIMARY KEY, gid TEXT, name TEXT, artist_credit INTEGER, length INTEGER);
+CREATE TABLE isrc (recording INTEGER, isrc TEXT);
+CREATE TABLE release (id INTEGER PRIMARY KEY, name TEXT, barcode TEXT);
+CREATE TABLE release_date (release INTEGER, year INTEGER, month INTEGER, day INTEGER);
+CREATE TABLE medium (id INTEGER PRIMARY KEY, release INTEGER, track_count INTEGER);
+CREATE TABLE track (recording INTEGER, medium INTEGER, position INTEGER);
+CREATE TABLE label (id INTEGER PRIMARY KEY, name TEXT);
+CREATE TABLE release_label (release INTEGER, label INTEGER, catalog_number TEXT);
+CREATE TABLE tag (id INTEGER PRIMARY KEY, name TEXT);
+CREATE TABLE recording_tag (recording INTEGER, tag INTEGER, count INTEGER);
+CREATE VIRTUAL TABLE recording_fts USING fts5(
+    title, artists,
+    content = '',
+    tokenize = 'unicode61 remove_diacritics 2'
+);";
+
+const INDEX: &str =
+    "CREATE INDEX artist_credit_name_artist_credit ON artist_credit_name (artist_credit);
+CREATE INDEX recording_gid ON recording (gid);
+CREATE INDEX recording_name ON recording (name);
+CREATE INDEX isrc_isrc ON isrc (isrc);
+CREATE INDEX isrc_recording ON isrc (recording);
+CREATE INDEX release_date_release ON release_date (release);
+CREATE INDEX track_recording ON track (recording);
+CREATE INDEX release_label_release ON release_label (release);
+CREATE INDEX recording_tag_recording ON recording_tag (recording);";
+
+/// Tables queries rely on, all of which `import` creates
+const SCHEMA: &Schema = &[
+    ("artist_credit_name", &["artist_credit", "position", "name"]),
+    (
+        "recording",
+        &["id", "gid", "name", "artist_credit", "length"],
+    ),
+    ("isrc", &["recording", "isrc"]),
+    ("release", &["id", "name", "barcode"]),
+    ("release_date", &["release", "year", "month", "day"]),
+    ("medium", &["id", "release", "track_count"]),
+    ("track", &["recording", "medium", "position"]),
+    ("label", &["id", "name"]),
+    ("release_label", &["release", "label", "catalog_number"]),
+    ("tag", &["id", "name"]),
+    ("recording_tag", &["recording", "tag", "count"]),
+];
+
+const RECORDING: &str = "SELECT id, gid, name, artist_credit, length FROM recording";
+
+/// Recordings of a strict query by title, before they are narrowed down by artist
+const STRICT_LIMIT: i64 = 25;
+/// Candidates of a fuzzy query
+const FUZZY_LIMIT: i64 = 10;
+
+/// A value of the PostgreSQL text format, where `\N` is null and special characters are escaped
+fn unescape(raw: &str) -> Option<String> {
+    if raw == "\\N" {
+        return None;
+    }
+    let mut value = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => value.push('\t'),
+            Some('n') => value.push('\n'),
+            Some('r') => value.push('\r'),
+            Some(c) => value.push(c),
+            None => value.push('\\'),
+        }
+    }
+    Some(value)
+}
+
+/// Finds `name` in the `mbdump` directory of an extracted dump, or directly in `dump`
+fn dumpfile(dump: &Path, name: &str) -> Option<PathBuf> {
+    [dump.join("mbdump").join(name), dump.join(name)]
+        .into_iter()
+        .find(|f| f.is_file())
+}
+
+/// Creates `dbfile` from the MusicBrainz PostgreSQL dump extracted to `dump`
+pub(crate) fn import(dump: &Path, dbfile: &Path) -> Result<(), String> {
+    // a failed import leaves any previous database in place, and nothing of its own
+    let tmpfile = dbfile.with_added_extension("tmp");
+    if tmpfile.exists() {
+        fs::remove_file(&tmpfile).map_err(|e| e.to_string())?;
+    }
+    let result = import_into(dump, &tmpfile)
+        .and_then(|()| fs::rename(&tmpfile, dbfile).map_err(|e| e.to_string()));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmpfile);
+    }
+    result
+}
+
+fn import_into(dump: &Path, tmpfile: &Path) -> Result<(), String> {
+    let mut client = rusqlite::Connection::open(tmpfile).map_err(|e| e.to_string())?;
+    client
+        .execute_batch("PRAGMA journal_mode = OFF; PRAGMA synchronous = OFF;")
+        .map_err(|e| e.to_string())?;
+    client.execute_batch(CREATE).map_err(|e| e.to_string())?;
+
+    let tx = client.transaction().map_err(|e| e.to_string())?;
+    for (name, columns, table, required) in TABLES {
+        let Some(file) = dumpfile(dump, name) else {
+            if required {
+                return Err(format!("Dump file '{}' missing", name));
+            }
+            eprintln!("Skipping {}, not part of the dump", name);
+            continue;
+        };
+        let mut insert = tx
+            .prepare(&format!(
+                "INSERT INTO {} VALUES ({});",
+                table,
+                vec!["?"; columns.len()].join(", ")
+            ))
+            .map_err(|e| e.to_string())?;
+        let reader = BufReader::new(fs::File::open(&file).map_err(|e| e.to_string())?);
+        let mut rows = 0;
+        for (n, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| e.to_string())?;
+            let fields = line.split('\t').collect::<Vec<_>>();
+            let values = columns
+                .iter()
+                .map(|&i| fields.get(i).map(|f| unescape(f)))
+                .collect::<Option<Vec<_>>>()
+                .ok_or(format!("{} line {}: Too few columns", name, n + 1))?;
+            insert
+                .execute(rusqlite::params_from_iter(values))
+                .map_err(|e| e.to_string())?;
+            rows += 1;
+        }
+        eprintln!("Imported {} rows of {}", rows, name);
+    }
+    tx.execute_batch(INDEX).map_err(|e| e.to_string())?;
+
+    {
+        let mut insert = tx
+            .prepare("INSERT INTO recording_fts (rowid, title, artists) VALUES (?1, ?2, ?3);")
+            .map_err(|e| e.to_string())?;
+        let mut select = tx
+            .prepare(
+                "SELECT recording.id, recording.name, group_concat(artist_credit_name.name, ' ')
+FROM recording
+    JOIN artist_credit_name ON artist_credit_name.artist_credit = recording.artist_credit
+GROUP BY recording.id;",
+            )
+            .map_err(|e| e.to_string())?;
+        let mut rows = select.query([]).map_err(|e| e.to_string())?;
+        while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+            let field = |i| {
+                row.get::<_, Option<String>>(i)
+                    .map(|f| f.as_deref().map(normalize).unwrap_or_default())
+                    .map_err(|e| e.to_string())
+            };
+            insert
+                .execute((
+                    row.get::<_, i64>(0).map_err(|e| e.to_string())?,
+                    field(1)?,
+                    field(2)?,
+                ))
+                .map_err(|e| e.to_string())?;
+        }
+        eprintln!("Indexed recordings for fuzzy search");
+    }
+    tx.commit().map_err(|e| e.to_string())
+}
+
+/// A row of the `recording` table
+struct Recording {
+    id: i64,
+    gid: String,
+    name: Option<String>,
+    artist_credit: i64,
+    length: Option<i64>,
+}
+
+impl MBMirror {
+    pub(super) fn new(dbfile: &str, votes: Option<i64>) -> Result<Self, String> {
+        Ok(Self {
+            pool: Pool::new(dbfile, SCHEMA)?,
+            votes: votes.unwrap_or(1),
+        })
+    }
+
+    fn recordings(
+        client: &rusqlite::Connection,
+        query: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<Recording>, String> {
+        let mut query = client.prepare_cached(query).map_err(|e| e.to_string())?;
+        query
+            .query_map(params, |row| {
+                Ok(Recording {
+                    id: row.get(0)?,
+                    gid: row.get(1)?,
+                    name: row.get(2)?,
+                    artist_credit: row.get(3)?,
+                    length: row.get(4)?,
+                })
+            })
+            .and_then(|rows| rows.collect())
+            .map_err(|e| e.to_string())
+    }
+
+    fn strings(
+        client: &rusqlite::Connection,
+        query: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<String>, String> {
+        let mut query = client.prepare_cached(query).map_err(|e| e.to_string())?;
+        query
+            .query_map(params, |row| row.get(0))
+            .and_then(|rows| rows.collect())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Everything known of `recording`, as it appeared on its earliest release
+    fn metadata(
+        client: &rusqlite::Connection,
+        recording: Recording,
+        votes: i64,
+    ) -> Result<util::Metadata, String> {
+        let mut meta = util::Metadata {
+            title: recording.name,
+            artists: MBMirror::strings(
+                client,
+                "SELECT name FROM artist_credit_name WHERE artist_credit = ?1 ORDER BY position;",
+                [recording.artist_credit],
+            )?,
+            genres: MBMirror::strings(
+                client,
+                "SELECT tag.name FROM recording_tag JOIN tag ON recording_tag.tag = tag.id
+WHERE recording_tag.recording = ?1 AND recording_tag.count >= ?2
+ORDER BY recording_tag.count DESC;",
+                [recording.id, votes],
+            )?,
+            isrc: MBMirror::strings(
+                client,
+                "SELECT isrc FROM isrc WHERE recording = ?1 LIMIT 1;",
+                [recording.id],
+            )?
+            .pop(),
+            duration: recording.length.map(|ms| ((ms + 500) / 1000) as u32),
+            mbid: Some(recording.gid),
+            ..Default::default()
+        };
+
+        let mut release = client
+            .prepare_cached(
+                "SELECT release.id, release.name, release.barcode, track.position, medium.track_count,
+    release_date.year, release_date.month, release_date.day
+FROM track
+    JOIN medium ON track.medium = medium.id
+    JOIN release ON medium.release = release.id
+    LEFT JOIN release_date ON release_date.release = release.id
+WHERE track.recording = ?1
+ORDER BY release_date.year IS NULL, release_date.year, release_date.month, release_date.day
+LIMIT 1;",
+            )
+            .map_err(|e| e.to_string())?;
+        let mut rows = release.query([recording.id]).map_err(|e| e.to_string())?;
+        let Some(row) = rows.next().map_err(|e| e.to_string())? else {
+            return Ok(meta);
+        };
+        let id = row.get::<_, i64>(0).map_err(|e| e.to_string())?;
+        meta.album = row.get(1).ok();
+        meta.barcode = row
+            .get::<_, Option<String>>(2)
+            .ok()
+            .flatten()
+            .filter(|b| !b.is_empty());
+        meta.track = row.get(3).ok();
+        meta.tracks = row.get(4).ok();
+        // the earliest release is the original one
+        meta.date = match (row.get::<_, Option<u16>>(5), row.get(6), row.get(7)) {
+            (Ok(Some(year)), Ok(month), Ok(day)) => Date::new(year, month, day).ok(),
+            _ => None,
+        };
+        meta.original_date = meta.date;
+
+        let mut label = client
+            .prepare_cached(
+                "SELECT label.name, release_label.catalog_number
+FROM release_label LEFT JOIN label ON release_label.label = label.id
+WHERE release_label.release = ?1
+LIMIT 1;",
+            )
+            .map_err(|e| e.to_string())?;
+        if let Some(row) = label
+            .query([id])
+            .and_then(|mut rows| {
+                rows.next()?
+                    .map(|row| Ok((row.get(0)?, row.get(1)?)))
+                    .transpose()
+            })
+            .map_err(|e| e.to_string())?
+        {
+            (meta.label, meta.catalog) = row;
+        }
+        Ok(meta)
+    }
+
+    fn query(
+        client: &rusqlite::Connection,
+        meta: &util::Metadata,
+        fuzzy: bool,
+        votes: i64,
+    ) -> Result<Vec<util::Metadata>, String> {
+        let recordings = if let Some(mbid) = &meta.mbid {
+            MBMirror::recordings(client, &format!("{} WHERE gid = ?1;", RECORDING), [mbid])?
+        } else if let Some(isrc) = &meta.isrc {
+            MBMirror::recordings(
+                client,
+                &format!(
+                    "{} WHERE id IN (SELECT recording FROM isrc WHERE isrc = ?1);",
+                    RECORDING
+                ),
+                [isrc],
+            )?
+        } else if fuzzy && let Some(expr) = fts_match(meta) {
+            MBMirror::recordings(
+                client,
+                &format!(
+                    "SELECT recording.id, gid, name, artist_credit, length
+FROM recording JOIN (
+    SELECT rowid, rank FROM recording_fts
+    WHERE recording_fts MATCH ?1
+    ORDER BY rank
+    LIMIT {}
+) AS found ON recording.id = found.rowid
+ORDER BY found.rank;",
+                    FUZZY_LIMIT
+                ),
+                [&expr],
+            )?
+        } else if let Some(title) = &meta.title {
+            let credit = Credit::of(meta);
+            let recordings = if credit.artists.is_empty() {
+                MBMirror::recordings(
+                    client,
+                    &format!("{} WHERE name = ?1 LIMIT {};", RECORDING, STRICT_LIMIT),
+                    [title],
+                )?
+            } else {
+                // narrowed down by artist before the limit, so that common titles still find
+                // the right recording, loosely since `Credit::matches` splits joint credits
+                let artists = serde_json::to_string(&credit.artists).map_err(|e| e.to_string())?;
+                MBMirror::recordings(
+                    client,
+                    &format!(
+                        "{} WHERE name = ?1 AND EXISTS (
+    SELECT 1 FROM artist_credit_name, json_each(?2)
+    WHERE artist_credit_name.artist_credit = recording.artist_credit
+        AND artist_credit_name.name LIKE '%' || json_each.value || '%'
+) LIMIT {};",
+                        RECORDING, STRICT_LIMIT
+                    ),
+                    (title, artists),
+                )?
+            };
+            let mut matching = Vec::with_capacity(recordings.len());
+            for recording in recordings {
+                let artists = MBMirror::strings(
+                    client,
+                    "SELECT name FROM artist_credit_name WHERE artist_credit = ?1 ORDER BY position;",
+                    [recording.artist_credit],
+                )?;
+                if credit.matches(&Credit::parse(title, &artists)) {
+                    matching.push(recording);
+                }
+            }
+            matching
+        } else {
+            return Err("Required Fields: title, isrc or mbid".to_string());
+        };
+        recordings
+            .into_iter()
+            .map(|r| MBMirror::metadata(client, r, votes))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl MetadataSource for MBMirror {
+    async fn get_track(
+        &self,
+        meta: &util::Metadata,
+        fuzzy: bool,
+    ) -> Result<Vec<util::Metadata>, String> {
+        let (meta, votes) = (meta.clone(), self.votes);
+        self.pool
+            .run(move |client| MBMirror::query(client, &meta, fuzzy, votes))
+            .await
+    }
+}
@@ -1,75 +1,379 @@
 use crate::util;
+use async_trait::async_trait;
+use futures_util::future::join_all;
+use std::{collections::HashMap, path::Path, sync::Arc, time::Duration};
 use tokio::join;
+mod acoustid;
+mod cache;
 mod deezer;
+mod discogs;
+mod genre;
+mod itunes;
+mod limit;
 mod lrclib;
+mod mbmirror;
 mod musicbrainz;
+mod pool;
 mod spotifydb;
+#[cfg(test)]
+mod tests;
 
-pub(crate) trait MetadataSource {
+use cache::Cached;
+pub(crate) use cache::{Cache, CacheEntry, CacheStats};
+pub(crate) use genre::GenreConfig;
+use limit::Limited;
+pub(crate) use mbmirror::import as mbmirror_import;
+pub(crate) use spotifydb::index as spotifydb_index;
+
+const USER_AGENT: &str = concat!(
+    "RecordBox/",
+    env!("CARGO_PKG_VERSION"),
+    " ( https://github.com/MaxAPCS/recordbox )"
+);
+
+#[async_trait]
+pub(crate) trait MetadataSource: Send + Sync {
     async fn get_track(
         &self,
         meta: &util::Metadata,
         fuzzy: bool,
     ) -> Result<Vec<util::Metadata>, String>;
+
+    /// Whether the source takes part in fuzzy queries
+    fn fuzzy(&self) -> bool {
+        true
+    }
+}
+
+#[async_trait]
+impl MetadataSource for Box<dyn MetadataSource> {
+    async fn get_track(
+        &self,
+        meta: &util::Metadata,
+        fuzzy: bool,
+    ) -> Result<Vec<util::Metadata>, String> {
+        (**self).get_track(meta, fuzzy).await
+    }
+
+    fn fuzzy(&self) -> bool {
+        (**self).fuzzy()
+    }
+}
+
+/// Options of one entry in the `sources:` configuration
+#[derive(serde::Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct SourceConfig {
+    enabled: Option<bool>,
+    /// Higher priorities are listed first
+    priority: Option<i64>,
+    /// Seconds until a query is abandoned
+    timeout: Option<f64>,
+    /// Queries per second
+    ratelimit: Option<f64>,
+    useragent: Option<String>,
+    url: Option<String>,
+    file: Option<String>,
+    token: Option<String>,
+    /// Top results followed up with detail lookups
+    details: Option<usize>,
+    /// Result pages searched for a good match
+    pages: Option<usize>,
+    /// Which lyrics to take: `synced`, `plain` or `any`
+    lyrics: Option<String>,
+    /// Votes a tag needs to count as a genre
+    votes: Option<i64>,
 }
 
+/// Takes the Spotify dump of the top-level `spotifydb:` key of configurations from before
+/// `sources:`, unless `sources.spotifydb.file` is set as well
+pub(crate) fn legacy_spotifydb(sources: &mut HashMap<String, SourceConfig>, file: String) {
+    eprintln!("The 'spotifydb' option is deprecated, set 'sources.spotifydb.file' instead");
+    sources
+        .entry("spotifydb".to_string())
+        .or_default()
+        .file
+        .get_or_insert(file);
+}
+
+impl SourceConfig {
+    fn useragent(&self) -> &str {
+        self.useragent.as_deref().unwrap_or(USER_AGENT)
+    }
+
+    fn client(&self) -> Result<reqwest::Client, String> {
+        reqwest::Client::builder()
+            .user_agent(self.useragent())
+            .build()
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Options of the `acoustid:` configuration
+#[derive(serde::Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct AcoustIDConfig {
+    key: Option<String>,
+    url: Option<String>,
+    fpcalc: Option<String>,
+    /// Minimum score of a match, between 0 and 1
+    score: Option<f64>,
+    /// Seconds until a lookup is abandoned
+    timeout: Option<f64>,
+    /// Lookups per second, 3 unless set
+    ratelimit: Option<f64>,
+}
+
+type Constructor = fn(&SourceConfig) -> Result<Option<Box<dyn MetadataSource>>, String>;
+
+/// Every known source with its default priority
+const SOURCES: [(&str, i64, Constructor); 7] = [
+    // Correct
+    ("spotifydb", 3, |cfg| {
+        Ok(match &cfg.file {
+            Some(file) => Some(Box::new(spotifydb::SpotifyDB::new(file)?)),
+            None => None,
+        })
+    }),
+    // Strict, Correct, +Lyrics
+    ("lrclib", 2, |cfg| {
+        Ok(Some(Box::new(lrclib::LRCLib::new(cfg)?)))
+    }),
+    // Correct
+    ("deezer", 1, |cfg| {
+        Ok(Some(Box::new(deezer::Deezer::new(cfg)?)))
+    }),
+    // Correct, +Artwork
+    ("itunes", 1, |cfg| {
+        Ok(Some(Box::new(itunes::ITunes::new(cfg)?)))
+    }),
+    // Complete, +Genre
+    ("musicbrainz", 0, |cfg| {
+        Ok(Some(Box::new(musicbrainz::MusicBrainz::new(cfg)?)))
+    }),
+    // Complete, +Genre, +Label, Offline
+    ("mbmirror", 0, |cfg| {
+        Ok(match &cfg.file {
+            Some(file) => Some(Box::new(mbmirror::MBMirror::new(file, cfg.votes)?)),
+            None => None,
+        })
+    }),
+    // Complete, +Genre, +Label
+    ("discogs", -1, |cfg| {
+        Ok(
+            match cfg.token.as_deref().filter(|t| !t.trim().is_empty()) {
+                Some(token) => Some(Box::new(discogs::Discogs::new(cfg, token)?)),
+                None => None,
+            },
+        )
+    }),
+];
+
 pub struct MetadataSources {
-    /// Complete, +Genre
-    musicbrainz: musicbrainz::MusicBrainz,
-    /// Correct
-    deezer: deezer::Deezer,
-    /// Strict, Correct, +Lyrics
-    lrclib: lrclib::LRCLib,
-    /// Strict, Correct
-    spotifydb: Option<spotifydb::SpotifyDB>,
+    sources: Vec<(&'static str, Box<dyn MetadataSource>)>,
+    acoustid: Option<acoustid::AcoustID>,
+    genres: genre::Genres,
+    cache: Arc<Cache>,
+}
+
+/// `input` without any parenthesized or bracketed parts, e.g. `(Remastered 2011)`
+fn rmparens(input: &str) -> String {
+    let mut result = String::new();
+    let mut segment = String::new();
+    let mut depth = 0;
+
+    for ch in input.chars() {
+        match ch {
+            '(' | '[' => {
+                if depth == 0 {
+                    result.push_str(&segment);
+                    segment.clear();
+                }
+                depth += 1;
+            }
+            ')' | ']' => depth -= 1,
+            _ => {
+                if depth == 0 {
+                    segment.push(ch);
+                }
+            }
+        }
+    }
+    result.push_str(&segment);
+
+    result.trim().to_string()
+}
+
+/// Lowercase words of `name`, without versions like `(Remastered)` or `- Live`
+fn normalize(name: &str) -> String {
+    let name = rmparens(name);
+    let name = name.split(" - ").next().unwrap_or_default();
+    name.replace('&', " and ")
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// An FTS5 expression requiring every word of the title and first artist
+fn fts_match(meta: &util::Metadata) -> Option<String> {
+    let words = |name: &str| {
+        normalize(name)
+            .split(' ')
+            .filter(|w| !w.is_empty())
+            .map(|w| format!("\"{}\"", w.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let title = words(meta.title.as_deref()?);
+    if title.is_empty() {
+        return None;
+    }
+    Some(match meta.artists.first().map(|a| words(a)) {
+        Some(artist) if !artist.is_empty() => {
+            format!("title : ({}) AND artists : ({})", title, artist)
+        }
+        _ => format!("title : ({})", title),
+    })
+}
+
+/// Joins the tracks of all results, failing only if none succeeded
+fn merge(
+    results: impl IntoIterator<Item = Result<Vec<util::Metadata>, String>>,
+) -> Result<Vec<util::Metadata>, String> {
+    let (tracks, errors): (Vec<_>, Vec<_>) = results.into_iter().partition(Result::is_ok);
+    if tracks.is_empty() && !errors.is_empty() {
+        return Err(errors
+            .into_iter()
+            .filter_map(Result::err)
+            .collect::<Vec<_>>()
+            .join("; "));
+    }
+    Ok(tracks.into_iter().flatten().flatten().collect())
 }
 
 impl MetadataSources {
-    pub(crate) fn new(spotifydbfile: Option<String>) -> Self {
-        Self {
-            spotifydb: spotifydbfile.map(|f| spotifydb::SpotifyDB::new(f)),
-            musicbrainz: musicbrainz::MusicBrainz::default(),
-            deezer: deezer::Deezer::default(),
-            lrclib: lrclib::LRCLib::default(),
+    pub(crate) fn new(
+        mut config: HashMap<String, SourceConfig>,
+        acoustid: Option<AcoustIDConfig>,
+        genres: GenreConfig,
+        cache: Cache,
+        ttl: impl Fn(&str) -> Duration,
+    ) -> Result<Self, String> {
+        if let Some(name) = config
+            .keys()
+            .find(|name| !SOURCES.iter().any(|(source, ..)| source == name))
+        {
+            return Err(format!("Unknown source '{}'", name));
+        }
+        let cache = Arc::new(cache);
+        let mut sources = Vec::with_capacity(SOURCES.len());
+        for (name, priority, new) in SOURCES {
+            let cfg = config.remove(name).unwrap_or_default();
+            if cfg.enabled == Some(false) {
+                continue;
+            }
+            let Some(source) = new(&cfg).map_err(|e| format!("Source '{}': {}", name, e))? else {
+                continue;
+            };
+            let source: Box<dyn MetadataSource> = Box::new(Cached::new(
+                name,
+                ttl(name),
+                cache.clone(),
+                Limited::new(&cfg, source),
+            ));
+            sources.push((cfg.priority.unwrap_or(priority), name, source));
         }
+        sources.sort_by_key(|(priority, ..)| -priority);
+        let acoustid = match acoustid {
+            Some(cfg) => {
+                let acoustid = acoustid::AcoustID::new(cfg, cache.clone(), ttl("acoustid"))?;
+                acoustid
+                    .installed()
+                    .map_err(|e| format!("acoustid: {}", e))?;
+                Some(acoustid)
+            }
+            None => None,
+        };
+        Ok(Self {
+            sources: sources
+                .into_iter()
+                .map(|(_, name, source)| (name, source))
+                .collect(),
+            acoustid,
+            genres: genre::Genres::new(genres)?,
+            cache,
+        })
+    }
+
+    pub(crate) fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    /// Recordings matching the fingerprint of `file`, followed by what the sources know of the best one
+    async fn identify(&self, file: &Path) -> Result<Vec<util::Metadata>, String> {
+        let Some(acoustid) = &self.acoustid else {
+            return Ok(Vec::new());
+        };
+        let mut tracks = acoustid
+            .identify(file)
+            .await
+            .map_err(|e| format!("acoustid: {}", e))?;
+        if let Some(best) = tracks.first()
+            && let Ok(found) = self.get_track(best, false).await
+        {
+            tracks.extend(found);
+        }
+        Ok(tracks)
+    }
+
+    /// Candidates for the track stored in `file`, by both its fingerprint and its tags
+    pub(crate) async fn get_file(
+        &self,
+        file: &Path,
+        meta: &util::Metadata,
+    ) -> Result<Vec<util::Metadata>, String> {
+        let (identified, tracks) = join!(
+            self.identify(file),
+            self.get_track(meta, meta.isrc.is_none())
+        );
+        Ok(self.normalize(merge([identified, tracks])?))
+    }
+
+    /// Reduces the genres of each track to known ones
+    fn normalize(&self, mut tracks: Vec<util::Metadata>) -> Vec<util::Metadata> {
+        for track in &mut tracks {
+            track.genres = self.genres.normalize(std::mem::take(&mut track.genres));
+        }
+        tracks
     }
 }
 
+#[async_trait]
 impl MetadataSource for MetadataSources {
+    /// Queries every applicable source at once, failing only if all of them fail
     async fn get_track(
         &self,
         meta: &util::Metadata,
         fuzzy: bool,
     ) -> Result<Vec<util::Metadata>, String> {
-        let meta = meta.clone();
-        Ok(if !fuzzy {
-            if let Some(spotifydb) = &self.spotifydb {
-                <[Result<Vec<util::Metadata>, String>; 4]>::from(join!(
-                    spotifydb.get_track(&meta, fuzzy),
-                    self.lrclib.get_track(&meta, fuzzy),
-                    self.deezer.get_track(&meta, fuzzy),
-                    self.musicbrainz.get_track(&meta, fuzzy)
-                ))
-                .to_vec()
-            } else {
-                <[Result<Vec<util::Metadata>, String>; 3]>::from(join!(
-                    self.lrclib.get_track(&meta, fuzzy),
-                    self.deezer.get_track(&meta, fuzzy),
-                    self.musicbrainz.get_track(&meta, fuzzy)
-                ))
-                .to_vec()
-            }
-        } else {
-            <[Result<Vec<util::Metadata>, String>; 2]>::from(join!(
-                self.deezer.get_track(&meta, fuzzy),
-                self.musicbrainz.get_track(&meta, fuzzy)
-            ))
-            .to_vec()
-        }
-        .into_iter()
-        .collect::<Result<Vec<_>, _>>()?
-        .into_iter()
-        .flatten()
-        .collect())
+        merge(
+            join_all(
+                self.sources
+                    .iter()
+                    .filter(|(_, source)| !fuzzy || source.fuzzy())
+                    .map(async |(name, source)| {
+                        source
+                            .get_track(meta, fuzzy)
+                            .await
+                            .map_err(|e| format!("{}: {}", name, e))
+                    }),
+            )
+            .await,
+        )
+        .map(|tracks| self.normalize(tracks))
     }
 }
@@ -1,84 +1,48 @@
-use std::{borrow::Cow, num::NonZero};
+use std::borrow::Cow;
 
-use crate::{autotag::MetadataSource, util};
-use musicbrainz_rs::{
-    MusicBrainzClient, Search,
-    chrono::{Datelike, Local},
-    entity::recording::Recording as MBRecording,
+use crate::{
+    autotag::{MetadataSource, SourceConfig},
+    util,
 };
+use async_trait::async_trait;
+use musicbrainz_rs::{MusicBrainzClient, Search, entity::recording::Recording as MBRecording};
 
-#[derive(Default)]
 pub(super) struct MusicBrainz {
     client: MusicBrainzClient,
+    /// Votes a tag needs to count as a genre
+    votes: i64,
 }
 
 impl MusicBrainz {
-    fn format_date(input: &str) -> Option<String> {
-        match input.len() {
-            10 if input.chars().nth(4) == Some('-') && input.chars().nth(7) == Some('-') => {
-                let parts: Vec<&str> = input.split('-').collect();
-                if parts.len() != 3 {
-                    return None;
-                }
-
-                let year = parts[0].parse::<u32>().ok()?;
-                let month = parts[1].parse::<NonZero<u32>>().ok()?;
-                let day = parts[2].parse::<NonZero<u32>>().ok()?;
-
-                if MusicBrainz::is_valid_date(year as i32, month, day) {
-                    Some(input.to_string())
-                } else {
-                    None
-                }
-            }
-            8 => {
-                let year_str = &input[0..4];
-                let month_str = &input[4..6];
-                let day_str = &input[6..8];
-
-                let year = year_str.parse::<u32>().ok()?;
-                let month = month_str.parse::<NonZero<u32>>().ok()?;
-                let day = day_str.parse::<NonZero<u32>>().ok()?;
-
-                if MusicBrainz::is_valid_date(year as i32, month, day) {
-                    return Some(format!("{}-{}-{}", year_str, month_str, day_str));
-                } else {
-                    None
-                }
-            }
-            _ => None,
+    pub(super) fn new(cfg: &SourceConfig) -> Result<Self, String> {
+        let mut client = MusicBrainzClient::default();
+        client
+            .set_user_agent(cfg.useragent())
+            .map_err(|e| e.to_string())?;
+        if let Some(url) = &cfg.url {
+            client.musicbrainz_domain = url.clone();
         }
-    }
-
-    fn is_valid_date(year: i32, month: NonZero<u32>, day: NonZero<u32>) -> bool {
-        // Validate year
-        if year < 1900 || year > Local::now().year() {
-            return false;
+        if cfg.ratelimit.is_some() {
+            // superseded by the configured limit
+            client.drop_ratelimit();
         }
-        let days_in_month: u32 = match month.into() {
-            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
-            4 | 6 | 9 | 11 => 30,
-            2 => {
-                if (year % 4 == 0 && year % 100 != 0) || (year % 400 == 0) {
-                    29
-                } else {
-                    28
-                }
-            }
-            _ => return false,
-        };
-
-        u32::from(day) <= days_in_month
+        Ok(Self {
+            client,
+            votes: cfg.votes.unwrap_or(1),
+        })
     }
 }
 
+#[async_trait]
 impl MetadataSource for MusicBrainz {
     async fn get_track(
         &self,
         meta: &util::Metadata,
         fuzzy: bool,
     ) -> Result<Vec<util::Metadata>, String> {
-        let query = if fuzzy {
+        let query = if let Some(mbid) = &meta.mbid {
+            format!("rid:\"{}\"", mbid)
+        } else if fuzzy {
             // TODO: TOO VIGILANT! ensure track titles overlap with query at least a little
             if let Some(isrc) = &meta.isrc {
                 format!("isrc: \"{}\"", isrc)
@@ -108,10 +72,8 @@ impl MetadataSource for MusicBrainz {
                 .collect::<Vec<String>>()
                 .join(" AND ");
 
-            if let Some(date) = &meta.date
-                && let Some(datef) = MusicBrainz::format_date(&date)
-            {
-                query += format!(" date:\"{}\"", datef).as_str();
+            if let Some(date) = &meta.date {
+                query += format!(" date:\"{}\"", date).as_str();
             }
             query
         };
@@ -136,15 +98,22 @@ impl MetadataSource for MusicBrainz {
                     .collect(),
                 album: f
                     .releases
-                    .map(|rs| rs.first().map(|r| r.title.clone()))
-                    .flatten(),
-                genres: f
-                    .tags
-                    .map(|ts| ts.iter().map(|g| g.name.clone()).collect())
-                    .unwrap_or_default(),
-                date: f.first_release_date.map(|d| d.0),
-                lyrics: None,
-                isrc: f.isrcs.map(|i| i.first().cloned()).flatten(),
+                    .as_ref()
+                    .and_then(|rs| rs.first().map(|r| r.title.clone())),
+                date: f
+                    .releases
+                    .as_ref()
+                    .and_then(|rs| rs.first()?.date.as_ref()?.0.parse().ok()),
+                genres: {
+                    let mut tags = f.tags.unwrap_or_default();
+                    tags.retain(|t| i64::from(t.count.unwrap_or_default()) >= self.votes);
+                    tags.sort_by_key(|t| -t.count.unwrap_or_default());
+                    tags.into_iter().map(|t| t.name).collect()
+                },
+                original_date: f.first_release_date.and_then(|d| d.0.parse().ok()),
+                isrc: f.isrcs.and_then(|i| i.first().cloned()),
+                mbid: Some(f.id),
+                ..Default::default()
             })
             .collect())
     }
@@ -0,0 +1,95 @@
+use std::sync::{Arc, Mutex};
+
+use rusqlite::OpenFlags;
+use tokio::sync::Semaphore;
+
+/// Tables and the columns of each that queries rely on
+pub(super) type Schema = [(&'static str, &'static [&'static str])];
+
+/// Read-only connections to a local database, opened as needed and reused
+pub(super) struct Pool {
+    dbfile: String,
+    idle: Mutex<Vec<rusqlite::Connection>>,
+    /// Bounds the open connections, each query holding one
+    permits: Semaphore,
+}
+
+impl Pool {
+    /// Fails unless `dbfile` holds every table and column of `schema`
+    pub(super) fn new(dbfile: &str, schema: &Schema) -> Result<Arc<Self>, String> {
+        let pool = Self {
+            dbfile: dbfile.to_string(),
+            idle: Mutex::new(Vec::new()),
+            permits: Semaphore::new(std::thread::available_parallelism().map_or(4, |n| n.get())),
+        };
+        pool.validate(schema)?;
+        Ok(Arc::new(pool))
+    }
+
+    pub(super) fn file(&self) -> &str {
+        &self.dbfile
+    }
+
+    fn open(&self) -> Result<rusqlite::Connection, String> {
+        rusqlite::Connection::open_with_flags(
+            &self.dbfile,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .map_err(|e| format!("{}: {}", self.dbfile, e))
+    }
+
+    fn take(&self) -> Result<rusqlite::Connection, String> {
+        match self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop() {
+            Some(client) => Ok(client),
+            None => self.open(),
+        }
+    }
+
+    fn give(&self, client: rusqlite::Connection) {
+        self.idle
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(client);
+    }
+
+    fn validate(&self, schema: &Schema) -> Result<(), String> {
+        let client = self.take()?;
+        for (table, columns) in schema {
+            let present = client
+                .prepare("SELECT name FROM pragma_table_info(?1);")
+                .and_then(|mut q| {
+                    q.query_map([table], |row| row.get::<_, String>(0))?
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .map_err(|e| format!("{}: {}", self.dbfile, e))?;
+            if present.is_empty() {
+                return Err(format!("{}: Table '{}' missing", self.dbfile, table));
+            }
+            if let Some(column) = columns.iter().find(|c| !present.iter().any(|p| p == *c)) {
+                return Err(format!(
+                    "{}: Column '{}.{}' missing",
+                    self.dbfile, table, column
+                ));
+            }
+        }
+        self.give(client);
+        Ok(())
+    }
+
+    /// Runs `query` with a connection on the blocking thread pool
+    pub(super) async fn run<T: Send + 'static>(
+        self: &Arc<Self>,
+        query: impl FnOnce(&rusqlite::Connection) -> Result<T, String> + Send + 'static,
+    ) -> Result<T, String> {
+        let _permit = self.permits.acquire().await.map_err(|e| e.to_string())?;
+        let pool = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let client = pool.take()?;
+            let result = query(&client);
+            pool.give(client);
+            result
+        })
+        .await
+        .map_err(|e| e.to_string())?
+    }
+}
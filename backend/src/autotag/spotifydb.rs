@@ -1,35 +1,148 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, fs, path::Path, sync::Arc};
 
-use tokio::sync::Mutex;
+use async_trait::async_trait;
 
-use crate::{autotag::MetadataSource, util};
+use crate::{
+    autotag::{
+        MetadataSource, fts_match, normalize,
+        pool::{Pool, Schema},
+    },
+    util,
+};
 
 pub(super) struct SpotifyDB {
-    client: Mutex<rusqlite::Connection>,
+    pool: Arc<Pool>,
 }
 
+/// Columns every query selects, in the order `SpotifyDB::metadata` reads them
+const COLUMNS: &str = "tracks.name AS track,
+    albums.name AS album,
+    jsonb_group_array(artists.name) AS artists,
+    tracks.external_id_isrc AS isrc,
+    albums.release_date AS date,
+    tracks.track_number AS track_number,
+    tracks.duration_ms AS duration";
+
+/// Columns of the dump queries rely on
+const SCHEMA: &Schema = &[
+    (
+        "tracks",
+        &[
+            "name",
+            "album_rowid",
+            "external_id_isrc",
+            "track_number",
+            "duration_ms",
+        ],
+    ),
+    ("albums", &["name", "release_date"]),
+    ("artists", &["name"]),
+    ("track_artists", &["track_rowid", "artist_rowid"]),
+];
+
+/// Candidates of a fuzzy query
+const FUZZY_LIMIT: i64 = 10;
+
 impl SpotifyDB {
-    pub(super) fn new(dbfile: String) -> Self {
-        Self {
-            client: Mutex::new(rusqlite::Connection::open(dbfile).unwrap()),
+    pub(super) fn new(dbfile: &str) -> Result<Self, String> {
+        let source = Self {
+            pool: Pool::new(dbfile, SCHEMA)?,
+        };
+        if !source.indexed() {
+            eprintln!(
+                "{}: Full-text index missing, run `recordbox index-spotify {}` for fuzzy queries",
+                dbfile, dbfile
+            );
         }
+        Ok(source)
     }
-}
 
-impl SpotifyDB {
-    fn build_query(meta: &util::Metadata) -> (String, Vec<(String, Cow<'_, str>)>) {
+    /// Whether the full-text index exists, without which fuzzy queries are strict, checked on
+    /// every query so that an index built while the server runs is used right away
+    fn indexed(&self) -> bool {
+        Path::new(self.pool.file())
+            .with_added_extension("fts")
+            .is_file()
+    }
+
+    /// Attaches the index as `fts` to a connection of the pool unless that happened already
+    fn attach(dbfile: &str, client: &rusqlite::Connection) -> Result<(), String> {
+        let attached = client
+            .query_row(
+                "SELECT count(*) FROM pragma_database_list WHERE name = 'fts';",
+                [],
+                |row| row.get::<_, i64>(0),
+            )
+            .map_err(|e| e.to_string())?
+            > 0;
+        if !attached {
+            client
+                .execute("ATTACH DATABASE ?1 AS fts;", [format!("{}.fts", dbfile)])
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Indexes the normalized names of the dump attached as `dump`
+    fn build_index(client: &rusqlite::Connection) -> Result<(), String> {
+        let tx = client.unchecked_transaction().map_err(|e| e.to_string())?;
+        tx.execute(
+            "CREATE VIRTUAL TABLE main.tracks_fts USING fts5(
+    title, artists, album,
+    content = '',
+    tokenize = 'unicode61 remove_diacritics 2'
+);",
+            [],
+        )
+        .map_err(|e| e.to_string())?;
+        {
+            let mut insert = tx
+                .prepare(
+                    "INSERT INTO main.tracks_fts (rowid, title, artists, album) VALUES (?1, ?2, ?3, ?4);",
+                )
+                .map_err(|e| e.to_string())?;
+            let mut select = tx
+                .prepare(
+                    "SELECT tracks.rowid, tracks.name, group_concat(artists.name, ' '), albums.name
+FROM dump.tracks AS tracks
+    JOIN dump.albums AS albums ON tracks.album_rowid = albums.rowid
+    JOIN dump.track_artists AS track_artists ON tracks.rowid = track_artists.track_rowid
+    JOIN dump.artists AS artists ON track_artists.artist_rowid = artists.rowid
+GROUP BY tracks.rowid;",
+                )
+                .map_err(|e| e.to_string())?;
+            let mut rows = select.query([]).map_err(|e| e.to_string())?;
+            while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+                let field = |i| {
+                    row.get::<_, Option<String>>(i)
+                        .map(|f| f.as_deref().map(normalize).unwrap_or_default())
+                };
+                insert
+                    .execute((
+                        row.get::<_, i64>(0).map_err(|e| e.to_string())?,
+                        field(1).map_err(|e| e.to_string())?,
+                        field(2).map_err(|e| e.to_string())?,
+                        field(3).map_err(|e| e.to_string())?,
+                    ))
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    fn build_query(meta: &util::Metadata) -> (String, Vec<(String, Cow<'static, str>)>) {
         let mut where_clause = Vec::new();
         let mut named_params = Vec::new();
 
         if let Some(title) = &meta.title {
             where_clause.push("    tracks.name = :title".to_string());
-            named_params.push((":title".to_string(), Cow::from(title)));
+            named_params.push((":title".to_string(), Cow::from(title.clone())));
         }
         if !meta.artists.is_empty() {
             let mut artist_list = Vec::new();
             for (i, artist) in meta.artists.iter().enumerate() {
                 let placeholder = format!(":artist_{}", i);
-                named_params.push((placeholder.clone(), Cow::from(artist)));
+                named_params.push((placeholder.clone(), Cow::from(artist.clone())));
                 artist_list.push(placeholder);
             }
             where_clause.push(format!(
@@ -45,20 +158,17 @@ impl SpotifyDB {
         }
         if let Some(album) = &meta.album {
             where_clause.push("    albums.name = :album".to_string());
-            named_params.push((":album".to_string(), Cow::from(album)));
+            named_params.push((":album".to_string(), Cow::from(album.clone())));
         }
         if let Some(isrc) = &meta.isrc {
             where_clause.push("    tracks.external_id_isrc = :isrc".to_string());
-            named_params.push((":isrc".to_string(), Cow::from(isrc)));
+            named_params.push((":isrc".to_string(), Cow::from(isrc.clone())));
         }
 
         (
             format!(
-                "SELECT 
-    tracks.name AS track,
-    albums.name AS album,
-    jsonb_group_array(artists.name) AS artists,
-    tracks.external_id_isrc AS isrc
+                "SELECT
+    {}
 FROM tracks
     JOIN albums ON tracks.album_rowid = albums.rowid
     JOIN track_artists ON tracks.rowid = track_artists.track_rowid
@@ -66,44 +176,120 @@ FROM tracks
 WHERE
 {}
 GROUP BY tracks.rowid;",
+                COLUMNS,
                 where_clause.join(" AND\n")
             ),
             named_params,
         )
     }
-}
-
-impl MetadataSource for SpotifyDB {
-    async fn get_track(
-        &self,
-        meta: &util::Metadata,
-        _fuzzy: bool, // not supported
-    ) -> Result<Vec<util::Metadata>, String> {
-        let client = self.client.lock().await;
 
-        let (query, params) = SpotifyDB::build_query(meta);
+    /// Best ranked tracks first
+    fn build_fuzzy_query(expr: String) -> (String, Vec<(String, Cow<'static, str>)>) {
+        (
+            format!(
+                "SELECT
+    {}
+FROM (
+    SELECT rowid, rank FROM fts.tracks_fts
+    WHERE tracks_fts MATCH :match
+    ORDER BY rank
+    LIMIT {}
+) AS found
+    JOIN tracks ON tracks.rowid = found.rowid
+    JOIN albums ON tracks.album_rowid = albums.rowid
+    JOIN track_artists ON tracks.rowid = track_artists.track_rowid
+    JOIN artists ON track_artists.artist_rowid = artists.rowid
+GROUP BY tracks.rowid
+ORDER BY found.rank;",
+                COLUMNS, FUZZY_LIMIT
+            ),
+            vec![(":match".to_string(), Cow::from(expr))],
+        )
+    }
 
-        let mut query = client.prepare(query.as_str()).map_err(|e| e.to_string())?;
+    fn run(
+        client: &rusqlite::Connection,
+        query: &str,
+        params: &[(String, Cow<'_, str>)],
+    ) -> Result<Vec<util::Metadata>, String> {
+        let mut query = client.prepare_cached(query).map_err(|e| e.to_string())?;
         let params = params
             .iter()
             .map(|(a, b)| (a.as_str(), &**b))
             .collect::<Vec<(&str, &str)>>();
 
         let req = query.query(params.as_slice()).map_err(|e| e.to_string())?;
-        let res = req.mapped(|row| {
-            Ok(util::Metadata {
-                title: row.get(0).ok(),
-                album: row.get(1).ok(),
-                artists: row
-                    .get::<_, Vec<u8>>(2)
-                    .ok()
-                    .map(|a| serde_sqlite_jsonb::from_slice::<Vec<String>>(a.as_slice()).ok())
-                    .flatten()
-                    .unwrap_or_default(),
-                isrc: row.get(3).ok(),
-                ..Default::default()
-            })
-        });
+        let res = req.mapped(|row| Ok(SpotifyDB::metadata(row)));
         Ok(res.map_while(|m| m.ok()).collect())
     }
+
+    fn metadata(row: &rusqlite::Row) -> util::Metadata {
+        util::Metadata {
+            title: row.get(0).ok(),
+            album: row.get(1).ok(),
+            artists: row
+                .get::<_, Vec<u8>>(2)
+                .ok()
+                .and_then(|a| serde_sqlite_jsonb::from_slice::<Vec<String>>(a.as_slice()).ok())
+                .unwrap_or_default(),
+            isrc: row.get(3).ok(),
+            date: row.get::<_, String>(4).ok().and_then(|d| d.parse().ok()),
+            track: row.get(5).ok(),
+            duration: row
+                .get::<_, i64>(6)
+                .ok()
+                .map(|ms| ((ms + 500) / 1000) as u32),
+            ..Default::default()
+        }
+    }
+}
+
+/// Builds the index of normalized names of `dbfile` into `<dbfile>.fts`, replacing any earlier
+/// one once it is complete
+pub(crate) fn index(dbfile: &Path) -> Result<(), String> {
+    let ftsfile = dbfile.with_added_extension("fts");
+    let tmpfile = ftsfile.with_added_extension("tmp");
+    if tmpfile.exists() {
+        fs::remove_file(&tmpfile).map_err(|e| e.to_string())?;
+    }
+    let build = || {
+        let client = rusqlite::Connection::open(&tmpfile).map_err(|e| e.to_string())?;
+        client
+            .execute("ATTACH DATABASE ?1 AS dump;", [dbfile.to_string_lossy()])
+            .map_err(|e| e.to_string())?;
+        SpotifyDB::build_index(&client)?;
+        drop(client);
+        fs::rename(&tmpfile, &ftsfile).map_err(|e| e.to_string())
+    };
+    let result = build();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmpfile);
+    }
+    result
+}
+
+#[async_trait]
+impl MetadataSource for SpotifyDB {
+    async fn get_track(
+        &self,
+        meta: &util::Metadata,
+        fuzzy: bool,
+    ) -> Result<Vec<util::Metadata>, String> {
+        // ISRCs are exact even in fuzzy queries
+        let expr = fts_match(meta).filter(|_| fuzzy && meta.isrc.is_none() && self.indexed());
+        let searching = expr.is_some();
+        let (query, params) = match expr {
+            Some(expr) => SpotifyDB::build_fuzzy_query(expr),
+            None => SpotifyDB::build_query(meta),
+        };
+        let dbfile = self.pool.file().to_string();
+        self.pool
+            .run(move |client| {
+                if searching {
+                    SpotifyDB::attach(&dbfile, client)?;
+                }
+                SpotifyDB::run(client, &query, &params)
+            })
+            .await
+    }
 }
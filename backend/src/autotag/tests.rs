@@ -0,0 +1,710 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{Router, http::StatusCode, routing};
+
+use crate::{
+    autotag::{
+        AcoustIDConfig, Cache, MetadataSource, SourceConfig, acoustid, deezer, discogs, itunes,
+        lrclib, mbmirror, musicbrainz, spotifydb,
+    },
+    util,
+};
+
+macro_rules! fixture {
+    ($path:literal) => {
+        include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/",
+            $path
+        ))
+    };
+}
+
+/// Serves each `(path, status, body)` on a local port, returning its address
+async fn mock(routes: &[(&str, StatusCode, &'static str)]) -> String {
+    let mut router = Router::new();
+    for &(path, status, body) in routes {
+        router = router.route(
+            path,
+            routing::any(async move || {
+                (
+                    status,
+                    [(axum::http::header::CONTENT_TYPE, "application/json")],
+                    body,
+                )
+            }),
+        );
+    }
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    address
+}
+
+fn config(url: String) -> SourceConfig {
+    SourceConfig {
+        url: Some(url),
+        ..Default::default()
+    }
+}
+
+fn query() -> util::Metadata {
+    util::Metadata {
+        title: Some("Harder, Better, Faster, Stronger".to_string()),
+        artists: vec!["Daft Punk".to_string()],
+        ..Default::default()
+    }
+}
+
+async fn deezer(status: StatusCode, body: &'static str) -> Result<Vec<util::Metadata>, String> {
+    let address = mock(&[("/search/track", status, body)]).await;
+    deezer::Deezer::new(&config(format!("http://{}", address)))
+        .unwrap()
+        .get_track(&query(), false)
+        .await
+}
+
+async fn deezer_with(cfg: SourceConfig) -> Vec<util::Metadata> {
+    deezer::Deezer::new(&cfg)
+        .unwrap()
+        .get_track(&query(), false)
+        .await
+        .unwrap()
+}
+
+async fn lrclib(status: StatusCode, body: &'static str) -> Result<Vec<util::Metadata>, String> {
+    let address = mock(&[
+        ("/api/get", status, body),
+        ("/api/search", StatusCode::OK, fixture!("lrclib/empty.json")),
+    ])
+    .await;
+    lrclib::LRCLib::new(&config(format!("http://{}", address)))
+        .unwrap()
+        .get_track(&query(), false)
+        .await
+}
+
+async fn musicbrainz(
+    status: StatusCode,
+    body: &'static str,
+) -> Result<Vec<util::Metadata>, String> {
+    let address = mock(&[("/ws/2/recording", status, body)]).await;
+    musicbrainz::MusicBrainz::new(&config(address))
+        .unwrap()
+        .get_track(&query(), false)
+        .await
+}
+
+async fn acoustid(status: StatusCode, body: &'static str) -> Result<Vec<util::Metadata>, String> {
+    let address = mock(&[("/lookup", status, body)]).await;
+    let cache = Arc::new(Cache::new(":memory:".to_string()).unwrap());
+    let acoustid = acoustid::AcoustID::new(
+        AcoustIDConfig {
+            url: Some(format!("http://{}", address)),
+            ..Default::default()
+        },
+        cache.clone(),
+        Duration::from_secs(60),
+    )
+    .unwrap();
+    let fingerprint = acoustid::Fingerprint {
+        duration: 224.6,
+        fingerprint: "AQADtEmUaEkSRZEGAA".to_string(),
+    };
+    let tracks = acoustid.lookup(&fingerprint).await;
+    // only successful lookups are cached
+    assert_eq!(
+        cache.stats().await.unwrap().len(),
+        usize::from(tracks.is_ok())
+    );
+    tracks
+}
+
+async fn discogs(status: StatusCode, body: &'static str) -> Result<Vec<util::Metadata>, String> {
+    discogs_limited(status, body, None).await
+}
+
+/// Release 2294 loads while every other one is missing
+async fn discogs_limited(
+    status: StatusCode,
+    body: &'static str,
+    ratelimit: Option<f64>,
+) -> Result<Vec<util::Metadata>, String> {
+    let address = mock(&[
+        ("/database/search", status, body),
+        (
+            "/releases/2294",
+            StatusCode::OK,
+            fixture!("discogs/release.json"),
+        ),
+    ])
+    .await;
+    let cfg = SourceConfig {
+        ratelimit,
+        ..config(format!("http://{}", address))
+    };
+    discogs::Discogs::new(&cfg, "token")
+        .unwrap()
+        .get_track(&query(), false)
+        .await
+}
+
+async fn itunes(status: StatusCode, body: &'static str) -> Result<Vec<util::Metadata>, String> {
+    let address = mock(&[("/search", status, body)]).await;
+    itunes::ITunes::new(&config(format!("http://{}", address)))
+        .unwrap()
+        .get_track(&query(), false)
+        .await
+}
+
+/// A directory of one test, removed with everything in it once dropped
+struct TempDir(std::path::PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "recordbox-{}-{}-{}",
+            name,
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+impl std::ops::Deref for TempDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A dump with the schema of the Spotify one, holding a few tracks of Discovery, not indexed yet
+fn spotifydb() -> (TempDir, spotifydb::SpotifyDB) {
+    let dir = TempDir::new("spotifydb");
+    let file = dir.join("spotify_clean.sqlite3");
+    rusqlite::Connection::open(&file)
+        .unwrap()
+        .execute_batch(
+            "CREATE TABLE artists (name TEXT);
+CREATE TABLE albums (name TEXT, release_date TEXT);
+CREATE TABLE tracks (name TEXT, album_rowid INTEGER, external_id_isrc TEXT, track_number INTEGER, duration_ms INTEGER);
+CREATE TABLE track_artists (track_rowid INTEGER, artist_rowid INTEGER);
+INSERT INTO artists VALUES ('Daft Punk');
+INSERT INTO albums VALUES ('Discovery', '2001-03-12');
+INSERT INTO tracks VALUES
+    ('One More Time', 1, 'GBDUW0000053', 1, 320357),
+    ('Harder, Better, Faster, Stronger - Remastered', 1, 'GBDUW0000059', 4, 224693);
+INSERT INTO track_artists VALUES (1, 1), (2, 1);",
+        )
+        .unwrap();
+    let spotifydb = spotifydb::SpotifyDB::new(file.to_str().unwrap()).unwrap();
+    (dir, spotifydb)
+}
+
+#[test]
+fn spotifydb_legacy() {
+    let mut sources = std::collections::HashMap::new();
+    super::legacy_spotifydb(&mut sources, "old.sqlite3".to_string());
+    assert_eq!(sources["spotifydb"].file.as_deref(), Some("old.sqlite3"));
+    super::legacy_spotifydb(&mut sources, "older.sqlite3".to_string());
+    assert_eq!(sources["spotifydb"].file.as_deref(), Some("old.sqlite3"));
+}
+
+#[test]
+fn spotifydb_invalid() {
+    let dir = TempDir::new("spotifydb");
+    let file = dir.join("spotify_clean.sqlite3");
+    rusqlite::Connection::open(&file)
+        .unwrap()
+        .execute_batch("CREATE TABLE tracks (name TEXT);")
+        .unwrap();
+    assert!(
+        spotifydb::SpotifyDB::new(file.to_str().unwrap())
+            .err()
+            .unwrap()
+            .ends_with("Column 'tracks.album_rowid' missing")
+    );
+    assert!(spotifydb::SpotifyDB::new("/nonexistent/spotify_clean.sqlite3").is_err());
+}
+
+#[tokio::test]
+async fn spotifydb_strict() {
+    let (_dir, spotifydb) = spotifydb();
+    let tracks = spotifydb.get_track(&query(), false).await.unwrap();
+    // the title differs by its version
+    assert!(tracks.is_empty());
+}
+
+#[tokio::test]
+async fn spotifydb_fuzzy() {
+    let (dir, spotifydb) = spotifydb();
+    let query = util::Metadata {
+        title: Some("harder better faster stronger".to_string()),
+        ..query()
+    };
+    // strict until the index is built, which is used without reopening the source
+    assert!(spotifydb.get_track(&query, true).await.unwrap().is_empty());
+    spotifydb::index(&dir.join("spotify_clean.sqlite3")).unwrap();
+    let tracks = spotifydb.get_track(&query, true).await.unwrap();
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(tracks[0].isrc.as_deref(), Some("GBDUW0000059"));
+    assert_eq!(tracks[0].date, "2001-03-12".parse().ok());
+    assert_eq!(tracks[0].track, Some(4));
+    assert_eq!(tracks[0].duration, Some(225));
+}
+
+/// A mirror imported from a dump holding Harder, Better, Faster, Stronger on Discovery
+fn mbmirror() -> (TempDir, mbmirror::MBMirror) {
+    let dump = TempDir::new("mbdump");
+    for (name, rows) in [
+        ("artist_credit_name", "27\t0\t56\tDaft Punk\t\n"),
+        (
+            "recording",
+            "3135\t5d1d5a1c-4d33-4e3b-9cd4-3e1c8e5b3a7a\tHarder, Better, Faster, Stronger\t27\t224693\t\t0\t\\N\tf\n",
+        ),
+        ("isrc", "1\t3135\tGBDUW0000059\t\\N\t0\t\\N\n"),
+        (
+            "release",
+            "302\tc0e6a6ba-7a3c-4a4b-8b3d-9d8a2d5f2d1e\tDiscovery\t27\t41\t1\t\\N\t120\t28\t724384960650\t\t0\t-1\t\\N\n",
+        ),
+        ("release_country", "302\t222\t2001\t3\t12\n"),
+        ("medium", "900\t302\t1\t1\t\t0\t\\N\t14\n"),
+        (
+            "track",
+            "1\tb1\t3135\t900\t4\t4\tHarder, Better, Faster, Stronger\t27\t224693\t0\t\\N\tf\n",
+        ),
+        ("label", "7\tl1\tVirgin\n"),
+        ("release_label", "1\t302\t7\t7243 8496061 4\t\\N\n"),
+        ("tag", "1\tfrench house\t12\n2\telectronic\t90\n"),
+        ("recording_tag", "3135\t1\t3\t\\N\n3135\t2\t5\t\\N\n"),
+    ] {
+        std::fs::write(dump.join(name), rows).unwrap();
+    }
+    let file = dump.join("musicbrainz.sqlite3");
+    mbmirror::import(&dump, &file).unwrap();
+    let mirror = mbmirror::MBMirror::new(file.to_str().unwrap(), None).unwrap();
+    (dump, mirror)
+}
+
+#[tokio::test]
+async fn mbmirror_strict() {
+    let (_dump, mirror) = mbmirror();
+    let tracks = mirror.get_track(&query(), false).await.unwrap();
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(tracks[0].artists, ["Daft Punk"]);
+    assert_eq!(tracks[0].album.as_deref(), Some("Discovery"));
+    assert_eq!((tracks[0].track, tracks[0].tracks), (Some(4), Some(14)));
+    assert_eq!(tracks[0].date, "2001-03-12".parse().ok());
+    assert_eq!(tracks[0].genres, ["electronic", "french house"]);
+    assert_eq!(tracks[0].isrc.as_deref(), Some("GBDUW0000059"));
+    assert_eq!(tracks[0].label.as_deref(), Some("Virgin"));
+    assert_eq!(tracks[0].catalog.as_deref(), Some("7243 8496061 4"));
+    assert_eq!(tracks[0].barcode.as_deref(), Some("724384960650"));
+}
+
+#[tokio::test]
+async fn mbmirror_fuzzy() {
+    let (_dump, mirror) = mbmirror();
+    let tracks = mirror
+        .get_track(
+            &util::Metadata {
+                title: Some("Harder Better Faster Stronger (Radio Edit)".to_string()),
+                ..query()
+            },
+            true,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        tracks[0].mbid.as_deref(),
+        Some("5d1d5a1c-4d33-4e3b-9cd4-3e1c8e5b3a7a")
+    );
+    let tracks = mirror
+        .get_track(
+            &util::Metadata {
+                title: Some("One More Time".to_string()),
+                ..query()
+            },
+            true,
+        )
+        .await
+        .unwrap();
+    assert!(tracks.is_empty());
+}
+
+#[tokio::test]
+async fn deezer_success() {
+    let tracks = deezer(StatusCode::OK, fixture!("deezer/search.json"))
+        .await
+        .unwrap();
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(
+        tracks[0].title.as_deref(),
+        Some("Harder, Better, Faster, Stronger")
+    );
+    assert_eq!(tracks[0].artists, ["Daft Punk"]);
+    assert_eq!(tracks[0].album.as_deref(), Some("Discovery"));
+    assert_eq!(tracks[0].isrc.as_deref(), Some("GBDUW0000059"));
+}
+
+#[tokio::test]
+async fn deezer_nomatch() {
+    let tracks = deezer(StatusCode::OK, fixture!("deezer/nomatch.json"))
+        .await
+        .unwrap();
+    assert!(tracks.is_empty());
+}
+
+#[tokio::test]
+async fn deezer_malformed() {
+    assert!(
+        deezer(StatusCode::OK, fixture!("deezer/malformed.json"))
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn deezer_details() {
+    let address = mock(&[
+        (
+            "/search/track",
+            StatusCode::OK,
+            fixture!("deezer/search.json"),
+        ),
+        (
+            "/track/3135556",
+            StatusCode::OK,
+            fixture!("deezer/track.json"),
+        ),
+        (
+            "/album/302127",
+            StatusCode::OK,
+            fixture!("deezer/album.json"),
+        ),
+    ])
+    .await;
+    let tracks = deezer_with(SourceConfig {
+        details: Some(1),
+        ..config(format!("http://{}", address))
+    })
+    .await;
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(tracks[0].album_artists, ["Daft Punk"]);
+    assert_eq!((tracks[0].track, tracks[0].tracks), (Some(4), Some(14)));
+    assert_eq!(tracks[0].date, "2001-03-07".parse().ok());
+    assert_eq!(tracks[0].genres, ["Dance"]);
+    assert_eq!(tracks[0].bpm, Some(123));
+    assert_eq!(tracks[0].barcode.as_deref(), Some("724384960650"));
+}
+
+#[tokio::test]
+async fn deezer_details_unavailable() {
+    let address = mock(&[
+        (
+            "/search/track",
+            StatusCode::OK,
+            fixture!("deezer/search.json"),
+        ),
+        (
+            "/track/3135556",
+            StatusCode::OK,
+            fixture!("deezer/malformed.json"),
+        ),
+    ])
+    .await;
+    let tracks = deezer_with(SourceConfig {
+        details: Some(1),
+        ..config(format!("http://{}", address))
+    })
+    .await;
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(tracks[0].album.as_deref(), Some("Discovery"));
+    assert_eq!(tracks[0].bpm, None);
+}
+
+#[tokio::test]
+async fn deezer_pages() {
+    // every page is the same here, and none of them match
+    let address = mock(&[(
+        "/search/track",
+        StatusCode::OK,
+        fixture!("deezer/page.json"),
+    )])
+    .await;
+    let tracks = deezer_with(SourceConfig {
+        pages: Some(3),
+        ..config(format!("http://{}", address))
+    })
+    .await;
+    assert_eq!(tracks.len(), 3);
+}
+
+#[tokio::test]
+async fn lrclib_success() {
+    let tracks = lrclib(StatusCode::OK, fixture!("lrclib/get.json"))
+        .await
+        .unwrap();
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(tracks[0].album.as_deref(), Some("Discovery"));
+    assert!(tracks[0].lyrics.as_ref().unwrap().starts_with("[00:50.12]"));
+}
+
+#[tokio::test]
+async fn lrclib_nomatch() {
+    let tracks = lrclib(StatusCode::NOT_FOUND, fixture!("lrclib/nomatch.json"))
+        .await
+        .unwrap();
+    assert!(tracks.is_empty());
+}
+
+#[tokio::test]
+async fn lrclib_malformed() {
+    assert!(
+        lrclib(StatusCode::OK, fixture!("lrclib/malformed.json"))
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn lrclib_search() {
+    let address = mock(&[
+        (
+            "/api/get",
+            StatusCode::NOT_FOUND,
+            fixture!("lrclib/nomatch.json"),
+        ),
+        (
+            "/api/search",
+            StatusCode::OK,
+            fixture!("lrclib/search.json"),
+        ),
+    ])
+    .await;
+    let tracks = lrclib::LRCLib::new(&config(format!("http://{}", address)))
+        .unwrap()
+        .get_track(
+            &util::Metadata {
+                album: Some("Discovery".to_string()),
+                duration: Some(224),
+                ..query()
+            },
+            false,
+        )
+        .await
+        .unwrap();
+    // the extended version is too long, and the album match comes first
+    assert_eq!(tracks.len(), 2);
+    assert_eq!(tracks[0].album.as_deref(), Some("Discovery"));
+    assert_eq!(tracks[1].album.as_deref(), Some("Alive 2007"));
+}
+
+#[tokio::test]
+async fn lrclib_instrumental() {
+    let tracks = lrclib(StatusCode::OK, fixture!("lrclib/instrumental.json"))
+        .await
+        .unwrap();
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(tracks[0].lyrics, None);
+}
+
+#[tokio::test]
+async fn lrclib_plain() {
+    let address = mock(&[("/api/get", StatusCode::OK, fixture!("lrclib/get.json"))]).await;
+    let tracks = lrclib::LRCLib::new(&SourceConfig {
+        lyrics: Some("plain".to_string()),
+        ..config(format!("http://{}", address))
+    })
+    .unwrap()
+    .get_track(&query(), false)
+    .await
+    .unwrap();
+    assert!(tracks[0].lyrics.as_ref().unwrap().starts_with("Work it"));
+}
+
+#[tokio::test]
+async fn musicbrainz_success() {
+    let tracks = musicbrainz(StatusCode::OK, fixture!("musicbrainz/search.json"))
+        .await
+        .unwrap();
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(tracks[0].artists, ["Daft Punk"]);
+    assert_eq!(tracks[0].album.as_deref(), Some("Discovery"));
+    assert_eq!(tracks[0].date, "2001-03-12".parse().ok());
+    assert_eq!(tracks[0].original_date, "2001-03-12".parse().ok());
+    assert_eq!(tracks[0].genres, ["electronic", "french house"]);
+    assert_eq!(tracks[0].isrc.as_deref(), Some("GBDUW0000059"));
+}
+
+#[tokio::test]
+async fn musicbrainz_nomatch() {
+    let tracks = musicbrainz(StatusCode::OK, fixture!("musicbrainz/nomatch.json"))
+        .await
+        .unwrap();
+    assert!(tracks.is_empty());
+}
+
+#[tokio::test]
+async fn musicbrainz_malformed() {
+    assert!(
+        musicbrainz(StatusCode::OK, fixture!("musicbrainz/malformed.html"))
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn musicbrainz_votes() {
+    let address = mock(&[(
+        "/ws/2/recording",
+        StatusCode::OK,
+        fixture!("musicbrainz/search.json"),
+    )])
+    .await;
+    let tracks = musicbrainz::MusicBrainz::new(&SourceConfig {
+        votes: Some(5),
+        ..config(address)
+    })
+    .unwrap()
+    .get_track(&query(), false)
+    .await
+    .unwrap();
+    assert_eq!(tracks[0].genres, ["electronic"]);
+}
+
+#[tokio::test]
+async fn acoustid_success() {
+    let tracks = acoustid(StatusCode::OK, fixture!("acoustid/lookup.json"))
+        .await
+        .unwrap();
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(
+        tracks[0].mbid.as_deref(),
+        Some("5d1d5a1c-4d33-4e3b-9cd4-3e1c8e5b3a7a")
+    );
+    assert_eq!(tracks[0].artists, ["Daft Punk"]);
+    assert_eq!(tracks[0].album.as_deref(), Some("Discovery"));
+}
+
+#[test]
+fn acoustid_fpcalc() {
+    let acoustid = acoustid::AcoustID::new(
+        AcoustIDConfig {
+            fpcalc: Some("/nonexistent/fpcalc".to_string()),
+            ..Default::default()
+        },
+        Arc::new(Cache::new(":memory:".to_string()).unwrap()),
+        Duration::ZERO,
+    )
+    .unwrap();
+    assert_eq!(
+        acoustid.installed().unwrap_err(),
+        "Fingerprinting requires fpcalc, '/nonexistent/fpcalc' not found"
+    );
+}
+
+#[tokio::test]
+async fn acoustid_error() {
+    assert_eq!(
+        acoustid(StatusCode::BAD_REQUEST, fixture!("acoustid/error.json"))
+            .await
+            .unwrap_err(),
+        "invalid API key"
+    );
+}
+
+#[tokio::test]
+async fn discogs_success() {
+    let tracks = discogs(StatusCode::OK, fixture!("discogs/search.json"))
+        .await
+        .unwrap();
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(tracks[0].artists, ["Daft Punk"]);
+    // B2, the second track of the second side
+    assert_eq!((tracks[0].track, tracks[0].tracks), (Some(2), Some(2)));
+    assert_eq!(tracks[0].date, "2001-03-12".parse().ok());
+    assert_eq!(
+        tracks[0].genres,
+        ["Electronic", "House", "Disco", "Synth-pop"]
+    );
+    assert_eq!(tracks[0].label.as_deref(), Some("Virgin"));
+    assert_eq!(tracks[0].catalog.as_deref(), Some("7243 8496061 4"));
+    assert_eq!(tracks[0].barcode.as_deref(), Some("724384960614"));
+}
+
+#[tokio::test]
+async fn discogs_ratelimit() {
+    // the search and both releases, one of which fails to load
+    let start = std::time::Instant::now();
+    let tracks = discogs_limited(StatusCode::OK, fixture!("discogs/search.json"), Some(20.0))
+        .await
+        .unwrap();
+    assert_eq!(tracks.len(), 1);
+    assert!(start.elapsed() >= Duration::from_millis(100));
+}
+
+#[tokio::test]
+async fn discogs_nomatch() {
+    let tracks = discogs(StatusCode::OK, fixture!("discogs/nomatch.json"))
+        .await
+        .unwrap();
+    assert!(tracks.is_empty());
+}
+
+#[tokio::test]
+async fn discogs_malformed() {
+    assert!(
+        discogs(StatusCode::UNAUTHORIZED, fixture!("discogs/malformed.json"))
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn itunes_success() {
+    let tracks = itunes(StatusCode::OK, fixture!("itunes/search.json"))
+        .await
+        .unwrap();
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(tracks[0].album.as_deref(), Some("Discovery"));
+    assert_eq!((tracks[0].track, tracks[0].tracks), (Some(4), Some(14)));
+    assert_eq!(tracks[0].date, "2001-03-07".parse().ok());
+    assert_eq!(tracks[0].genres, ["Electronic"]);
+    assert!(
+        tracks[0]
+            .artwork
+            .as_ref()
+            .unwrap()
+            .ends_with("/3000x3000bb.jpg")
+    );
+}
+
+#[tokio::test]
+async fn itunes_nomatch() {
+    let tracks = itunes(StatusCode::OK, fixture!("itunes/nomatch.json"))
+        .await
+        .unwrap();
+    assert!(tracks.is_empty());
+}
+
+#[tokio::test]
+async fn itunes_malformed() {
+    assert!(
+        itunes(StatusCode::BAD_REQUEST, fixture!("itunes/malformed.json"))
+            .await
+            .is_err()
+    );
+}
@@ -0,0 +1,278 @@
+use crate::util;
+
+/// Separators of featured artists, which never occur within a name
+const FEATURED: [&str; 4] = [" feat. ", " feat ", " ft. ", " featuring "];
+
+/// Separators of joint credits, matched without regard to ASCII case; besides `FEATURED`, these
+/// are also part of names like `Simon & Garfunkel` or `Tyler, The Creator`
+const JOINTS: [&str; 10] = [
+    " feat. ",
+    " feat ",
+    " ft. ",
+    " featuring ",
+    " with ",
+    " x ",
+    " & ",
+    " vs. ",
+    ", ",
+    "; ",
+];
+
+/// Keywords introducing featured artists in a title, of which `with ` may also begin one
+const FEATURING: [&str; 6] = ["feat. ", "feat ", "ft. ", "ft ", "featuring ", "with "];
+
+/// Keywords introducing producers, who are not credited as artists
+const PRODUCED: [&str; 3] = ["prod. ", "prod ", "produced by "];
+
+/// Bracketed parts of video titles which are not part of the track title
+const JUNK: [&str; 9] = [
+    "official video",
+    "official audio",
+    "official music video",
+    "official lyric video",
+    "lyric video",
+    "visualizer",
+    "lyrics",
+    "audio",
+    "hd",
+];
+
+/// The artists of a joint credit like `A feat. B & C`, in order, separated by any of `joints`
+fn split(artists: &str, joints: &[&str]) -> Vec<String> {
+    let lower = artists.to_ascii_lowercase();
+    let mut names = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i < artists.len() {
+        match joints.iter().find(|j| lower[i..].starts_with(*j)) {
+            Some(joint) => {
+                names.push(&artists[start..i]);
+                i += joint.len();
+                start = i;
+            }
+            None => i += lower[i..].chars().next().map_or(1, char::len_utf8),
+        }
+    }
+    names.push(&artists[start..]);
+    names
+        .into_iter()
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn strip_keyword<'a>(text: &'a str, keywords: &[&str]) -> Option<&'a str> {
+    let lower = text.to_ascii_lowercase();
+    keywords
+        .iter()
+        .find(|k| lower.starts_with(*k))
+        .map(|k| text[k.len()..].trim())
+}
+
+/// A track title and everyone credited for it
+#[derive(Debug, PartialEq)]
+pub(crate) struct Credit {
+    /// Without featured artists or producers, but with qualifiers like `(Remix)`
+    pub(crate) title: String,
+    /// Main artists first, then featured ones
+    pub(crate) artists: Vec<String>,
+}
+
+impl Credit {
+    /// Splits joint credits of `artists` and moves featured artists out of `title`, splitting
+    /// on every separator so that credits compare however they were written
+    pub(crate) fn parse(title: &str, artists: &[String]) -> Self {
+        Credit::parse_with(title, artists, &JOINTS, &FEATURING)
+    }
+
+    /// Like `parse`, but only splitting off artists marked as featured, as safe to write into
+    /// tags without review
+    pub(crate) fn featured(title: &str, artists: &[String]) -> Self {
+        // without `with `
+        Credit::parse_with(title, artists, &FEATURED, &FEATURING[..5])
+    }
+
+    fn parse_with(title: &str, artists: &[String], joints: &[&str], featuring: &[&str]) -> Self {
+        let split = |artists: &str| split(artists, joints);
+        let mut credited = artists.iter().flat_map(|a| split(a)).collect::<Vec<_>>();
+        let mut featured = Vec::new();
+        let mut segments = Vec::new();
+
+        let mut rest = title.trim();
+        while !rest.is_empty() {
+            let open = rest.find(['(', '[']).unwrap_or(rest.len());
+            let (plain, bracketed) = rest.split_at(open);
+            segments.push(plain.to_string());
+            let close = match bracketed.chars().next() {
+                Some('(') => bracketed.find(')'),
+                Some('[') => bracketed.find(']'),
+                _ => None,
+            };
+            let Some(close) = close else {
+                segments.push(bracketed.to_string());
+                break;
+            };
+            let inner = bracketed[1..close].trim();
+            if let Some(names) = strip_keyword(inner, featuring) {
+                featured.extend(split(names));
+            } else if strip_keyword(inner, &PRODUCED).is_none()
+                && !JUNK.contains(&inner.to_lowercase().as_str())
+            {
+                segments.push(bracketed[..=close].to_string());
+            }
+            rest = &bracketed[close + 1..];
+        }
+
+        // unbracketed, featured artists run until the next qualifier
+        let mut title = Vec::with_capacity(segments.len());
+        for segment in segments {
+            let lower = segment.to_ascii_lowercase();
+            let feat = FEATURED
+                .iter()
+                .filter_map(|k| lower.find(k).map(|i| (i, k.len())))
+                .min();
+            match feat {
+                Some((i, len)) if !segment.starts_with(['(', '[']) => {
+                    featured.extend(split(&segment[i + len..]));
+                    title.push(segment[..i].to_string());
+                }
+                _ => title.push(segment),
+            }
+        }
+        let mut title = title
+            .join(" ")
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        // video titles are often `Artist - Title`
+        if let Some((artist, rest)) = title.split_once(" - ")
+            && !credited.is_empty()
+            && split(artist)
+                .iter()
+                .all(|p| credited.iter().any(|c| c.eq_ignore_ascii_case(p)))
+        {
+            title = rest.to_string();
+        }
+
+        for artist in featured {
+            if !credited.iter().any(|c| c.eq_ignore_ascii_case(&artist)) {
+                credited.push(artist);
+            }
+        }
+        let mut artists: Vec<String> = Vec::with_capacity(credited.len());
+        for artist in credited {
+            if !artists.iter().any(|a| a.eq_ignore_ascii_case(&artist)) {
+                artists.push(artist);
+            }
+        }
+        Self { title, artists }
+    }
+
+    pub(crate) fn of(meta: &util::Metadata) -> Self {
+        Credit::parse(meta.title.as_deref().unwrap_or_default(), &meta.artists)
+    }
+
+    /// The title as compared, i.e. its lowercase words
+    fn key(&self) -> String {
+        self.title
+            .replace('&', " and ")
+            .chars()
+            .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+            .collect::<String>()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase()
+    }
+
+    /// Whether both credit the same track, telling apart versions but not spellings
+    pub(crate) fn matches(&self, other: &Credit) -> bool {
+        let (a, b) = (self.key(), other.key());
+        (a == b || a.is_empty() || b.is_empty())
+            && (self.artists.is_empty()
+                || other.artists.is_empty()
+                || self
+                    .artists
+                    .iter()
+                    .any(|a| other.artists.iter().any(|b| a.eq_ignore_ascii_case(b))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credit(title: &str, artists: &[&str]) -> Credit {
+        Credit::parse(
+            title,
+            &artists.iter().map(|a| a.to_string()).collect::<Vec<_>>(),
+        )
+    }
+
+    #[test]
+    fn joint() {
+        assert_eq!(
+            split("Daft Punk feat. Pharrell Williams & Nile Rodgers", &JOINTS),
+            ["Daft Punk", "Pharrell Williams", "Nile Rodgers"]
+        );
+        assert_eq!(split("Skrillex x Diplo", &JOINTS), ["Skrillex", "Diplo"]);
+    }
+
+    #[test]
+    fn names() {
+        for name in [
+            "Simon & Garfunkel",
+            "Earth, Wind & Fire",
+            "Tyler, The Creator",
+        ] {
+            let credit = Credit::featured("Song (with Someone)", &[name.to_string()]);
+            assert_eq!(credit.title, "Song (with Someone)");
+            assert_eq!(credit.artists, [name]);
+        }
+        let credit = Credit::featured(
+            "Song",
+            &["Earth, Wind & Fire feat. The Emotions".to_string()],
+        );
+        assert_eq!(credit.artists, ["Earth, Wind & Fire", "The Emotions"]);
+    }
+
+    #[test]
+    fn featured() {
+        let credit = credit(
+            "Get Lucky (feat. Pharrell Williams) [Radio Edit]",
+            &["Daft Punk"],
+        );
+        assert_eq!(credit.title, "Get Lucky [Radio Edit]");
+        assert_eq!(credit.artists, ["Daft Punk", "Pharrell Williams"]);
+    }
+
+    #[test]
+    fn unbracketed() {
+        let credit = credit("Lose Yourself to Dance ft. Pharrell (Remix)", &[]);
+        assert_eq!(credit.title, "Lose Yourself to Dance (Remix)");
+        assert_eq!(credit.artists, ["Pharrell"]);
+    }
+
+    #[test]
+    fn video() {
+        let credit = credit(
+            "Daft Punk - One More Time (Official Video) (prod. Thomas Bangalter)",
+            &["Daft Punk"],
+        );
+        assert_eq!(credit.title, "One More Time");
+        assert_eq!(credit.artists, ["Daft Punk"]);
+    }
+
+    #[test]
+    fn matches() {
+        let a = credit("Harder, Better, Faster, Stronger", &["Daft Punk"]);
+        assert!(a.matches(&credit("Harder Better Faster Stronger", &["daft punk"])));
+        assert!(!a.matches(&credit(
+            "Harder, Better, Faster, Stronger (Live)",
+            &["Daft Punk"]
+        )));
+        assert!(!a.matches(&credit("Harder, Better, Faster, Stronger", &["Kanye West"])));
+    }
+}
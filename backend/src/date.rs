@@ -0,0 +1,148 @@
+use std::{fmt, str::FromStr};
+
+/// A calendar date of which the month and day may be unknown
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct Date {
+    year: u16,
+    month: Option<u8>,
+    day: Option<u8>,
+}
+
+impl Date {
+    pub(crate) fn new(year: u16, month: Option<u8>, day: Option<u8>) -> Result<Self, String> {
+        if !(1..=9999).contains(&year) {
+            return Err(format!("Invalid year {}", year));
+        }
+        if let Some(month) = month
+            && !(1..=12).contains(&month)
+        {
+            return Err(format!("Invalid month {}", month));
+        }
+        if let Some(day) = day {
+            let Some(month) = month else {
+                return Err("Day without month".to_string());
+            };
+            let leap =
+                (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400);
+            let days = match month {
+                4 | 6 | 9 | 11 => 30,
+                2 if leap => 29,
+                2 => 28,
+                _ => 31,
+            };
+            if !(1..=days).contains(&day) {
+                return Err(format!("Invalid day {}", day));
+            }
+        }
+        Ok(Self { year, month, day })
+    }
+
+    pub(crate) fn year(&self) -> u16 {
+        self.year
+    }
+}
+
+/// Reads `YYYY`, `YYYY-MM`, `YYYY-MM-DD` and `YYYYMMDD`, followed by an optional time as in
+/// `2001-03-07T08:00:00Z`, where unknown parts may be zeroed as in `2001-00-00`
+impl FromStr for Date {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let date = input.trim();
+        let date = date.split_once(['T', ' ']).map_or(date, |(date, _)| date);
+        let invalid = || format!("Invalid date '{}'", input);
+        let parts = match date.len() {
+            8 if date.bytes().all(|b| b.is_ascii_digit()) => {
+                vec![&date[..4], &date[4..6], &date[6..]]
+            }
+            _ => date.split(['-', '/']).collect(),
+        };
+        if parts.len() > 3
+            || parts
+                .iter()
+                .any(|p| p.is_empty() || !p.chars().all(|c| c.is_ascii_digit()))
+            || parts[0].len() != 4
+            || parts[1..].iter().any(|p| p.len() != 2)
+        {
+            return Err(invalid());
+        }
+        // zeroed parts are unknown, as are the ones following them
+        let mut parts = parts.into_iter().map(|p| p.parse::<u16>().unwrap_or(0));
+        let year = parts.next().unwrap_or(0);
+        let month = parts.next().filter(|m| *m > 0);
+        let day = parts.next().filter(|d| *d > 0 && month.is_some());
+        Date::new(year, month.map(|m| m as u8), day.map(|d| d as u8))
+            .map_err(|e| format!("{}: {}", invalid(), e))
+    }
+}
+
+/// Only the known parts, e.g. `2001-03`
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}", self.year)?;
+        if let Some(month) = self.month {
+            write!(f, "-{:02}", month)?;
+        }
+        if let Some(day) = self.day {
+            write!(f, "-{:02}", day)?;
+        }
+        Ok(())
+    }
+}
+
+impl serde::Serialize for Date {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Date {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        for (input, output) in [
+            ("2001", "2001"),
+            ("2001-03", "2001-03"),
+            ("2001-03-07", "2001-03-07"),
+            ("20010307", "2001-03-07"),
+            ("2001-03-07T08:00:00Z", "2001-03-07"),
+            ("2001-00-00", "2001"),
+            ("1877-12-06", "1877-12-06"),
+        ] {
+            assert_eq!(input.parse::<Date>().unwrap().to_string(), output);
+        }
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for input in [
+            "",
+            "0000-00-00",
+            "2001-13",
+            "2001-02-29",
+            "01-03-07",
+            "March 2001",
+            "200é567",
+        ] {
+            assert!(input.parse::<Date>().is_err(), "{}", input);
+        }
+        assert!("2000-02-29".parse::<Date>().is_ok());
+    }
+
+    #[test]
+    fn order() {
+        let date = |d: &str| d.parse::<Date>().unwrap();
+        assert!(date("2001") < date("2001-03"));
+        assert!(date("2001-03-07") < date("2001-03-12"));
+    }
+}
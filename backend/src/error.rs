@@ -0,0 +1,278 @@
+use axum::{
+    extract::rejection::{JsonRejection, PathRejection, QueryRejection},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use reqwest::StatusCode;
+
+use crate::util;
+
+/// Everything the HTTP API reports as failed, each with a stable code
+#[derive(Debug)]
+pub(crate) enum Error {
+    /// The request body, query or path could not be read
+    InvalidRequest(String),
+    InvalidUrl,
+    /// A search query could not be parsed
+    InvalidQuery(String),
+    /// Neither a session nor an API token was given
+    Unauthorized,
+    InvalidCredentials,
+    /// The role of the user does not allow the request
+    Forbidden,
+    UserExists,
+    UserNotFound,
+    TokenNotFound,
+    TrackNotFound,
+    /// A restored track would replace another by its ID
+    TrackExists,
+    /// The track changed since it was read as given by `If-Match`, holding its current state
+    Modified(Box<util::Metadata>),
+    /// No journaled edit or batch of the track has the given ID
+    EditNotFound,
+    LyricsNotFound,
+    /// Requested as LRC though without timestamps
+    LyricsNotSynced,
+    InvalidLyrics(String),
+    PermissionDenied,
+    ReadOnly,
+    CorruptedFile(String),
+    /// A written tag did not read back intact, leaving the track as it was
+    WriteFailed(String),
+    /// The library directory is unset or missing
+    Library(String),
+    DownloaderMissing,
+    DownloadFailed(String),
+    ArtworkUnavailable(String),
+    ArtworkUnsupported,
+    /// Every metadata source failed
+    Sources(String),
+    Cache(String),
+    History(String),
+    Auth(String),
+    Internal(String),
+}
+
+#[derive(serde::Serialize)]
+struct Body<'a> {
+    code: &'static str,
+    message: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current: Option<&'a util::Metadata>,
+}
+
+impl Error {
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            Error::InvalidRequest(_) | Error::InvalidUrl | Error::InvalidQuery(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            Error::Unauthorized | Error::InvalidCredentials => StatusCode::UNAUTHORIZED,
+            Error::Forbidden => StatusCode::FORBIDDEN,
+            Error::TrackNotFound
+            | Error::EditNotFound
+            | Error::LyricsNotFound
+            | Error::UserNotFound
+            | Error::TokenNotFound => StatusCode::NOT_FOUND,
+            Error::TrackExists | Error::UserExists => StatusCode::CONFLICT,
+            Error::Modified(_) => StatusCode::PRECONDITION_FAILED,
+            Error::LyricsNotSynced => StatusCode::NOT_ACCEPTABLE,
+            Error::InvalidLyrics(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::PermissionDenied | Error::ReadOnly => StatusCode::FORBIDDEN,
+            Error::ArtworkUnsupported => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::DownloaderMissing => StatusCode::NOT_IMPLEMENTED,
+            Error::DownloadFailed(_) | Error::ArtworkUnavailable(_) | Error::Sources(_) => {
+                StatusCode::BAD_GATEWAY
+            }
+            Error::CorruptedFile(_)
+            | Error::WriteFailed(_)
+            | Error::Library(_)
+            | Error::Cache(_)
+            | Error::History(_)
+            | Error::Auth(_)
+            | Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            Error::InvalidRequest(_) => "invalid_request",
+            Error::InvalidUrl => "invalid_url",
+            Error::InvalidQuery(_) => "invalid_query",
+            Error::Unauthorized => "unauthorized",
+            Error::InvalidCredentials => "invalid_credentials",
+            Error::Forbidden => "forbidden",
+            Error::UserExists => "user_exists",
+            Error::UserNotFound => "user_not_found",
+            Error::TokenNotFound => "token_not_found",
+            Error::TrackNotFound => "track_not_found",
+            Error::TrackExists => "track_exists",
+            Error::Modified(_) => "track_modified",
+            Error::EditNotFound => "edit_not_found",
+            Error::LyricsNotFound => "lyrics_not_found",
+            Error::LyricsNotSynced => "lyrics_not_synced",
+            Error::InvalidLyrics(_) => "invalid_lyrics",
+            Error::PermissionDenied => "permission_denied",
+            Error::ReadOnly => "read_only",
+            Error::CorruptedFile(_) => "corrupted_file",
+            Error::WriteFailed(_) => "write_failed",
+            Error::Library(_) => "library_unavailable",
+            Error::DownloaderMissing => "downloader_missing",
+            Error::DownloadFailed(_) => "download_failed",
+            Error::ArtworkUnavailable(_) => "artwork_unavailable",
+            Error::ArtworkUnsupported => "artwork_unsupported",
+            Error::Sources(_) => "sources_failed",
+            Error::Cache(_) => "cache_failed",
+            Error::History(_) => "history_failed",
+            Error::Auth(_) => "auth_failed",
+            Error::Internal(_) => "internal",
+        }
+    }
+
+    pub(crate) fn message(&self) -> &'static str {
+        match self {
+            Error::InvalidRequest(_) => "Request Invalid",
+            Error::InvalidUrl => "Invalid URL(s)",
+            Error::InvalidQuery(_) => "Search Query Invalid",
+            Error::Unauthorized => "Authentication Required",
+            Error::InvalidCredentials => "Name or Password Invalid",
+            Error::Forbidden => "Role Insufficient",
+            Error::UserExists => "User Already Exists",
+            Error::UserNotFound => "User Not Found",
+            Error::TokenNotFound => "Token Not Found",
+            Error::TrackNotFound => "Track Not Found",
+            Error::TrackExists => "Track Already Exists",
+            Error::Modified(_) => "Track Modified",
+            Error::EditNotFound => "Edit Not Found",
+            Error::LyricsNotFound => "Lyrics Not Found",
+            Error::LyricsNotSynced => "Lyrics Not Synced",
+            Error::InvalidLyrics(_) => "Lyrics Invalid",
+            Error::PermissionDenied => "Filesystem Permission Denied",
+            Error::ReadOnly => "Filesystem Read-Only",
+            Error::CorruptedFile(_) => "Corrupted File",
+            Error::WriteFailed(_) => "Tag Write Failed",
+            Error::Library(_) => "Library Unavailable",
+            Error::DownloaderMissing => "Track Download requires yt-dlp",
+            Error::DownloadFailed(_) => "Track Download Failed",
+            Error::ArtworkUnavailable(_) => "Artwork Unavailable",
+            Error::ArtworkUnsupported => "Artwork Format Unsupported",
+            Error::Sources(_) => "Metadata Sources Failed",
+            Error::Cache(_) => "Cache Failed",
+            Error::History(_) => "History Failed",
+            Error::Auth(_) => "Authentication Failed",
+            Error::Internal(_) => "Internal Error",
+        }
+    }
+
+    fn details(&self) -> Option<&str> {
+        match self {
+            Error::InvalidRequest(details)
+            | Error::InvalidQuery(details)
+            | Error::InvalidLyrics(details)
+            | Error::CorruptedFile(details)
+            | Error::WriteFailed(details)
+            | Error::Library(details)
+            | Error::DownloadFailed(details)
+            | Error::ArtworkUnavailable(details)
+            | Error::Sources(details)
+            | Error::Cache(details)
+            | Error::History(details)
+            | Error::Auth(details)
+            | Error::Internal(details) => Some(details),
+            _ => None,
+        }
+    }
+
+    /// Failures of the filesystem while accessing a track
+    pub(crate) fn io(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::NotFound => Error::TrackNotFound,
+            std::io::ErrorKind::PermissionDenied => Error::PermissionDenied,
+            std::io::ErrorKind::ReadOnlyFilesystem => Error::ReadOnly,
+            _ => Error::Internal(err.to_string()),
+        }
+    }
+
+    /// Failures of reading or writing the tag of a track
+    pub(crate) fn tag(err: mp4ameta::Error) -> Self {
+        match err.kind {
+            mp4ameta::ErrorKind::Io(err) => Error::io(err),
+            _ => Error::CorruptedFile(err.description.to_string()),
+        }
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let current = match &self {
+            Error::Modified(current) => Some(&**current),
+            _ => None,
+        };
+        let body = Body {
+            code: self.code(),
+            message: self.message(),
+            details: self.details(),
+            current,
+        };
+        let mut response = (self.status(), axum::Json(body)).into_response();
+        if let Some(etag) = current.and_then(|c| header::HeaderValue::from_str(&c.etag()).ok()) {
+            response.headers_mut().insert(header::ETAG, etag);
+        }
+        if matches!(self, Error::Unauthorized) {
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                header::HeaderValue::from_static("Bearer"),
+            );
+        }
+        response
+    }
+}
+
+impl From<JsonRejection> for Error {
+    fn from(rejection: JsonRejection) -> Self {
+        Error::InvalidRequest(rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for Error {
+    fn from(rejection: QueryRejection) -> Self {
+        Error::InvalidRequest(rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for Error {
+    fn from(rejection: PathRejection) -> Self {
+        Error::InvalidRequest(rejection.body_text())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn response() {
+        let response = Error::InvalidLyrics("Line 2: Invalid timestamp [00:75.00]".to_string())
+            .into_response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({
+                "code": "invalid_lyrics",
+                "message": "Lyrics Invalid",
+                "details": "Line 2: Invalid timestamp [00:75.00]",
+            })
+        );
+    }
+
+    #[test]
+    fn io() {
+        let err = std::io::Error::from(std::io::ErrorKind::NotFound);
+        assert!(matches!(Error::io(err), Error::TrackNotFound));
+    }
+}
@@ -0,0 +1,417 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{store::Store, util};
+
+/// On-disk journal of every tag edit, so that any of them can be reverted
+pub(crate) struct History {
+    store: Store,
+}
+
+/// What made an edit
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Kind {
+    /// Replaced every field
+    Manual,
+    Patch,
+    Autotag,
+    /// Patched several tracks at once
+    Bulk,
+    /// Credits split after a download
+    Download,
+    Revert,
+}
+
+impl Kind {
+    const ALL: [Kind; 6] = [
+        Kind::Manual,
+        Kind::Patch,
+        Kind::Autotag,
+        Kind::Bulk,
+        Kind::Download,
+        Kind::Revert,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Manual => "manual",
+            Kind::Patch => "patch",
+            Kind::Autotag => "autotag",
+            Kind::Bulk => "bulk",
+            Kind::Download => "download",
+            Kind::Revert => "revert",
+        }
+    }
+}
+
+/// The edits of one request, all journaled under the same batch
+pub(crate) struct Change {
+    pub(crate) kind: Kind,
+    pub(crate) author: Option<String>,
+    pub(crate) batch: i64,
+}
+
+/// One edit of one track
+#[derive(serde::Serialize, Debug)]
+pub(crate) struct Entry {
+    pub(crate) id: i64,
+    pub(crate) track: String,
+    pub(crate) batch: i64,
+    pub(crate) kind: Kind,
+    pub(crate) author: Option<String>,
+    /// Seconds since the Unix epoch
+    pub(crate) timestamp: i64,
+    pub(crate) before: util::Metadata,
+    pub(crate) after: util::Metadata,
+    /// Digest of the artwork before the edit if it changed it, which is none if there was none
+    #[serde(skip)]
+    pub(crate) artwork: Option<Option<u64>>,
+}
+
+const COLUMNS: &str = "id, track, batch, kind, author, timestamp, before, after, edit, artwork";
+
+fn format_name(format: &mp4ameta::ImgFmt) -> &'static str {
+    match format {
+        mp4ameta::ImgFmt::Bmp => "bmp",
+        mp4ameta::ImgFmt::Jpeg => "jpeg",
+        mp4ameta::ImgFmt::Png => "png",
+    }
+}
+
+impl History {
+    pub(crate) fn new(dbfile: String) -> Result<Self, String> {
+        Ok(Self {
+            store: Store::new(
+                dbfile,
+                "CREATE TABLE IF NOT EXISTS batches (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS edits (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    track TEXT NOT NULL,
+    batch INTEGER NOT NULL REFERENCES batches (id),
+    kind TEXT NOT NULL,
+    author TEXT,
+    timestamp INTEGER NOT NULL,
+    before TEXT NOT NULL,
+    after TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS edits_track ON edits (track);
+CREATE INDEX IF NOT EXISTS edits_batch ON edits (batch);
+CREATE TABLE IF NOT EXISTS artworks (
+    digest INTEGER PRIMARY KEY,
+    format TEXT NOT NULL,
+    data BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS edit_artworks (
+    edit INTEGER PRIMARY KEY REFERENCES edits (id),
+    artwork INTEGER REFERENCES artworks (digest)
+);",
+            )?,
+        })
+    }
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default()
+    }
+
+    /// Starts a batch for the edits of one request
+    pub(crate) async fn change(
+        &self,
+        kind: Kind,
+        author: Option<String>,
+    ) -> Result<Change, String> {
+        let batch = self
+            .store
+            .run(|client| {
+                client
+                    .execute(
+                        "INSERT INTO batches (timestamp) VALUES (?1);",
+                        [History::now()],
+                    )
+                    .map_err(|e| e.to_string())?;
+                Ok(client.last_insert_rowid())
+            })
+            .await?;
+        Ok(Change {
+            kind,
+            author,
+            batch,
+        })
+    }
+
+    /// Journals an edit, along with `artwork` as it was before if the edit changed it, returning
+    /// its ID
+    pub(crate) async fn record(
+        &self,
+        track: &str,
+        change: &Change,
+        before: &util::Metadata,
+        after: &util::Metadata,
+        artwork: Option<mp4ameta::ImgRef<'_>>,
+    ) -> Result<i64, String> {
+        let before_json = serde_json::to_string(before).map_err(|e| e.to_string())?;
+        let after_json = serde_json::to_string(after).map_err(|e| e.to_string())?;
+        let (track, batch, kind, author) = (
+            track.to_string(),
+            change.batch,
+            change.kind.name(),
+            change.author.clone(),
+        );
+        // digests are stored as their bits, as SQLite integers are signed
+        let artwork = match before.artwork_digest != after.artwork_digest {
+            true => Some((
+                before.artwork_digest.map(|d| d as i64),
+                artwork.map(|a| (format_name(&a.fmt), a.data.to_vec())),
+            )),
+            false => None,
+        };
+        self.store.run(move |client| {
+        let transaction = client.transaction().map_err(|e| e.to_string())?;
+        transaction
+            .execute(
+                "INSERT INTO edits (track, batch, kind, author, timestamp, before, after)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7);",
+                (
+                    track,
+                    batch,
+                    kind,
+                    author,
+                    History::now(),
+                    before_json,
+                    after_json,
+                ),
+            )
+            .map_err(|e| e.to_string())?;
+        let edit = transaction.last_insert_rowid();
+        if let Some((digest, artwork)) = artwork {
+            if let (Some(digest), Some((format, data))) = (digest, artwork) {
+                transaction
+                    .execute(
+                        "INSERT OR IGNORE INTO artworks (digest, format, data) VALUES (?1, ?2, ?3);",
+                        (digest, format, data),
+                    )
+                    .map_err(|e| e.to_string())?;
+            }
+            transaction
+                .execute(
+                    "INSERT INTO edit_artworks (edit, artwork) VALUES (?1, ?2);",
+                    (edit, digest),
+                )
+                .map_err(|e| e.to_string())?;
+        }
+        transaction.commit().map_err(|e| e.to_string())?;
+        Ok(edit)
+        }).await
+    }
+
+    /// Removes a journaled edit that failed to land, keeping its artwork, which other edits may
+    /// share
+    pub(crate) async fn discard(&self, edit: i64) -> Result<(), String> {
+        self.store
+            .run(move |client| {
+                let transaction = client.transaction().map_err(|e| e.to_string())?;
+                transaction
+                    .execute("DELETE FROM edit_artworks WHERE edit = ?1;", [edit])
+                    .map_err(|e| e.to_string())?;
+                transaction
+                    .execute("DELETE FROM edits WHERE id = ?1;", [edit])
+                    .map_err(|e| e.to_string())?;
+                transaction.commit().map_err(|e| e.to_string())
+            })
+            .await
+    }
+
+    /// Journaled artwork of the given digest
+    pub(crate) async fn artwork(
+        &self,
+        digest: u64,
+    ) -> Result<Option<mp4ameta::Img<Vec<u8>>>, String> {
+        let artwork = self
+            .store
+            .run(move |client| {
+                let mut query = client
+                    .prepare("SELECT format, data FROM artworks WHERE digest = ?1;")
+                    .map_err(|e| e.to_string())?;
+                let mut rows = query
+                    .query_map([digest as i64], |row| {
+                        Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+                    })
+                    .map_err(|e| e.to_string())?;
+                rows.next().transpose().map_err(|e| e.to_string())
+            })
+            .await?;
+        let Some((format, data)) = artwork else {
+            return Ok(None);
+        };
+        let format = [
+            mp4ameta::ImgFmt::Bmp,
+            mp4ameta::ImgFmt::Jpeg,
+            mp4ameta::ImgFmt::Png,
+        ]
+        .into_iter()
+        .find(|f| format_name(f) == format)
+        .ok_or_else(|| format!("Unknown artwork format '{}'", format))?;
+        Ok(Some(mp4ameta::Img::new(format, data)))
+    }
+
+    fn entries(
+        client: &rusqlite::Connection,
+        filter: &str,
+        param: impl rusqlite::ToSql,
+    ) -> Result<Vec<Entry>, String> {
+        let mut query = client
+            .prepare(&format!(
+                "SELECT {} FROM edits LEFT JOIN edit_artworks ON edit = id {};",
+                COLUMNS, filter
+            ))
+            .map_err(|e| e.to_string())?;
+        query
+            .query_map([param], |row| {
+                let kind: String = row.get(3)?;
+                let before: String = row.get(6)?;
+                let after: String = row.get(7)?;
+                Ok(Entry {
+                    id: row.get(0)?,
+                    track: row.get(1)?,
+                    batch: row.get(2)?,
+                    kind: Kind::ALL
+                        .into_iter()
+                        .find(|k| k.name() == kind)
+                        .unwrap_or(Kind::Manual),
+                    author: row.get(4)?,
+                    timestamp: row.get(5)?,
+                    before: serde_json::from_str(&before).unwrap_or_default(),
+                    after: serde_json::from_str(&after).unwrap_or_default(),
+                    artwork: row
+                        .get::<_, Option<i64>>(8)?
+                        .map(|_| row.get::<_, Option<i64>>(9))
+                        .transpose()?
+                        .map(|a| a.map(|d| d as u64)),
+                })
+            })
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())
+    }
+
+    /// Edits of `track`, the latest first
+    pub(crate) async fn track(&self, track: &str) -> Result<Vec<Entry>, String> {
+        let track = track.to_string();
+        self.store
+            .run(move |client| History::entries(client, "WHERE track = ?1 ORDER BY id DESC", track))
+            .await
+    }
+
+    pub(crate) async fn entry(&self, id: i64) -> Result<Option<Entry>, String> {
+        self.store
+            .run(move |client| Ok(History::entries(client, "WHERE id = ?1", id)?.pop()))
+            .await
+    }
+
+    /// Edits of `batch`, in the order they were made
+    pub(crate) async fn batch(&self, batch: i64) -> Result<Vec<Entry>, String> {
+        self.store
+            .run(move |client| History::entries(client, "WHERE batch = ?1 ORDER BY id", batch))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(title: &str) -> util::Metadata {
+        util::Metadata {
+            title: Some(title.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn journal() {
+        let history = History::new(":memory:".to_string()).unwrap();
+        let bulk = history
+            .change(Kind::Bulk, Some("admin@example.com".to_string()))
+            .await
+            .unwrap();
+        history
+            .record(
+                "a",
+                &bulk,
+                &meta("One More Time"),
+                &meta("one more time"),
+                None,
+            )
+            .await
+            .unwrap();
+        history
+            .record("b", &bulk, &meta("Aerodynamic"), &meta("aerodynamic"), None)
+            .await
+            .unwrap();
+        let patch = history.change(Kind::Patch, None).await.unwrap();
+        assert_ne!(patch.batch, bulk.batch);
+        history
+            .record(
+                "a",
+                &patch,
+                &meta("one more time"),
+                &meta("One More Time"),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let entries = history.track("a").await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].kind, Kind::Patch);
+        assert_eq!(entries[1].author.as_deref(), Some("admin@example.com"));
+        assert_eq!(entries[1].before.title.as_deref(), Some("One More Time"));
+        let batch = history.batch(bulk.batch).await.unwrap();
+        assert_eq!(
+            batch.iter().map(|e| e.track.as_str()).collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+        assert!(history.entry(entries[0].id).await.unwrap().is_some());
+        assert!(history.entry(0).await.unwrap().is_none());
+
+        history.discard(entries[0].id).await.unwrap();
+        assert!(history.entry(entries[0].id).await.unwrap().is_none());
+        assert_eq!(history.track("a").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn artwork() {
+        let history = History::new(":memory:".to_string()).unwrap();
+        let change = history.change(Kind::Patch, None).await.unwrap();
+        let mut tag = mp4ameta::Tag::default();
+        tag.set_artwork(mp4ameta::Img::png(b"\x89PNG".to_vec()));
+        let before = util::Metadata::from(tag.clone());
+        let digest = before.artwork_digest.unwrap();
+        history
+            .record("a", &change, &before, &meta("a"), tag.artwork())
+            .await
+            .unwrap();
+        history
+            .record("a", &change, &meta("a"), &meta("b"), None)
+            .await
+            .unwrap();
+        history
+            .record("a", &change, &meta("b"), &before, None)
+            .await
+            .unwrap();
+
+        let entries = history.track("a").await.unwrap();
+        assert_eq!(entries[0].artwork, Some(None));
+        assert_eq!(entries[1].artwork, None);
+        assert_eq!(entries[2].artwork, Some(Some(digest)));
+        let artwork = history.artwork(digest).await.unwrap().unwrap();
+        assert_eq!(artwork.fmt, mp4ameta::ImgFmt::Png);
+        assert_eq!(artwork.data, b"\x89PNG");
+        assert!(history.artwork(0).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,364 @@
+use std::{cmp::Ordering, collections::HashMap, fs, path::Path, time::SystemTime};
+
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+use crate::{error::Error, search, sync, util};
+
+/// Metadata of every track in the library, read again only once a file changes
+#[derive(Default)]
+pub(crate) struct Index {
+    tracks: Mutex<HashMap<String, Entry>>,
+}
+
+struct Entry {
+    modified: Option<SystemTime>,
+    meta: util::Metadata,
+}
+
+/// A track as listed, without its lyrics
+#[derive(serde::Serialize, Clone, Debug)]
+pub(crate) struct Summary {
+    pub(crate) id: String,
+    #[serde(flatten)]
+    pub(crate) meta: util::Metadata,
+}
+
+impl Index {
+    /// Every track in `dir`, after reading the tags of new and modified ones
+    pub(crate) async fn tracks(&self, dir: &Path) -> Result<Vec<Summary>, Error> {
+        let mut tracks = self.tracks.lock().await;
+        let mut scanned = std::mem::take(&mut *tracks);
+        let dir = dir.to_path_buf();
+        // reading tags blocks, for a while when many tracks changed
+        let (scanned, summaries) = tokio::task::spawn_blocking(move || {
+            let summaries = Index::scan(&mut scanned, &dir);
+            (scanned, summaries)
+        })
+        .await
+        .map_err(|e| Error::Internal(e.to_string()))?;
+        *tracks = scanned;
+        summaries
+    }
+
+    fn scan(tracks: &mut HashMap<String, Entry>, dir: &Path) -> Result<Vec<Summary>, Error> {
+        let ids = sync::track_list(dir)?;
+        tracks.retain(|id, _| ids.contains(id));
+        let mut summaries = Vec::with_capacity(ids.len());
+        for id in ids {
+            let modified = fs::metadata(dir.join(&id).with_added_extension("m4a"))
+                .and_then(|m| m.modified())
+                .ok();
+            let entry = match tracks.get(&id) {
+                Some(entry) if entry.modified.is_some() && entry.modified == modified => entry,
+                _ => {
+                    let meta: util::Metadata = match sync::track_info(&id, dir) {
+                        Ok(tag) => tag.into(),
+                        // listed regardless, so that it can be fixed or deleted
+                        Err(Error::CorruptedFile(_)) => util::Metadata::default(),
+                        Err(e) => return Err(e),
+                    };
+                    tracks.insert(id.clone(), Entry { modified, meta });
+                    &tracks[&id]
+                }
+            };
+            summaries.push(Summary {
+                id,
+                meta: entry.meta.clone(),
+            });
+        }
+        Ok(summaries)
+    }
+}
+
+/// Names of `util::Metadata` fields as filtered on, besides the fields themselves
+pub(crate) fn field_name(name: &str) -> &str {
+    match name {
+        "artist" => "artists",
+        "album_artist" => "album_artists",
+        "genre" => "genres",
+        name => name,
+    }
+}
+
+/// The fields of a track by name, as serialized
+pub(crate) fn fields(track: &Summary) -> serde_json::Map<String, Value> {
+    match serde_json::to_value(track) {
+        Ok(Value::Object(fields)) => fields,
+        _ => serde_json::Map::new(),
+    }
+}
+
+/// Whether `name` is a field of every track
+pub(crate) fn is_field(name: &str) -> bool {
+    fields(&Summary {
+        id: String::new(),
+        meta: util::Metadata::default(),
+    })
+    .contains_key(name)
+}
+
+pub(crate) fn is_empty(value: Option<&Value>) -> bool {
+    match value {
+        None | Some(Value::Null) => true,
+        Some(Value::Array(values)) => values.is_empty(),
+        Some(Value::String(value)) => value.is_empty(),
+        _ => false,
+    }
+}
+
+/// Numbers by value and text without regard to case, with missing values last
+pub(crate) fn compare(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    match (a, b) {
+        (Some(Value::Array(a)), Some(Value::Array(b))) => a
+            .iter()
+            .zip(b)
+            .map(|(a, b)| compare(Some(a), Some(b)))
+            .find(|o| o.is_ne())
+            .unwrap_or(a.len().cmp(&b.len())),
+        (Some(Value::Array(a)), b) => compare(a.first(), b),
+        (a, Some(Value::Array(b))) => compare(a, b.first()),
+        (a, b) if is_empty(a) || is_empty(b) => is_empty(a).cmp(&is_empty(b)),
+        (Some(Value::Number(a)), Some(Value::Number(b))) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (Some(a), Some(b)) => text(a).to_lowercase().cmp(&text(b).to_lowercase()),
+        _ => Ordering::Equal,
+    }
+}
+
+pub(crate) fn text(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Whether `value` or any of its elements equals `expected`, without regard to case
+fn equals(value: Option<&Value>, expected: &str) -> bool {
+    match value {
+        Some(Value::Array(values)) => values.iter().any(|v| equals(Some(v), expected)),
+        Some(Value::Null) | None => false,
+        Some(value) => text(value).eq_ignore_ascii_case(expected),
+    }
+}
+
+/// The year of the release date of a track
+pub(crate) fn year_of(fields: &serde_json::Map<String, Value>) -> Option<u16> {
+    fields.get("date")?.as_str()?.get(..4)?.parse().ok()
+}
+
+/// One condition of a listing, from a query parameter
+enum Filter {
+    Equals(String, String),
+    Missing(String),
+    /// Years ordered as given against the year, or equal to it if inclusive
+    Year(Ordering, bool, u16),
+}
+
+impl Filter {
+    fn matches(&self, fields: &serde_json::Map<String, Value>) -> bool {
+        match self {
+            Filter::Equals(field, value) => equals(fields.get(field), value),
+            Filter::Missing(field) => is_empty(fields.get(field)),
+            Filter::Year(ordering, inclusive, year) => year_of(fields)
+                .is_some_and(|y| y.cmp(year) == *ordering || (*inclusive && y == *year)),
+        }
+    }
+}
+
+/// Which tracks to list and how, from the query string
+#[derive(Default)]
+pub(crate) struct Listing {
+    filters: Vec<Filter>,
+    /// Field to sort by, descending if true
+    sort: Option<(String, bool)>,
+    limit: Option<usize>,
+    /// ID of the last track of the previous page
+    cursor: Option<String>,
+    /// Search query the tracks must match
+    query: Option<search::Query>,
+}
+
+/// A page of a listing, with the cursor of the next one if there is more
+#[derive(serde::Serialize)]
+pub(crate) struct Page {
+    pub(crate) tracks: Vec<Summary>,
+    pub(crate) cursor: Option<String>,
+}
+
+/// Tracks per page unless a limit is given
+const LIMIT: usize = 100;
+
+impl Listing {
+    /// Reads `limit`, `cursor`, `sort`, a search query `q` and any field filter, e.g. `artist=`,
+    /// `missing=isrc` or `year>=`
+    pub(crate) fn parse(params: Vec<(String, String)>) -> Result<Self, Error> {
+        let mut listing = Listing::default();
+        for (key, value) in params {
+            let invalid = || Error::InvalidRequest(format!("Invalid parameter '{}'", key));
+            match key.as_str() {
+                "limit" => listing.limit = Some(value.parse().map_err(|_| invalid())?),
+                "cursor" => listing.cursor = Some(value),
+                "q" => listing.query = Some(search::Query::parse(&value)?),
+                "sort" => {
+                    let (field, descending) = match value.strip_prefix('-') {
+                        Some(field) => (field, true),
+                        None => (value.as_str(), false),
+                    };
+                    let field = field_name(field);
+                    if !is_field(field) {
+                        return Err(Error::InvalidRequest(format!("Unknown field '{}'", field)));
+                    }
+                    listing.sort = Some((field.to_string(), descending));
+                }
+                "missing" => {
+                    let field = field_name(&value);
+                    if !is_field(field) {
+                        return Err(Error::InvalidRequest(format!("Unknown field '{}'", field)));
+                    }
+                    listing.filters.push(Filter::Missing(field.to_string()));
+                }
+                // `year>=2001` arrives as `year>` = `2001`, and `year>2001` without a value
+                key if let Some(operator) = key.strip_prefix("year") => {
+                    let condition = match value.as_str() {
+                        "" => operator.to_string(),
+                        value => format!("{}={}", operator, value),
+                    };
+                    let (ordering, inclusive, year) = [
+                        (">=", Ordering::Greater, true),
+                        ("<=", Ordering::Less, true),
+                        (">", Ordering::Greater, false),
+                        ("<", Ordering::Less, false),
+                        ("=", Ordering::Equal, true),
+                    ]
+                    .into_iter()
+                    .find_map(|(op, ordering, inclusive)| {
+                        Some((ordering, inclusive, condition.strip_prefix(op)?))
+                    })
+                    .ok_or_else(invalid)?;
+                    let year = year.trim().parse().map_err(|_| invalid())?;
+                    listing
+                        .filters
+                        .push(Filter::Year(ordering, inclusive, year));
+                }
+                key if is_field(field_name(key)) => listing
+                    .filters
+                    .push(Filter::Equals(field_name(key).to_string(), value)),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(listing)
+    }
+
+    /// The page of `tracks` after the cursor, filtered and sorted by ID unless by another field
+    pub(crate) fn apply(&self, tracks: Vec<Summary>) -> Result<Page, Error> {
+        let mut tracks = tracks
+            .into_iter()
+            .map(|t| (fields(&t), t))
+            .filter(|(fields, _)| {
+                self.filters.iter().all(|f| f.matches(fields))
+                    && self.query.as_ref().is_none_or(|q| q.matches(fields))
+            })
+            .collect::<Vec<_>>();
+        tracks.sort_by(|(a, x), (b, y)| {
+            let by_field = match &self.sort {
+                // missing values stay last either way
+                Some((field, descending)) => {
+                    let (a, b) = (a.get(field), b.get(field));
+                    is_empty(a)
+                        .cmp(&is_empty(b))
+                        .then_with(|| match descending {
+                            true => compare(b, a),
+                            false => compare(a, b),
+                        })
+                }
+                None => Ordering::Equal,
+            };
+            by_field.then_with(|| x.id.cmp(&y.id))
+        });
+        let start =
+            match &self.cursor {
+                Some(cursor) => {
+                    tracks.iter().position(|(_, t)| &t.id == cursor).ok_or(
+                        Error::InvalidRequest(format!("Invalid cursor '{}'", cursor)),
+                    )? + 1
+                }
+                None => 0,
+            };
+        let limit = self.limit.unwrap_or(LIMIT);
+        let more = tracks.len() > start + limit;
+        let tracks = tracks
+            .into_iter()
+            .skip(start)
+            .take(limit)
+            .map(|(_, mut t)| {
+                t.meta.lyrics = None;
+                t
+            })
+            .collect::<Vec<_>>();
+        Ok(Page {
+            cursor: tracks.last().map(|t| t.id.clone()).filter(|_| more),
+            tracks,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(id: &str, artist: &str, date: &str, isrc: Option<&str>) -> Summary {
+        Summary {
+            id: id.to_string(),
+            meta: util::Metadata {
+                artists: vec![artist.to_string()],
+                date: date.parse().ok(),
+                isrc: isrc.map(str::to_string),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn tracks() -> Vec<Summary> {
+        vec![
+            track("a", "Daft Punk", "2001-03-12", Some("GBDUW0000059")),
+            track("b", "Justice", "2007-06-11", None),
+            track("c", "daft punk", "1997-01-20", None),
+        ]
+    }
+
+    fn list(params: &[(&str, &str)]) -> Result<Page, Error> {
+        Listing::parse(
+            params
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )?
+        .apply(tracks())
+    }
+
+    fn ids(page: &Page) -> Vec<&str> {
+        page.tracks.iter().map(|t| t.id.as_str()).collect()
+    }
+
+    #[test]
+    fn filter() {
+        assert_eq!(ids(&list(&[("artist", "Daft Punk")]).unwrap()), ["a", "c"]);
+        assert_eq!(ids(&list(&[("missing", "isrc")]).unwrap()), ["b", "c"]);
+        assert_eq!(ids(&list(&[("year>", "2001")]).unwrap()), ["a", "b"]);
+        assert_eq!(ids(&list(&[("year<2001", "")]).unwrap()), ["c"]);
+        assert!(list(&[("colour", "red")]).is_err());
+    }
+
+    #[test]
+    fn sort_and_page() {
+        let page = list(&[("sort", "-date"), ("limit", "2")]).unwrap();
+        assert_eq!(ids(&page), ["b", "a"]);
+        assert_eq!(page.cursor.as_deref(), Some("a"));
+        let page = list(&[("sort", "-date"), ("limit", "2"), ("cursor", "a")]).unwrap();
+        assert_eq!(ids(&page), ["c"]);
+        assert_eq!(page.cursor, None);
+    }
+}
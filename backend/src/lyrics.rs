@@ -0,0 +1,293 @@
+use std::{fmt, time::Duration};
+
+/// Lyrics as stored in the lyrics atom, either synced (LRC) or plain
+#[derive(serde::Serialize, Default, Debug)]
+pub(crate) struct Lyrics {
+    /// ID tags such as `ar` or `ti`, without `offset` which is applied on parsing
+    tags: Vec<(String, String)>,
+    lines: Vec<Line>,
+}
+
+#[derive(serde::Serialize, Debug, PartialEq)]
+pub(crate) struct Line {
+    /// Milliseconds from the start of the track, absent for plain lines
+    time: Option<u64>,
+    text: String,
+}
+
+/// A bracketed prefix of an LRC line
+enum Prefix {
+    Time(u64),
+    Tag(String, String),
+}
+
+impl Lyrics {
+    /// Timestamps are `mm:ss`, `mm:ss.xx` or `mm:ss:xx`, where fractions are of a second
+    fn timestamp(input: &str) -> Option<Result<u64, String>> {
+        let (minutes, rest) = input.split_once(':')?;
+        let (seconds, fraction) = match rest.split_once(['.', ':']) {
+            Some((seconds, fraction)) => (seconds, fraction),
+            None => (rest, "0"),
+        };
+        let numeric = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+        if !numeric(minutes) || !numeric(seconds) || !numeric(fraction) {
+            return None;
+        }
+        let (Ok(minutes), Ok(seconds)) = (minutes.parse::<u64>(), seconds.parse::<u64>()) else {
+            return Some(Err(format!("Invalid timestamp [{}]", input)));
+        };
+        if seconds >= 60 {
+            return Some(Err(format!("Invalid timestamp [{}]", input)));
+        }
+        // only the first three digits of a fraction are significant
+        let fraction = format!("{:0<3}", &fraction[..fraction.len().min(3)]);
+        let time = minutes
+            .checked_mul(60)
+            .and_then(|t| t.checked_add(seconds))
+            .and_then(|t| t.checked_mul(1000))
+            .and_then(|t| t.checked_add(fraction.parse::<u64>().unwrap_or(0)));
+        Some(time.ok_or_else(|| format!("Invalid timestamp [{}]", input)))
+    }
+
+    fn prefix(group: &str) -> Option<Result<Prefix, String>> {
+        if let Some(time) = Lyrics::timestamp(group) {
+            return Some(time.map(Prefix::Time));
+        }
+        let (key, value) = group.split_once(':')?;
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphabetic()) {
+            return None;
+        }
+        Some(Ok(Prefix::Tag(
+            key.to_lowercase(),
+            value.trim().to_string(),
+        )))
+    }
+
+    /// Reads LRC lyrics, taking lines without timestamps as plain ones
+    pub(crate) fn parse(input: &str) -> Result<Self, String> {
+        let mut lyrics = Lyrics::default();
+        let mut shift = 0;
+        for (n, line) in input.lines().enumerate() {
+            let mut rest = line.trim();
+            let mut times = Vec::new();
+            let mut tag = None;
+            while let Some(group) = rest.strip_prefix('[')
+                && let Some((group, after)) = group.split_once(']')
+            {
+                match Lyrics::prefix(group) {
+                    Some(Ok(Prefix::Time(time))) => times.push(time),
+                    Some(Ok(Prefix::Tag(key, value))) if times.is_empty() => {
+                        tag = Some((key, value));
+                    }
+                    Some(Err(e)) => return Err(format!("Line {}: {}", n + 1, e)),
+                    // e.g. `[Chorus]` in plain lyrics
+                    _ => break,
+                }
+                rest = after;
+            }
+            match tag {
+                Some((key, value)) if rest.trim().is_empty() => {
+                    if key == "offset" {
+                        // a positive offset shows lines sooner
+                        shift = value
+                            .parse::<i64>()
+                            .ok()
+                            .and_then(i64::checked_neg)
+                            .ok_or_else(|| format!("Line {}: Invalid offset", n + 1))?;
+                    } else {
+                        lyrics.tags.push((key, value));
+                    }
+                    continue;
+                }
+                Some(_) => rest = line.trim(),
+                None => {}
+            }
+            let text = rest.trim().to_string();
+            if times.is_empty() {
+                lyrics.lines.push(Line { time: None, text });
+            } else {
+                lyrics.lines.extend(times.into_iter().map(|time| Line {
+                    time: Some(time),
+                    text: text.clone(),
+                }));
+            }
+        }
+        lyrics.shift(shift);
+        if lyrics.lines.iter().any(|l| l.time.is_some())
+            && lyrics
+                .lines
+                .iter()
+                .all(|l| l.time.is_some() || l.text.is_empty())
+        {
+            // blank lines only separate verses of synced lyrics
+            lyrics.lines.retain(|l| l.time.is_some());
+            lyrics.lines.sort_by_key(|l| l.time);
+        }
+        Ok(lyrics)
+    }
+
+    /// Each line with its time in milliseconds, if synced
+    pub(crate) fn lines(&self) -> impl Iterator<Item = (Option<u64>, &str)> {
+        self.lines.iter().map(|l| (l.time, l.text.as_str()))
+    }
+
+    /// Whether every line has a timestamp
+    pub(crate) fn synced(&self) -> bool {
+        !self.lines.is_empty() && self.lines.iter().all(|l| l.time.is_some())
+    }
+
+    /// Ensures no line is timed past the end of the track
+    pub(crate) fn validate(&self, duration: Duration) -> Result<(), String> {
+        let end = duration.as_millis() as u64;
+        match self.lines.iter().find(|l| l.time.is_some_and(|t| t > end)) {
+            Some(line) => Err(format!(
+                "Line '{}' at {} is past the end of the track at {}",
+                line.text,
+                Lyrics::format(line.time.unwrap_or_default()),
+                Lyrics::format(end)
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Moves every line by `millis`, which may be negative, stopping at the start of the track
+    pub(crate) fn shift(&mut self, millis: i64) {
+        for line in &mut self.lines {
+            if let Some(time) = &mut line.time {
+                *time = time.saturating_add_signed(millis);
+            }
+        }
+    }
+
+    /// Replaces the text of each line with the respective line of `plain`, keeping the timing
+    pub(crate) fn merge(&mut self, plain: &str) -> Result<(), String> {
+        let text = plain
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .collect::<Vec<_>>();
+        let mut lines = self
+            .lines
+            .iter_mut()
+            .filter(|l| !l.text.is_empty())
+            .collect::<Vec<_>>();
+        if lines.len() != text.len() {
+            return Err(format!(
+                "Lyrics have {} lines, but the text has {}",
+                lines.len(),
+                text.len()
+            ));
+        }
+        for (line, text) in lines.iter_mut().zip(text) {
+            line.text = text.to_string();
+        }
+        Ok(())
+    }
+
+    /// The text alone, without blank instrumental breaks
+    pub(crate) fn plain(&self) -> String {
+        self.lines
+            .iter()
+            .filter(|l| l.time.is_none() || !l.text.is_empty())
+            .map(|l| l.text.as_str())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn format(millis: u64) -> String {
+        format!(
+            "{:02}:{:02}.{:02}",
+            millis / 60000,
+            millis / 1000 % 60,
+            millis % 1000 / 10
+        )
+    }
+}
+
+impl fmt::Display for Lyrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, value) in &self.tags {
+            writeln!(f, "[{}:{}]", key, value)?;
+        }
+        for line in &self.lines {
+            match line.time {
+                Some(time) => writeln!(f, "[{}]{}", Lyrics::format(time), line.text)?,
+                None => writeln!(f, "{}", line.text)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LRC: &str = "[ar:Daft Punk]\n[ti:Harder, Better, Faster, Stronger]\n[offset:+500]\n\n[00:50.12] Work it\n[00:50.62][01:30.62] Make it\n[00:51.12] Do it\n[00:52.00]\n";
+
+    #[test]
+    fn parse() {
+        let lyrics = Lyrics::parse(LRC).unwrap();
+        assert!(lyrics.synced());
+        assert_eq!(lyrics.tags[0], ("ar".to_string(), "Daft Punk".to_string()));
+        assert_eq!(lyrics.lines.len(), 5);
+        assert_eq!(
+            lyrics.lines[0],
+            Line {
+                time: Some(49620),
+                text: "Work it".to_string()
+            }
+        );
+        assert_eq!(lyrics.lines[4].time, Some(90120));
+        assert_eq!(lyrics.plain(), "Work it\nMake it\nDo it\nMake it");
+    }
+
+    #[test]
+    fn parse_plain() {
+        let lyrics = Lyrics::parse("[Chorus]\nWork it\nMake it").unwrap();
+        assert!(!lyrics.synced());
+        assert_eq!(lyrics.plain(), "[Chorus]\nWork it\nMake it");
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(
+            Lyrics::parse("[00:50.12] Work it\n[00:75.00] Make it").unwrap_err(),
+            "Line 2: Invalid timestamp [00:75.00]"
+        );
+        assert_eq!(
+            Lyrics::parse("[307445734561825860:00.00] Work it").unwrap_err(),
+            "Line 1: Invalid timestamp [307445734561825860:00.00]"
+        );
+        assert_eq!(
+            Lyrics::parse("[offset:-9223372036854775808]\n[00:50.12] Work it").unwrap_err(),
+            "Line 1: Invalid offset"
+        );
+    }
+
+    #[test]
+    fn roundtrip() {
+        let lyrics = Lyrics::parse(LRC).unwrap();
+        let again = Lyrics::parse(&lyrics.to_string()).unwrap();
+        assert_eq!(lyrics.lines, again.lines);
+    }
+
+    #[test]
+    fn validate() {
+        let lyrics = Lyrics::parse(LRC).unwrap();
+        assert!(lyrics.validate(Duration::from_secs(224)).is_ok());
+        assert!(lyrics.validate(Duration::from_secs(60)).is_err());
+    }
+
+    #[test]
+    fn shift_and_merge() {
+        let mut lyrics = Lyrics::parse(LRC).unwrap();
+        lyrics.shift(-50000);
+        assert_eq!(lyrics.lines[0].time, Some(0));
+        lyrics
+            .merge("Work it harder\nMake it better\nDo it faster\nMakes us stronger")
+            .unwrap();
+        assert_eq!(lyrics.lines[4].text, "Makes us stronger");
+        assert!(lyrics.merge("Work it").is_err());
+    }
+}
@@ -1,9 +1,81 @@
+mod auth;
 mod autotag;
+mod credit;
+mod date;
+mod error;
+mod history;
+mod library;
+mod lyrics;
+mod mpd;
+mod playlist;
+mod search;
 mod server;
+mod store;
+mod subsonic;
 mod sync;
 mod util;
 
 #[tokio::main]
 async fn main() {
-    server::serve(util::Configuration::open().unwrap()).await
+    let args = std::env::args().collect::<Vec<_>>();
+    match args.get(1).map(String::as_str) {
+        Some("import-musicbrainz") => {
+            let (Some(dump), Some(dbfile)) = (args.get(2), args.get(3)) else {
+                eprintln!(
+                    "Usage: {} import-musicbrainz <dump directory> <database>",
+                    args[0]
+                );
+                std::process::exit(2);
+            };
+            if let Err(e) = autotag::mbmirror_import(dump.as_ref(), dbfile.as_ref()) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Some("index-spotify") => {
+            let Some(dbfile) = args.get(2) else {
+                eprintln!("Usage: {} index-spotify <database>", args[0]);
+                std::process::exit(2);
+            };
+            if let Err(e) = autotag::spotifydb_index(dbfile.as_ref()) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Some("user-add") => {
+            let (Some(name), Some(role)) = (args.get(2), args.get(3)) else {
+                eprintln!(
+                    "Usage: {} user-add <name> <admin|editor|read-only> (password on stdin)",
+                    args[0]
+                );
+                std::process::exit(2);
+            };
+            let role = role.parse().unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            });
+            let mut password = String::new();
+            if std::io::stdin().read_line(&mut password).is_err() || password.trim().is_empty() {
+                eprintln!("Password missing");
+                std::process::exit(2);
+            }
+            let cfg = util::Configuration::open().unwrap();
+            match cfg
+                .users
+                .create(name, password.trim_end_matches(['\r', '\n']), role)
+                .await
+            {
+                Ok(true) => {}
+                Ok(false) => {
+                    eprintln!("User '{}' exists", name);
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => server::serve(util::Configuration::open().unwrap()).await,
+    }
 }
@@ -0,0 +1,1127 @@
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    process::Stdio,
+    str::FromStr,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::Notify,
+    task::JoinHandle,
+};
+
+use crate::{auth, error::Error, library, subsonic, util};
+
+const GREETING: &[u8] = b"OK MPD 0.23.5\n";
+
+/// Tags of songs as named by MPD, in the order they are listed
+const TAGS: [&str; 10] = [
+    "Artist",
+    "AlbumArtist",
+    "Album",
+    "Title",
+    "Track",
+    "Date",
+    "OriginalDate",
+    "Genre",
+    "Label",
+    "MUSICBRAINZ_TRACKID",
+];
+
+const COMMANDS: [&str; 33] = [
+    "add",
+    "addid",
+    "albumart",
+    "binarylimit",
+    "clear",
+    "close",
+    "commands",
+    "currentsong",
+    "delete",
+    "deleteid",
+    "find",
+    "idle",
+    "list",
+    "listall",
+    "listallinfo",
+    "lsinfo",
+    "next",
+    "noidle",
+    "notcommands",
+    "outputs",
+    "password",
+    "ping",
+    "play",
+    "playid",
+    "playlistid",
+    "playlistinfo",
+    "previous",
+    "readpicture",
+    "search",
+    "stats",
+    "status",
+    "stop",
+    "tagtypes",
+];
+
+/// Longest line accepted from clients, who are dropped on longer ones
+const LINE_LIMIT: usize = 64 * 1024;
+
+/// Most bytes of commands in one list, like `max_command_list_size` of MPD
+const LIST_LIMIT: usize = 2 * 1024 * 1024;
+
+/// How long clients may stay silent, unless waiting in `idle`
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Commands allowed before sending a password
+const PUBLIC: [&str; 5] = ["binarylimit", "commands", "notcommands", "password", "ping"];
+
+/// The next line from a client, without its line break, or an error past `LINE_LIMIT`
+async fn read_line(
+    reader: &mut BufReader<impl AsyncRead + Unpin>,
+) -> std::io::Result<Option<String>> {
+    let mut line = Vec::new();
+    let read = (&mut *reader)
+        .take(LINE_LIMIT as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Line too long",
+        ));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// A failed command, with one of the error codes of the MPD protocol
+#[derive(Debug)]
+struct Ack(u16, String);
+
+impl Ack {
+    fn arg(message: impl ToString) -> Self {
+        Ack(2, message.to_string())
+    }
+
+    fn no_exist(message: impl ToString) -> Self {
+        Ack(50, message.to_string())
+    }
+
+    fn system(message: impl ToString) -> Self {
+        Ack(52, message.to_string())
+    }
+}
+
+impl From<Error> for Ack {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::TrackNotFound => Ack::no_exist("No such song"),
+            err => Ack::system(err.message()),
+        }
+    }
+}
+
+/// The words of a command line, of which quoted ones may contain spaces and escaped quotes
+fn arguments(line: &str) -> Result<Vec<String>, Ack> {
+    let mut args = Vec::new();
+    let mut chars = line.trim_start().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        let mut arg = String::new();
+        if c == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => arg.extend(chars.next()),
+                    Some(c) => arg.push(c),
+                    None => return Err(Ack::arg("Missing closing '\"'")),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                arg.push(c);
+            }
+        }
+        args.push(arg);
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+    Ok(args)
+}
+
+fn arg(args: &[String], index: usize) -> Result<&str, Ack> {
+    args.get(index)
+        .map(String::as_str)
+        .ok_or_else(|| Ack::arg("too few arguments"))
+}
+
+fn number<T: FromStr>(arg: &str) -> Result<T, Ack> {
+    arg.parse()
+        .map_err(|_| Ack::arg(format!("Number expected: {}", arg)))
+}
+
+fn uri(track: &str) -> String {
+    format!("{}.m4a", track)
+}
+
+fn find<'a>(tracks: &'a [library::Summary], path: &str) -> Result<&'a library::Summary, Ack> {
+    let path = path.trim_start_matches('/');
+    tracks
+        .iter()
+        .find(|t| uri(&t.id) == path)
+        .ok_or_else(|| Ack::no_exist("No such song"))
+}
+
+/// The values of the tag `name` of a track, which are never empty
+fn tag(meta: &util::Metadata, name: &str) -> Vec<String> {
+    let values = match name.to_lowercase().as_str() {
+        "artist" => meta.artists.clone(),
+        "albumartist" => meta.album_artists.clone(),
+        "album" => meta.album.iter().cloned().collect(),
+        "title" => meta.title.iter().cloned().collect(),
+        "track" => meta.track.iter().map(u16::to_string).collect(),
+        "date" => meta.date.iter().map(ToString::to_string).collect(),
+        "originaldate" => meta.original_date.iter().map(ToString::to_string).collect(),
+        "genre" => meta.genres.clone(),
+        "label" => meta.label.iter().cloned().collect(),
+        "musicbrainz_trackid" => meta.mbid.iter().cloned().collect(),
+        _ => Vec::new(),
+    };
+    values.into_iter().filter(|v| !v.is_empty()).collect()
+}
+
+/// The values of a tag, `file` or `any`
+fn values(track: &library::Summary, name: &str) -> Vec<String> {
+    match name {
+        "file" | "base" => vec![uri(&track.id)],
+        "any" => TAGS
+            .iter()
+            .flat_map(|t| tag(&track.meta, t))
+            .chain([uri(&track.id)])
+            .collect(),
+        name => tag(&track.meta, name),
+    }
+}
+
+/// A tag as named in responses, checking that it is known
+fn tag_name(name: &str) -> Result<&'static str, Ack> {
+    TAGS.into_iter()
+        .chain(["file", "any", "base"])
+        .find(|t| t.eq_ignore_ascii_case(name))
+        .ok_or_else(|| Ack::arg(format!("Unknown tag type: {}", name)))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Equals,
+    NotEquals,
+    Contains,
+    StartsWith,
+}
+
+#[derive(Debug, PartialEq)]
+enum Filter {
+    /// A tag, `file`, `base` or `any`, in lowercase, compared to a value
+    Tag(String, Op, String),
+    Not(Box<Filter>),
+    And(Vec<Filter>),
+}
+
+impl Filter {
+    fn matches(&self, track: &library::Summary, ignore_case: bool) -> bool {
+        match self {
+            Filter::Not(filter) => !filter.matches(track, ignore_case),
+            Filter::And(filters) => filters.iter().all(|f| f.matches(track, ignore_case)),
+            Filter::Tag(name, op, value) => {
+                let fold = |s: &str| match ignore_case {
+                    true => s.to_lowercase(),
+                    false => s.to_string(),
+                };
+                let value = fold(value);
+                let mut values = values(track, name);
+                // a missing tag equals the empty string
+                if values.is_empty() {
+                    values.push(String::new());
+                }
+                let mut values = values.iter().map(|v| fold(v));
+                match op {
+                    Op::Equals => values.any(|v| v == value),
+                    Op::NotEquals => values.all(|v| v != value),
+                    Op::Contains => values.any(|v| v.contains(&value)),
+                    Op::StartsWith => values.any(|v| v.starts_with(&value)),
+                }
+            }
+        }
+    }
+}
+
+fn expect(input: &mut &str, token: char) -> Result<(), Ack> {
+    *input = input
+        .trim_start()
+        .strip_prefix(token)
+        .ok_or_else(|| Ack::arg(format!("'{}' expected", token)))?;
+    Ok(())
+}
+
+fn word<'a>(input: &mut &'a str) -> &'a str {
+    let trimmed = input.trim_start();
+    let (word, rest) = trimmed.split_at(
+        trimmed
+            .find(|c: char| c.is_whitespace() || c == ')')
+            .unwrap_or(trimmed.len()),
+    );
+    *input = rest;
+    word
+}
+
+fn quoted(input: &mut &str) -> Result<String, Ack> {
+    let trimmed = input.trim_start();
+    let mut chars = trimmed.char_indices();
+    let Some((_, quote @ ('"' | '\''))) = chars.next() else {
+        return Err(Ack::arg("Quoted value expected"));
+    };
+    let mut value = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => value.extend(chars.next().map(|(_, c)| c)),
+            c if c == quote => {
+                *input = &trimmed[i + 1..];
+                return Ok(value);
+            }
+            c => value.push(c),
+        }
+    }
+    Err(Ack::arg("Closing quote expected"))
+}
+
+const NESTING_LIMIT: usize = 32;
+
+/// A filter expression such as `((Artist == 'Daft Punk') AND (!(Album contains "live")))`,
+/// nested `depth` levels deep
+fn expression(input: &mut &str, depth: usize) -> Result<Filter, Ack> {
+    if depth == NESTING_LIMIT {
+        return Err(Ack::arg(format!(
+            "Nested deeper than {} levels",
+            NESTING_LIMIT
+        )));
+    }
+    expect(input, '(')?;
+    *input = input.trim_start();
+    let filter = if let Some(rest) = input.strip_prefix('!') {
+        *input = rest;
+        Filter::Not(Box::new(expression(input, depth + 1)?))
+    } else if input.starts_with('(') {
+        let mut filters = vec![expression(input, depth + 1)?];
+        while let Some(rest) = input.trim_start().strip_prefix("AND ") {
+            *input = rest;
+            filters.push(expression(input, depth + 1)?);
+        }
+        match filters.len() {
+            1 => filters.remove(0),
+            _ => Filter::And(filters),
+        }
+    } else {
+        let name = tag_name(word(input))?.to_lowercase();
+        let op = match name.as_str() {
+            "base" => Op::StartsWith,
+            _ => match word(input) {
+                "==" => Op::Equals,
+                "!=" => Op::NotEquals,
+                "contains" => Op::Contains,
+                "starts_with" => Op::StartsWith,
+                op => return Err(Ack::arg(format!("Unsupported operator: {}", op))),
+            },
+        };
+        Filter::Tag(name, op, quoted(input)?)
+    };
+    expect(input, ')')?;
+    Ok(filter)
+}
+
+const OPTIONS: [&str; 3] = ["sort", "window", "group"];
+
+/// The filter at the start of `args`, either an expression or pairs of tags and values compared
+/// by `op`, and the options after it
+fn filter(args: &[String], op: Op) -> Result<(Option<Filter>, &[String]), Ack> {
+    if let Some(first) = args.first()
+        && first.starts_with('(')
+    {
+        let mut input = first.as_str();
+        let filter = expression(&mut input, 0)?;
+        if !input.trim().is_empty() {
+            return Err(Ack::arg(format!(
+                "Unparsed garbage after expression: {}",
+                input
+            )));
+        }
+        return Ok((Some(filter), &args[1..]));
+    }
+    let mut filters = Vec::new();
+    let mut rest = args;
+    while let [name, value, tail @ ..] = rest
+        && !OPTIONS.contains(&name.as_str())
+    {
+        let name = tag_name(name)?.to_lowercase();
+        filters.push(Filter::Tag(name, op, value.clone()));
+        rest = tail;
+    }
+    Ok((
+        match filters.len() {
+            0 => None,
+            1 => filters.pop(),
+            _ => Some(Filter::And(filters)),
+        },
+        rest,
+    ))
+}
+
+#[derive(Default)]
+struct Options {
+    /// A tag to sort by and whether descending
+    sort: Option<(String, bool)>,
+    window: Option<(usize, usize)>,
+    group: Vec<String>,
+}
+
+fn options(args: &[String]) -> Result<Options, Ack> {
+    let mut options = Options::default();
+    for pair in args.chunks(2) {
+        let [option, value] = pair else {
+            return Err(Ack::arg(format!("Missing value of {}", pair[0])));
+        };
+        match option.as_str() {
+            "sort" => {
+                let (name, descending) = match value.strip_prefix('-') {
+                    Some(name) => (name, true),
+                    None => (value.as_str(), false),
+                };
+                options.sort = Some((tag_name(name)?.to_lowercase(), descending));
+            }
+            "window" => {
+                let (start, end) = value
+                    .split_once(':')
+                    .ok_or_else(|| Ack::arg(format!("Range expected: {}", value)))?;
+                let end = match end {
+                    "" => usize::MAX,
+                    end => number(end)?,
+                };
+                options.window = Some((number(start)?, end));
+            }
+            "group" => options.group.push(tag_name(value)?.to_lowercase()),
+            option => return Err(Ack::arg(format!("Unknown filter option: {}", option))),
+        }
+    }
+    Ok(options)
+}
+
+/// Numbers by value and text without regard to case
+fn compare(a: &str, b: &str) -> std::cmp::Ordering {
+    match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.to_lowercase().cmp(&b.to_lowercase()),
+    }
+}
+
+/// The distinct values of the tag `name` of `tracks`, each after the values of the `groups`
+/// it differs in from the one before
+fn list(out: &mut Vec<u8>, tracks: &[&library::Summary], name: &str, groups: &[String]) {
+    let mut rows = Vec::new();
+    for track in tracks {
+        let group = groups
+            .iter()
+            .map(|g| values(track, g).into_iter().next().unwrap_or_default())
+            .collect::<Vec<_>>();
+        for value in values(track, name) {
+            rows.push((group.clone(), value));
+        }
+    }
+    rows.sort();
+    rows.dedup();
+    let mut last: Option<&Vec<String>> = None;
+    for (group, value) in &rows {
+        let changed = (0..groups.len())
+            .find(|&i| last.is_none_or(|l| l[i] != group[i]))
+            .unwrap_or(groups.len());
+        for i in changed..groups.len() {
+            line(out, tag_name(&groups[i]).unwrap_or_default(), &group[i]);
+        }
+        line(out, tag_name(name).unwrap_or_default(), value);
+        last = Some(group);
+    }
+}
+
+fn line(out: &mut Vec<u8>, key: &str, value: impl Display) {
+    let value = value.to_string().replace('\n', " ");
+    out.extend(format!("{}: {}\n", key, value).into_bytes());
+}
+
+fn song(out: &mut Vec<u8>, track: &library::Summary, dir: &Path) {
+    line(out, "file", uri(&track.id));
+    let modified = fs::metadata(dir.join(&track.id).with_added_extension("m4a"))
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok());
+    if let Some(modified) = modified {
+        line(
+            out,
+            "Last-Modified",
+            subsonic::timestamp(modified.as_secs() as i64),
+        );
+    }
+    for name in TAGS {
+        for value in tag(&track.meta, name) {
+            line(out, name, value);
+        }
+    }
+    if let Some(duration) = track.meta.duration {
+        line(out, "Time", duration);
+        line(out, "duration", duration);
+    }
+}
+
+/// The queue and what of it plays, shared by every client
+#[derive(Default)]
+struct Player {
+    /// Queue IDs and tracks, in order
+    queue: Vec<(u32, String)>,
+    last_id: u32,
+    /// Increases with every change of the queue
+    version: u32,
+    current: Option<usize>,
+    playing: Option<JoinHandle<()>>,
+}
+
+impl Player {
+    fn add(&mut self, track: String) -> u32 {
+        self.last_id += 1;
+        self.queue.push((self.last_id, track));
+        self.version += 1;
+        self.last_id
+    }
+
+    fn remove(&mut self, pos: usize) -> Result<(), Ack> {
+        if pos >= self.queue.len() {
+            return Err(Ack::arg("Bad song index"));
+        }
+        self.queue.remove(pos);
+        self.version += 1;
+        match self.current {
+            Some(current) if current == pos => {
+                self.stop();
+                self.current = None;
+            }
+            Some(current) if current > pos => self.current = Some(current - 1),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn position(&self, id: u32) -> Result<usize, Ack> {
+        self.queue
+            .iter()
+            .position(|(i, _)| *i == id)
+            .ok_or_else(|| Ack::no_exist("No such song"))
+    }
+
+    fn stop(&mut self) {
+        if let Some(playing) = self.playing.take() {
+            playing.abort();
+        }
+    }
+
+    fn is_playing(&self) -> bool {
+        self.playing.as_ref().is_some_and(|p| !p.is_finished())
+    }
+}
+
+struct Shared {
+    player: Mutex<Player>,
+    /// Notified whenever the queue or playback changes, ending `idle`
+    changed: Notify,
+    started: Instant,
+}
+
+impl Shared {
+    fn player(&self) -> std::sync::MutexGuard<'_, Player> {
+        self.player.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Plays the queue from the current song on through `output`, one process per song
+async fn play(shared: Arc<Shared>, output: Vec<String>, dir: PathBuf) {
+    loop {
+        let file = {
+            let player = shared.player();
+            player
+                .current
+                .and_then(|c| player.queue.get(c))
+                .map(|(_, track)| dir.join(track).with_added_extension("m4a"))
+        };
+        let Some(file) = file else {
+            break;
+        };
+        shared.changed.notify_waiters();
+        let played = tokio::process::Command::new(&output[0])
+            .args(&output[1..])
+            .arg(&file)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .status()
+            .await;
+        if let Err(e) = played {
+            eprintln!("Playing {} failed: {}", file.display(), e);
+            break;
+        }
+        let mut player = shared.player();
+        player.current = player
+            .current
+            .map(|c| c + 1)
+            .filter(|&c| c < player.queue.len());
+    }
+    shared.changed.notify_waiters();
+}
+
+struct Connection {
+    cfg: Arc<util::Configuration>,
+    shared: Arc<Shared>,
+    user: Option<auth::User>,
+    /// Most bytes of artwork sent at once
+    binary_limit: usize,
+}
+
+impl Connection {
+    async fn handle(mut self, stream: TcpStream) -> std::io::Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut reader = BufReader::new(read);
+        write.write_all(GREETING).await?;
+        // commands of a list, their size and whether to acknowledge each
+        let mut list: Option<(Vec<String>, usize, bool)> = None;
+        loop {
+            let line = match tokio::time::timeout(TIMEOUT, read_line(&mut reader)).await {
+                Ok(line) => line?,
+                Err(_) => break,
+            };
+            let Some(line) = line else {
+                break;
+            };
+            let response = if let Some((commands, size, ok)) = list.as_mut() {
+                if line != "command_list_end" {
+                    *size += line.len();
+                    // the client is dropped, as it would not expect an answer yet
+                    if *size > LIST_LIMIT {
+                        break;
+                    }
+                    commands.push(line);
+                    continue;
+                }
+                let (commands, ok) = (std::mem::take(commands), *ok);
+                list = None;
+                self.batch(&commands, ok).await
+            } else if line == "command_list_begin" || line == "command_list_ok_begin" {
+                list = Some((Vec::new(), 0, line == "command_list_ok_begin"));
+                continue;
+            } else if line == "close" {
+                break;
+            } else if (line == "idle" || line.starts_with("idle ")) && self.user.is_some() {
+                // waiting as long as it takes, unlike for other commands
+                tokio::select! {
+                    line = read_line(&mut reader) => match line?.as_deref() {
+                        Some("noidle") => b"OK\n".to_vec(),
+                        _ => break,
+                    },
+                    _ = self.shared.changed.notified() => {
+                        b"changed: player\nchanged: playlist\nOK\n".to_vec()
+                    }
+                }
+            } else {
+                self.batch(std::slice::from_ref(&line), false).await
+            };
+            write.write_all(&response).await?;
+        }
+        Ok(())
+    }
+
+    /// Runs `commands` in order until one fails, answering for all of them
+    async fn batch(&mut self, commands: &[String], ok: bool) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (i, command) in commands.iter().enumerate() {
+            match self.run(command).await {
+                Ok(response) => {
+                    out.extend(response);
+                    if ok {
+                        out.extend(b"list_OK\n");
+                    }
+                }
+                Err(Ack(code, message)) => {
+                    let name = command.split_whitespace().next().unwrap_or_default();
+                    out.extend(
+                        format!("ACK [{}@{}] {{{}}} {}\n", code, i, name, message).into_bytes(),
+                    );
+                    return out;
+                }
+            }
+        }
+        out.extend(b"OK\n");
+        out
+    }
+
+    async fn library(&self) -> Result<(PathBuf, Vec<library::Summary>), Ack> {
+        let dir = self.cfg.get_library().map_err(Ack::system)?;
+        let tracks = self.cfg.index.tracks(&dir).await?;
+        Ok((dir, tracks))
+    }
+
+    /// Plays the queue from `pos` on
+    fn play(&self, player: &mut Player, pos: usize) -> Result<(), Ack> {
+        let output = self
+            .cfg
+            .mpd_output()
+            .ok_or_else(|| Ack::system("No output configured"))?;
+        let dir = self.cfg.get_library().map_err(Ack::system)?;
+        if pos >= player.queue.len() {
+            return Err(Ack::arg("Bad song index"));
+        }
+        player.stop();
+        player.current = Some(pos);
+        player.playing = Some(tokio::spawn(play(self.shared.clone(), output, dir)));
+        Ok(())
+    }
+
+    async fn run(&mut self, command: &str) -> Result<Vec<u8>, Ack> {
+        let args = arguments(command)?;
+        let Some((command, args)) = args.split_first() else {
+            return Err(Ack(5, "No command given".to_string()));
+        };
+        if self.user.is_none() && !PUBLIC.contains(&command.as_str()) {
+            return Err(Ack(
+                4,
+                format!("you don't have permission for \"{}\"", command),
+            ));
+        }
+        let mut out = Vec::new();
+        match command.as_str() {
+            "ping" | "noidle" => {}
+            "password" => {
+                let password = arg(args, 0)?;
+                let user = match password.split_once(':') {
+                    _ if password.starts_with("rb_") => self.cfg.users.token(password).await,
+                    Some((name, password)) => self.cfg.users.verify(name, password).await,
+                    None => Ok(None),
+                }
+                .map_err(Ack::system)?;
+                self.user = Some(user.ok_or_else(|| Ack(3, "incorrect password".to_string()))?);
+            }
+            "commands" => {
+                for command in COMMANDS {
+                    line(&mut out, "command", command);
+                }
+            }
+            "notcommands" => {}
+            "binarylimit" => self.binary_limit = number::<usize>(arg(args, 0)?)?.max(64),
+            "tagtypes" => {
+                // every tag is always sent, whatever the client asks for
+                if args.is_empty() {
+                    for name in TAGS {
+                        line(&mut out, "tagtype", name);
+                    }
+                }
+            }
+            "outputs" => {
+                if let Some(output) = self.cfg.mpd_output() {
+                    line(&mut out, "outputid", 0);
+                    line(&mut out, "outputname", &output[0]);
+                    line(&mut out, "plugin", "pipe");
+                    line(&mut out, "outputenabled", 1);
+                }
+            }
+            "stats" => {
+                let (_, tracks) = self.library().await?;
+                let distinct = |name: &str| {
+                    tracks
+                        .iter()
+                        .flat_map(|t| tag(&t.meta, name))
+                        .collect::<HashSet<_>>()
+                        .len()
+                };
+                line(&mut out, "artists", distinct("artist"));
+                line(&mut out, "albums", distinct("album"));
+                line(&mut out, "songs", tracks.len());
+                line(&mut out, "uptime", self.shared.started.elapsed().as_secs());
+                line(
+                    &mut out,
+                    "db_playtime",
+                    tracks
+                        .iter()
+                        .filter_map(|t| t.meta.duration)
+                        .map(u64::from)
+                        .sum::<u64>(),
+                );
+                line(&mut out, "db_update", 0);
+                line(&mut out, "playtime", 0);
+            }
+            "lsinfo" | "listall" | "listallinfo" => {
+                let (dir, tracks) = self.library().await?;
+                let path = args.first().map_or("", |a| a.trim_matches('/'));
+                // the library has no directories
+                let tracks = match path {
+                    "" => tracks.iter().collect(),
+                    path => vec![find(&tracks, path)?],
+                };
+                for track in tracks {
+                    match command.as_str() {
+                        "listall" => line(&mut out, "file", uri(&track.id)),
+                        _ => song(&mut out, track, &dir),
+                    }
+                }
+            }
+            "find" | "search" => {
+                let (op, ignore_case) = match command.as_str() {
+                    "find" => (Op::Equals, false),
+                    _ => (Op::Contains, true),
+                };
+                let (filter, rest) = filter(args, op)?;
+                let Some(filter) = filter else {
+                    return Err(Ack::arg("Filter expected"));
+                };
+                let options = options(rest)?;
+                let (dir, tracks) = self.library().await?;
+                let mut tracks = tracks
+                    .iter()
+                    .filter(|t| filter.matches(t, ignore_case))
+                    .collect::<Vec<_>>();
+                if let Some((name, descending)) = &options.sort {
+                    tracks.sort_by(|a, b| {
+                        let (a, b) = (values(a, name), values(b, name));
+                        let first = |v: &Vec<String>| v.first().cloned().unwrap_or_default();
+                        compare(&first(&a), &first(&b))
+                    });
+                    if *descending {
+                        tracks.reverse();
+                    }
+                }
+                let (start, end) = options.window.unwrap_or((0, usize::MAX));
+                for track in tracks.iter().take(end).skip(start) {
+                    song(&mut out, track, &dir);
+                }
+            }
+            "list" => {
+                let name = tag_name(arg(args, 0)?)?.to_lowercase();
+                let (filter, rest) = match &args[1..] {
+                    // the artist of albums, as sent by old clients
+                    [artist] if name == "album" => (
+                        Some(Filter::Tag(
+                            "artist".to_string(),
+                            Op::Equals,
+                            artist.clone(),
+                        )),
+                        &[][..],
+                    ),
+                    args => filter(args, Op::Equals)?,
+                };
+                let options = options(rest)?;
+                let (_, tracks) = self.library().await?;
+                let tracks = tracks
+                    .iter()
+                    .filter(|t| filter.as_ref().is_none_or(|f| f.matches(t, false)))
+                    .collect::<Vec<_>>();
+                list(&mut out, &tracks, &name, &options.group);
+            }
+            "albumart" | "readpicture" => {
+                let (dir, tracks) = self.library().await?;
+                let track = find(&tracks, arg(args, 0)?)?;
+                let offset = number::<usize>(arg(args, 1)?)?;
+                let file = dir.join(&track.id).with_added_extension("m4a");
+                let tag = tokio::task::spawn_blocking(move || mp4ameta::Tag::read_from_path(file))
+                    .await
+                    .map_err(Ack::system)?
+                    .map_err(Error::tag)?;
+                match tag.artwork() {
+                    Some(artwork) => {
+                        let data = artwork.data;
+                        if offset > data.len() {
+                            return Err(Ack::arg("Bad file offset"));
+                        }
+                        let chunk = &data[offset..data.len().min(offset + self.binary_limit)];
+                        line(&mut out, "size", data.len());
+                        if command == "readpicture" {
+                            let mime = match artwork.fmt {
+                                mp4ameta::ImgFmt::Jpeg => "image/jpeg",
+                                mp4ameta::ImgFmt::Png => "image/png",
+                                mp4ameta::ImgFmt::Bmp => "image/bmp",
+                            };
+                            line(&mut out, "type", mime);
+                        }
+                        line(&mut out, "binary", chunk.len());
+                        out.extend(chunk);
+                        out.push(b'\n');
+                    }
+                    // no picture is no error for `readpicture`
+                    None if command == "readpicture" => {}
+                    None => return Err(Ack::no_exist("No file exists")),
+                }
+            }
+            "add" | "addid" => {
+                let (_, tracks) = self.library().await?;
+                let added = match arg(args, 0)?.trim_matches('/') {
+                    "" if command == "add" => tracks.iter().collect(),
+                    path => vec![find(&tracks, path)?],
+                };
+                let mut player = self.shared.player();
+                for track in added {
+                    let id = player.add(track.id.clone());
+                    if command == "addid" {
+                        line(&mut out, "Id", id);
+                    }
+                }
+            }
+            "clear" => {
+                let mut player = self.shared.player();
+                player.stop();
+                player.queue.clear();
+                player.current = None;
+                player.version += 1;
+            }
+            "delete" => self.shared.player().remove(number(arg(args, 0)?)?)?,
+            "deleteid" => {
+                let mut player = self.shared.player();
+                let pos = player.position(number(arg(args, 0)?)?)?;
+                player.remove(pos)?;
+            }
+            "playlistinfo" | "playlistid" | "currentsong" => {
+                let (dir, tracks) = self.library().await?;
+                let player = self.shared.player();
+                let positions = match (command.as_str(), args.first()) {
+                    ("currentsong", _) => player.current.into_iter().collect(),
+                    ("playlistinfo", Some(pos)) => {
+                        let pos = number::<usize>(pos)?;
+                        if pos >= player.queue.len() {
+                            return Err(Ack::arg("Bad song index"));
+                        }
+                        vec![pos]
+                    }
+                    ("playlistid", Some(id)) => vec![player.position(number(id)?)?],
+                    _ => (0..player.queue.len()).collect::<Vec<_>>(),
+                };
+                for pos in positions {
+                    let (id, track) = &player.queue[pos];
+                    match tracks.iter().find(|t| &t.id == track) {
+                        Some(track) => song(&mut out, track, &dir),
+                        // deleted since it was added
+                        None => line(&mut out, "file", uri(track)),
+                    }
+                    line(&mut out, "Pos", pos);
+                    line(&mut out, "Id", id);
+                }
+            }
+            "play" | "playid" => {
+                let mut player = self.shared.player();
+                let pos = match (command.as_str(), args.first()) {
+                    ("play", Some(pos)) => number(pos)?,
+                    ("playid", Some(id)) => player.position(number(id)?)?,
+                    _ => player.current.unwrap_or_default(),
+                };
+                self.play(&mut player, pos)?;
+            }
+            "stop" => self.shared.player().stop(),
+            "next" | "previous" => {
+                let mut player = self.shared.player();
+                let pos = match (command.as_str(), player.current) {
+                    (_, None) => None,
+                    ("next", Some(current)) => Some(current + 1),
+                    (_, Some(current)) => current.checked_sub(1),
+                };
+                match pos.filter(|&p| p < player.queue.len()) {
+                    Some(pos) if player.is_playing() => self.play(&mut player, pos)?,
+                    Some(pos) => player.current = Some(pos),
+                    None => {
+                        player.stop();
+                        player.current = None;
+                    }
+                }
+            }
+            "status" => {
+                let player = self.shared.player();
+                line(&mut out, "repeat", 0);
+                line(&mut out, "random", 0);
+                line(&mut out, "single", 0);
+                line(&mut out, "consume", 0);
+                line(&mut out, "playlist", player.version);
+                line(&mut out, "playlistlength", player.queue.len());
+                line(
+                    &mut out,
+                    "state",
+                    if player.is_playing() { "play" } else { "stop" },
+                );
+                if let Some(current) = player.current {
+                    line(&mut out, "song", current);
+                    line(&mut out, "songid", player.queue[current].0);
+                }
+            }
+            command => return Err(Ack(5, format!("unknown command \"{}\"", command))),
+        }
+        if matches!(
+            command.as_str(),
+            "add"
+                | "addid"
+                | "clear"
+                | "delete"
+                | "deleteid"
+                | "play"
+                | "playid"
+                | "stop"
+                | "next"
+                | "previous"
+        ) {
+            self.shared.changed.notify_waiters();
+        }
+        Ok(out)
+    }
+}
+
+/// Answers MPD clients on `address`, sharing one queue among all of them
+pub(crate) async fn serve(cfg: Arc<util::Configuration>, address: String) {
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Listening for MPD clients on {} failed: {}", address, e);
+            return;
+        }
+    };
+    let shared = Arc::new(Shared {
+        player: Mutex::default(),
+        changed: Notify::new(),
+        started: Instant::now(),
+    });
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let connection = Connection {
+            cfg: cfg.clone(),
+            shared: shared.clone(),
+            user: None,
+            binary_limit: 8192,
+        };
+        // clients disconnect as they please
+        tokio::spawn(async move { connection.handle(stream).await.ok() });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(id: &str, artist: &str, album: &str) -> library::Summary {
+        library::Summary {
+            id: id.to_string(),
+            meta: util::Metadata {
+                artists: vec![artist.to_string()],
+                album: Some(album.to_string()),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn arguments() {
+        assert_eq!(
+            super::arguments(r#"find  "(Artist == \"Daft Punk\")" sort Track"#).unwrap(),
+            ["find", r#"(Artist == "Daft Punk")"#, "sort", "Track"]
+        );
+        assert_eq!(super::arguments("status").unwrap(), ["status"]);
+        assert!(super::arguments(r#"find "album"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn read_line() {
+        let mut reader = BufReader::new(&b"ping\r\nstatus\n"[..]);
+        assert_eq!(
+            super::read_line(&mut reader).await.unwrap().unwrap(),
+            "ping"
+        );
+        assert_eq!(
+            super::read_line(&mut reader).await.unwrap().unwrap(),
+            "status"
+        );
+        assert!(super::read_line(&mut reader).await.unwrap().is_none());
+        let long = vec![b'a'; LINE_LIMIT + 1];
+        assert!(
+            super::read_line(&mut BufReader::new(&long[..]))
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn filter() {
+        let args = [
+            r#"((Artist == 'Daft Punk') AND (!(Album contains "live")))"#.to_string(),
+            "window".to_string(),
+            "0:1".to_string(),
+        ];
+        let (filter, rest) = super::filter(&args, Op::Equals).unwrap();
+        let filter = filter.unwrap();
+        assert_eq!(
+            filter,
+            Filter::And(vec![
+                Filter::Tag("artist".to_string(), Op::Equals, "Daft Punk".to_string()),
+                Filter::Not(Box::new(Filter::Tag(
+                    "album".to_string(),
+                    Op::Contains,
+                    "live".to_string()
+                ))),
+            ])
+        );
+        assert_eq!(rest, ["window", "0:1"]);
+        assert!(filter.matches(&track("a", "Daft Punk", "Discovery"), false));
+        assert!(!filter.matches(&track("b", "Daft Punk", "Alive 2007"), false));
+        assert!(!filter.matches(&track("c", "daft punk", "Discovery"), false));
+        assert!(filter.matches(&track("c", "daft punk", "Discovery"), true));
+
+        let args = ["any".to_string(), "b.m4a".to_string()];
+        let (filter, _) = super::filter(&args, Op::Contains).unwrap();
+        assert!(filter.unwrap().matches(&track("b", "", ""), true));
+        assert!(super::filter(&["(Artist =~ 'x')".to_string()], Op::Equals).is_err());
+        assert!(super::filter(&["(Mood == 'x')".to_string()], Op::Equals).is_err());
+
+        let nested = |depth: usize| "(!".repeat(depth) + "(Artist == 'x')" + &")".repeat(depth);
+        assert!(super::filter(&[nested(NESTING_LIMIT - 1)], Op::Equals).is_ok());
+        assert!(super::filter(&[nested(NESTING_LIMIT)], Op::Equals).is_err());
+        assert!(super::filter(&[nested(20_000)], Op::Equals).is_err());
+    }
+
+    #[test]
+    fn list() {
+        let tracks = [
+            track("a", "Daft Punk", "Discovery"),
+            track("b", "Daft Punk", "Homework"),
+            track("c", "Justice", "Cross"),
+            track("d", "Daft Punk", "Discovery"),
+        ];
+        let mut out = Vec::new();
+        super::list(
+            &mut out,
+            &tracks.iter().collect::<Vec<_>>(),
+            "album",
+            &["artist".to_string()],
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Artist: Daft Punk\nAlbum: Discovery\nAlbum: Homework\nArtist: Justice\nAlbum: Cross\n"
+        );
+    }
+}
@@ -0,0 +1,280 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::store::Store;
+
+/// On-disk store of the playlists of every user
+pub(crate) struct Playlists {
+    store: Store,
+}
+
+#[derive(Debug)]
+pub(crate) struct Playlist {
+    pub(crate) id: i64,
+    pub(crate) name: String,
+    pub(crate) owner: String,
+    pub(crate) public: bool,
+    pub(crate) comment: Option<String>,
+    /// Seconds since the Unix epoch
+    pub(crate) created: i64,
+    pub(crate) changed: i64,
+    /// IDs of the tracks in order, which may no longer be in the library
+    pub(crate) tracks: Vec<String>,
+}
+
+/// Changes to a playlist, of which unset fields stay as they are
+#[derive(Default)]
+pub(crate) struct Update {
+    pub(crate) name: Option<String>,
+    pub(crate) comment: Option<String>,
+    pub(crate) public: Option<bool>,
+    /// Positions of tracks to remove, before adding any
+    pub(crate) remove: Vec<usize>,
+    pub(crate) add: Vec<String>,
+}
+
+impl Playlists {
+    pub(crate) fn new(dbfile: String) -> Result<Self, String> {
+        Ok(Self {
+            store: Store::new(
+                dbfile,
+                "CREATE TABLE IF NOT EXISTS playlists (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    owner TEXT NOT NULL,
+    public INTEGER NOT NULL,
+    comment TEXT,
+    created INTEGER NOT NULL,
+    changed INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS entries (
+    playlist INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    track TEXT NOT NULL,
+    PRIMARY KEY (playlist, position)
+);",
+            )?,
+        })
+    }
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default()
+    }
+
+    fn tracks(client: &rusqlite::Connection, id: i64) -> Result<Vec<String>, String> {
+        let mut query = client
+            .prepare("SELECT track FROM entries WHERE playlist = ?1 ORDER BY position;")
+            .map_err(|e| e.to_string())?;
+        query
+            .query_map([id], |row| row.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())
+    }
+
+    fn set_tracks(client: &rusqlite::Connection, id: i64, tracks: &[String]) -> Result<(), String> {
+        client
+            .execute("DELETE FROM entries WHERE playlist = ?1;", [id])
+            .map_err(|e| e.to_string())?;
+        for (position, track) in tracks.iter().enumerate() {
+            client
+                .execute(
+                    "INSERT INTO entries (playlist, position, track) VALUES (?1, ?2, ?3);",
+                    (id, position as i64, track),
+                )
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Playlists of `user` and the public ones of others
+    pub(crate) async fn list(&self, user: &str) -> Result<Vec<Playlist>, String> {
+        let user = user.to_string();
+        self.store
+            .run(move |client| {
+                let mut query = client
+                    .prepare(
+                        "SELECT id, name, owner, public, comment, created, changed FROM playlists
+WHERE owner = ?1 OR public
+ORDER BY name, id;",
+                    )
+                    .map_err(|e| e.to_string())?;
+                let playlists = query
+                    .query_map([user], |row| {
+                        Ok(Playlist {
+                            id: row.get(0)?,
+                            name: row.get(1)?,
+                            owner: row.get(2)?,
+                            public: row.get(3)?,
+                            comment: row.get(4)?,
+                            created: row.get(5)?,
+                            changed: row.get(6)?,
+                            tracks: Vec::new(),
+                        })
+                    })
+                    .map_err(|e| e.to_string())?
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| e.to_string())?;
+                playlists
+                    .into_iter()
+                    .map(|p| {
+                        Ok(Playlist {
+                            tracks: Playlists::tracks(client, p.id)?,
+                            ..p
+                        })
+                    })
+                    .collect()
+            })
+            .await
+    }
+
+    pub(crate) async fn get(&self, id: i64) -> Result<Option<Playlist>, String> {
+        self.store
+            .run(move |client| {
+                let playlist = client.query_row(
+                    "SELECT id, name, owner, public, comment, created, changed FROM playlists
+WHERE id = ?1;",
+                    [id],
+                    |row| {
+                        Ok(Playlist {
+                            id: row.get(0)?,
+                            name: row.get(1)?,
+                            owner: row.get(2)?,
+                            public: row.get(3)?,
+                            comment: row.get(4)?,
+                            created: row.get(5)?,
+                            changed: row.get(6)?,
+                            tracks: Vec::new(),
+                        })
+                    },
+                );
+                match playlist {
+                    Ok(playlist) => Ok(Some(Playlist {
+                        tracks: Playlists::tracks(client, id)?,
+                        ..playlist
+                    })),
+                    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                    Err(e) => Err(e.to_string()),
+                }
+            })
+            .await
+    }
+
+    pub(crate) async fn create(
+        &self,
+        owner: &str,
+        name: &str,
+        tracks: &[String],
+    ) -> Result<i64, String> {
+        let (owner, name, tracks) = (owner.to_string(), name.to_string(), tracks.to_vec());
+        self.store
+            .run(move |client| {
+                let now = Playlists::now();
+                client
+                    .execute(
+                        "INSERT INTO playlists (name, owner, public, created, changed)
+VALUES (?1, ?2, FALSE, ?3, ?3);",
+                        (name, owner, now),
+                    )
+                    .map_err(|e| e.to_string())?;
+                let id = client.last_insert_rowid();
+                Playlists::set_tracks(client, id, &tracks)?;
+                Ok(id)
+            })
+            .await
+    }
+
+    /// Replaces the tracks of a playlist
+    pub(crate) async fn replace(&self, id: i64, tracks: &[String]) -> Result<(), String> {
+        let tracks = tracks.to_vec();
+        self.store
+            .run(move |client| {
+                client
+                    .execute(
+                        "UPDATE playlists SET changed = ?2 WHERE id = ?1;",
+                        (id, Playlists::now()),
+                    )
+                    .map_err(|e| e.to_string())?;
+                Playlists::set_tracks(client, id, &tracks)
+            })
+            .await
+    }
+
+    pub(crate) async fn update(&self, id: i64, update: Update) -> Result<(), String> {
+        self.store
+            .run(move |client| {
+                client
+                    .execute(
+                        "UPDATE playlists SET
+    name = COALESCE(?2, name),
+    comment = COALESCE(?3, comment),
+    public = COALESCE(?4, public),
+    changed = ?5
+WHERE id = ?1;",
+                        (
+                            id,
+                            update.name,
+                            update.comment,
+                            update.public,
+                            Playlists::now(),
+                        ),
+                    )
+                    .map_err(|e| e.to_string())?;
+                let tracks = Playlists::tracks(client, id)?
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(position, _)| !update.remove.contains(position))
+                    .map(|(_, track)| track)
+                    .chain(update.add)
+                    .collect::<Vec<_>>();
+                Playlists::set_tracks(client, id, &tracks)
+            })
+            .await
+    }
+
+    pub(crate) async fn delete(&self, id: i64) -> Result<(), String> {
+        self.store
+            .run(move |client| {
+                client
+                    .execute("DELETE FROM entries WHERE playlist = ?1;", [id])
+                    .map_err(|e| e.to_string())?;
+                client
+                    .execute("DELETE FROM playlists WHERE id = ?1;", [id])
+                    .map_err(|e| e.to_string())?;
+                Ok(())
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn playlists() {
+        let playlists = Playlists::new(":memory:".to_string()).unwrap();
+        let tracks = ["a", "b", "c"].map(str::to_string);
+        let id = playlists.create("alice", "Mix", &tracks).await.unwrap();
+        playlists
+            .update(
+                id,
+                Update {
+                    public: Some(true),
+                    remove: vec![0, 2],
+                    add: vec!["d".to_string()],
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let playlist = playlists.get(id).await.unwrap().unwrap();
+        assert_eq!(playlist.tracks, ["b", "d"]);
+        assert!(playlist.public);
+        assert_eq!(playlists.list("bob").await.unwrap().len(), 1);
+        playlists.delete(id).await.unwrap();
+        assert!(playlists.get(id).await.unwrap().is_none());
+    }
+}
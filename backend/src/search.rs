@@ -0,0 +1,410 @@
+use std::cmp::Ordering;
+
+use serde_json::{Map, Value};
+
+use crate::{error::Error, library};
+
+/// Fields searched for bare words and phrases
+const TEXT: [&str; 4] = ["title", "artists", "album", "album_artists"];
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Or,
+    And,
+    Not,
+    /// A term as typed, quotes included
+    Word(String),
+}
+
+/// A search over the fields of tracks, e.g. `artist:"daft punk" year:1995..2001 -genre:house missing:lyrics`
+#[derive(Debug, PartialEq)]
+pub(crate) enum Query {
+    All(Vec<Query>),
+    Any(Vec<Query>),
+    Not(Box<Query>),
+    /// Words found in the text fields, allowing for typos and unfinished words
+    Text(Vec<String>),
+    /// Words found next to each other in one of the text fields
+    Phrase(String),
+    /// Whether a field has a value
+    Has(String),
+    /// Text found within a field, or a number equal to it
+    Contains(String, String),
+    /// A field ordered as given against a bound, or equal to it if inclusive
+    Compare(String, Ordering, bool, Value),
+    /// A field between two bounds, both inclusive
+    Range(String, Option<Value>, Option<Value>),
+}
+
+/// Lowercase words of `text`
+fn words(text: &str) -> Vec<String> {
+    text.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .to_lowercase()
+        .split_whitespace()
+        .map(str::to_string)
+        .collect()
+}
+
+/// Edits between `a` and `b`, i.e. insertions, deletions and substitutions of characters
+fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b) in b.iter().enumerate() {
+            current[j + 1] = (previous[j] + usize::from(a != *b))
+                .min(previous[j + 1] + 1)
+                .min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Whether a word typed in a query means `word`, allowing a typo per four letters
+fn similar(typed: &str, word: &str) -> bool {
+    let tolerance = typed.chars().count() / 4;
+    word.starts_with(typed) || distance(typed, word) <= tolerance.min(2)
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | '-' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    _ => Token::Not,
+                });
+            }
+            _ => {
+                let mut word = String::new();
+                let mut quoted = false;
+                while let Some(&c) = chars.peek() {
+                    if !quoted && (c.is_whitespace() || c == '(' || c == ')') {
+                        break;
+                    }
+                    quoted ^= c == '"';
+                    word.push(c);
+                    chars.next();
+                }
+                if quoted {
+                    return Err(Error::InvalidQuery(format!("Unclosed quote in '{}'", word)));
+                }
+                tokens.push(match word.as_str() {
+                    "OR" | "|" => Token::Or,
+                    "AND" | "&" => Token::And,
+                    "NOT" => Token::Not,
+                    _ => Token::Word(word),
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// A bound of a comparison or range, as a number where possible
+fn bound(value: &str) -> Option<Value> {
+    match value {
+        "" => None,
+        value => Some(match (value.parse::<i64>(), value.parse::<f64>()) {
+            (Ok(number), _) => Value::from(number),
+            (_, Ok(number)) => Value::from(number),
+            _ => Value::from(value.to_lowercase()),
+        }),
+    }
+}
+
+/// Ordering of a value against a bound, where text starting with the bound equals it, e.g. dates
+/// in the year `2001`
+fn against(value: &Value, bound: &Value) -> Ordering {
+    match value {
+        Value::String(value) => {
+            let (value, bound) = (value.to_lowercase(), library::text(bound));
+            match value.starts_with(&bound) {
+                true => Ordering::Equal,
+                false => value.cmp(&bound),
+            }
+        }
+        value => library::compare(Some(value), Some(bound)),
+    }
+}
+
+/// Values of a field, one per element of a list
+fn values(fields: &Map<String, Value>, field: &str) -> Vec<Value> {
+    let value = match field {
+        "year" => library::year_of(fields).map(Value::from),
+        field => fields.get(field).cloned(),
+    };
+    match value {
+        Some(Value::Array(values)) => values,
+        Some(Value::Null) | None => Vec::new(),
+        Some(value) => vec![value],
+    }
+}
+
+/// Most groups and negations nested in one another, as each takes a frame to parse and evaluate
+const NESTING_LIMIT: usize = 32;
+
+struct Parser {
+    tokens: std::iter::Peekable<std::vec::IntoIter<Token>>,
+    depth: usize,
+}
+
+impl Parser {
+    fn nested(&mut self, parse: fn(&mut Parser) -> Result<Query, Error>) -> Result<Query, Error> {
+        if self.depth == NESTING_LIMIT {
+            return Err(Error::InvalidQuery(format!(
+                "Nested deeper than {} levels",
+                NESTING_LIMIT
+            )));
+        }
+        self.depth += 1;
+        let query = parse(self);
+        self.depth -= 1;
+        query
+    }
+
+    fn any(&mut self) -> Result<Query, Error> {
+        let mut any = vec![self.all()?];
+        while self.tokens.next_if_eq(&Token::Or).is_some() {
+            any.push(self.all()?);
+        }
+        Ok(match any.len() {
+            1 => any.remove(0),
+            _ => Query::Any(any),
+        })
+    }
+
+    fn all(&mut self) -> Result<Query, Error> {
+        let mut all = vec![self.unary()?];
+        loop {
+            match self.tokens.peek() {
+                None | Some(Token::Close) | Some(Token::Or) => break,
+                Some(Token::And) => {
+                    self.tokens.next();
+                }
+                _ => {}
+            }
+            all.push(self.unary()?);
+        }
+        Ok(match all.len() {
+            1 => all.remove(0),
+            _ => Query::All(all),
+        })
+    }
+
+    fn unary(&mut self) -> Result<Query, Error> {
+        match self.tokens.next() {
+            Some(Token::Not) => Ok(Query::Not(Box::new(self.nested(Parser::unary)?))),
+            Some(Token::Open) => {
+                let query = self.nested(Parser::any)?;
+                match self.tokens.next() {
+                    Some(Token::Close) => Ok(query),
+                    _ => Err(Error::InvalidQuery("Unclosed '('".to_string())),
+                }
+            }
+            Some(Token::Word(word)) => Query::term(&word),
+            Some(Token::Close) => Err(Error::InvalidQuery("Unexpected ')'".to_string())),
+            Some(_) => Err(Error::InvalidQuery("Operator without operand".to_string())),
+            None => Err(Error::InvalidQuery("Unexpected end".to_string())),
+        }
+    }
+}
+
+impl Query {
+    /// Terms are ANDed unless joined by `OR`, negated by `-` or `NOT`, and grouped by parentheses
+    pub(crate) fn parse(input: &str) -> Result<Self, Error> {
+        let tokens = tokenize(input)?;
+        if tokens.is_empty() {
+            return Ok(Query::All(Vec::new()));
+        }
+        let mut parser = Parser {
+            tokens: tokens.into_iter().peekable(),
+            depth: 0,
+        };
+        let query = parser.any()?;
+        match parser.tokens.next() {
+            None => Ok(query),
+            Some(_) => Err(Error::InvalidQuery("Unexpected ')'".to_string())),
+        }
+    }
+
+    /// `word`, `"some words"`, `field:value`, `field:>=value`, `field:from..to`, `has:field` or
+    /// `missing:field`
+    fn term(word: &str) -> Result<Self, Error> {
+        let (field, value) = match word.split_once(':') {
+            Some((field, value)) if !field.is_empty() && !field.contains('"') => {
+                (Some(field), value)
+            }
+            _ => (None, word),
+        };
+        let quoted = value.starts_with('"');
+        let value = value.replace('"', "");
+        let Some(field) = field else {
+            return Ok(match quoted {
+                true => Query::Phrase(words(&value).join(" ")),
+                false => Query::Text(words(&value)),
+            });
+        };
+        let known = |field: &str| {
+            let field = library::field_name(field);
+            match field == "year" || library::is_field(field) {
+                true => Ok(field.to_string()),
+                false => Err(Error::InvalidQuery(format!("Unknown field '{}'", field))),
+            }
+        };
+        match field {
+            "has" => return Ok(Query::Has(known(&value)?)),
+            "missing" => return Ok(Query::Not(Box::new(Query::Has(known(&value)?)))),
+            _ => {}
+        }
+        let field = known(field)?;
+        if value.is_empty() {
+            return Err(Error::InvalidQuery(format!("No value for '{}'", field)));
+        }
+        if !quoted {
+            if let Some((from, to)) = value.split_once("..") {
+                return Ok(Query::Range(field, bound(from), bound(to)));
+            }
+            for (operator, ordering, inclusive) in [
+                (">=", Ordering::Greater, true),
+                ("<=", Ordering::Less, true),
+                (">", Ordering::Greater, false),
+                ("<", Ordering::Less, false),
+            ] {
+                if let Some(value) = value.strip_prefix(operator)
+                    && let Some(value) = bound(value)
+                {
+                    return Ok(Query::Compare(field, ordering, inclusive, value));
+                }
+            }
+        }
+        Ok(Query::Contains(field, value.to_lowercase()))
+    }
+
+    /// Whether a track with `fields`, as listed by `library::fields`, matches
+    pub(crate) fn matches(&self, fields: &Map<String, Value>) -> bool {
+        let text = || {
+            TEXT.iter()
+                .flat_map(|field| values(fields, field))
+                .map(|value| library::text(&value))
+        };
+        match self {
+            Query::All(all) => all.iter().all(|q| q.matches(fields)),
+            Query::Any(any) => any.iter().any(|q| q.matches(fields)),
+            Query::Not(query) => !query.matches(fields),
+            Query::Text(typed) => {
+                let found = text().flat_map(|t| words(&t)).collect::<Vec<_>>();
+                typed
+                    .iter()
+                    .all(|typed| found.iter().any(|word| similar(typed, word)))
+            }
+            Query::Phrase(phrase) => text().any(|t| words(&t).join(" ").contains(phrase.as_str())),
+            Query::Has(field) => values(fields, field)
+                .iter()
+                .any(|value| !library::is_empty(Some(value))),
+            Query::Contains(field, needle) => {
+                values(fields, field).iter().any(|value| match value {
+                    Value::Number(number) => number.to_string() == *needle,
+                    value => library::text(value)
+                        .to_lowercase()
+                        .contains(needle.as_str()),
+                })
+            }
+            Query::Compare(field, ordering, inclusive, bound) => {
+                values(fields, field).iter().any(|value| {
+                    let order = against(value, bound);
+                    order == *ordering || (*inclusive && order.is_eq())
+                })
+            }
+            Query::Range(field, from, to) => values(fields, field).iter().any(|value| {
+                from.as_ref()
+                    .is_none_or(|from| against(value, from).is_ge())
+                    && to.as_ref().is_none_or(|to| against(value, to).is_le())
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util;
+
+    fn fields(title: &str, artist: &str, date: &str, genres: &[&str]) -> Map<String, Value> {
+        library::fields(&library::Summary {
+            id: title.to_lowercase(),
+            meta: util::Metadata {
+                title: Some(title.to_string()),
+                artists: vec![artist.to_string()],
+                date: date.parse().ok(),
+                genres: genres.iter().map(|g| g.to_string()).collect(),
+                ..Default::default()
+            },
+        })
+    }
+
+    fn matches(query: &str, fields: &Map<String, Value>) -> bool {
+        Query::parse(query).unwrap().matches(fields)
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Query::parse("artist:\"daft punk\" (year:1995..2001 OR -genre:house)").unwrap(),
+            Query::All(vec![
+                Query::Contains("artists".to_string(), "daft punk".to_string()),
+                Query::Any(vec![
+                    Query::Range("year".to_string(), Some(1995.into()), Some(2001.into())),
+                    Query::Not(Box::new(Query::Contains(
+                        "genres".to_string(),
+                        "house".to_string()
+                    ))),
+                ]),
+            ])
+        );
+        assert!(Query::parse("artist:\"daft punk").is_err());
+        assert!(Query::parse("(year:2001").is_err());
+        assert!(Query::parse("colour:red").is_err());
+        assert!(Query::parse(&format!("{}a{}", "(".repeat(32), ")".repeat(32))).is_ok());
+        assert!(Query::parse(&format!("{}a{}", "(".repeat(33), ")".repeat(33))).is_err());
+        assert!(Query::parse(&format!("{}a", "NOT ".repeat(100_000))).is_err());
+    }
+
+    #[test]
+    fn evaluate() {
+        let track = fields(
+            "One More Time",
+            "Daft Punk",
+            "2000-11-13",
+            &["french house"],
+        );
+        assert!(matches(
+            "artist:\"daft punk\" year:1995..2001 missing:lyrics",
+            &track
+        ));
+        assert!(!matches("-genre:house", &track));
+        assert!(matches("date:<=2000 has:date", &track));
+        assert!(matches("genre:techno OR year:>1999", &track));
+        assert!(!matches("\"more one\"", &track));
+    }
+
+    #[test]
+    fn fuzzy() {
+        let track = fields("Harder, Better, Faster, Stronger", "Daft Punk", "2001", &[]);
+        assert!(matches("harder bettr daft", &track));
+        assert!(matches("strong", &track));
+        assert!(!matches("weaker", &track));
+    }
+}
@@ -1,7 +1,14 @@
-use crate::{autotag::MetadataSource, sync, util};
-use axum::{Router, extract, routing};
+use crate::{auth, autotag, error::Error, history, library, mpd, subsonic, sync, util};
+use axum::{
+    Router, extract,
+    http::{HeaderMap, header},
+    middleware,
+    response::IntoResponse,
+    routing,
+};
+use serde::de::DeserializeOwned;
 use static_serve::embed_assets;
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
 embed_assets!(
     "frontend/pkg",
@@ -13,96 +20,776 @@ embed_assets!(
 
 pub async fn serve(configuration: util::Configuration) {
     let address = configuration.address().unwrap();
-    let router = Router::new()
-        .merge(static_router())
-        .route("/health", routing::get(async || "Working!"))
-        .route("/trackadd", routing::post(trackadd))
+    let configuration = Arc::new(configuration);
+    tokio::spawn(trash_expire(configuration.clone()));
+    if let Some(address) = configuration.mpd_address() {
+        tokio::spawn(mpd::serve(configuration.clone(), address));
+    }
+    let reader = Router::new()
+        .route("/session", routing::get(session))
+        .route("/session", routing::delete(logout))
+        .route("/tokens", routing::get(tokenls))
+        .route("/tokens", routing::post(tokenadd))
+        .route("/tokens/{id}", routing::delete(tokenrm))
         .route("/tracks", routing::get(trackls))
-        .route("/track/{id}", routing::delete(trackrm))
+        .route("/search", routing::get(search))
         .route("/track/{id}", routing::get(trackinfo))
+        .route("/track/{id}/lyrics", routing::get(tracklyrics))
+        .route("/track/{id}/history", routing::get(trackhistory))
+        .route("/history/{batch}", routing::get(batchinfo));
+    let editor = Router::new()
+        .route("/trackadd", routing::post(trackadd))
+        .route("/tracks", routing::patch(tracksedit))
+        .route("/tracks/autotag", routing::post(tracksautotag))
+        .route("/track/{id}", routing::delete(trackrm))
         .route("/track/{id}", routing::put(trackedit))
         .route("/track/{id}", routing::patch(trackpatch))
         .route("/track/{id}/autotag", routing::get(trackautotag))
-        .with_state(Arc::new(configuration));
+        .route("/track/{id}/lyrics", routing::patch(tracklyricsedit))
+        .route(
+            "/track/{id}/history/{edit}/revert",
+            routing::post(trackrevert),
+        )
+        .route("/history/{batch}/revert", routing::post(batchrevert))
+        .route("/trash", routing::get(trashls))
+        .route("/trash/{id}/restore", routing::post(trashrestore));
+    let admin = Router::new()
+        .route("/users", routing::get(userls))
+        .route("/users", routing::post(useradd))
+        .route("/users/{name}", routing::patch(useredit))
+        .route("/users/{name}", routing::delete(userrm))
+        .route("/trash", routing::delete(trashpurge))
+        .route("/trash/{id}", routing::delete(trashrm))
+        .route("/cache", routing::get(cachestats))
+        .route("/cache", routing::delete(cachepurge))
+        .route("/cache/{source}", routing::get(cachels));
+    let router = Router::new()
+        .merge(static_router())
+        .route("/health", routing::get(async || "Working!"))
+        .route("/session", routing::post(login))
+        // authenticated by their own parameters
+        .route("/rest/{method}", routing::get(subsonic::rest))
+        .route("/rest/{method}", routing::post(subsonic::rest))
+        .merge(guard(reader, &configuration, auth::Role::ReadOnly))
+        .merge(guard(editor, &configuration, auth::Role::Editor))
+        .merge(guard(admin, &configuration, auth::Role::Admin))
+        .with_state(configuration);
     let listener = tokio::net::TcpListener::bind(address).await.unwrap();
     axum::serve(listener, router).await.unwrap();
 }
 
+/// Purges the tracks deleted longer ago than the retention period, every hour
+async fn trash_expire(cfg: Arc<util::Configuration>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+        if let (Some(retention), Ok(library)) = (cfg.trash_retention(), cfg.get_library())
+            && let Err(e) = sync::trash_purge(&library, Some(retention)).await
+        {
+            eprintln!("Purging the trash failed: {:?}", e);
+        }
+    }
+}
+
+/// Rejects the requests to `router` of users below `role`, handing the user on to its handlers
+fn guard(
+    router: Router<Arc<util::Configuration>>,
+    cfg: &Arc<util::Configuration>,
+    role: auth::Role,
+) -> Router<Arc<util::Configuration>> {
+    router.route_layer(middleware::from_fn_with_state(
+        cfg.clone(),
+        move |extract::State(cfg): extract::State<Arc<util::Configuration>>,
+              mut req: extract::Request,
+              next: middleware::Next| async move {
+            let user = authenticate(&cfg, req.headers()).await?;
+            if user.role < role {
+                return Err(Error::Forbidden);
+            }
+            req.extensions_mut().insert(user);
+            Ok::<_, Error>(next.run(req).await)
+        },
+    ))
+}
+
+/// Cookie holding the session of the web UI
+const SESSION: &str = "session";
+
+fn session_cookie(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .find_map(|cookie| cookie.trim().strip_prefix(SESSION)?.strip_prefix('='))
+}
+
+/// The user of the API token given as bearer, or else of the session cookie
+async fn authenticate(cfg: &util::Configuration, headers: &HeaderMap) -> Result<auth::User, Error> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let user = match (bearer, session_cookie(headers)) {
+        (Some(token), _) => cfg.users.token(token.trim()).await,
+        (None, Some(session)) => cfg.users.session(session).await,
+        (None, None) => return Err(Error::Unauthorized),
+    };
+    user.map_err(Error::Auth)?.ok_or(Error::Unauthorized)
+}
+
+/// `extract::Json`, rejecting with an `Error`
+struct Json<T>(T);
+
+impl<T: DeserializeOwned, S: Send + Sync> extract::FromRequest<S> for Json<T> {
+    type Rejection = Error;
+
+    async fn from_request(req: extract::Request, state: &S) -> Result<Self, Error> {
+        let extract::Json(value) = extract::Json::from_request(req, state).await?;
+        Ok(Json(value))
+    }
+}
+
+/// `extract::Query`, rejecting with an `Error`
+struct Query<T>(T);
+
+impl<T: DeserializeOwned, S: Send + Sync> extract::FromRequestParts<S> for Query<T> {
+    type Rejection = Error;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &S,
+    ) -> Result<Self, Error> {
+        let extract::Query(value) = extract::Query::from_request_parts(parts, state).await?;
+        Ok(Query(value))
+    }
+}
+
+/// `extract::Path`, rejecting with an `Error`
+struct Path<T>(T);
+
+impl<T: DeserializeOwned + Send, S: Send + Sync> extract::FromRequestParts<S> for Path<T> {
+    type Rejection = Error;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &S,
+    ) -> Result<Self, Error> {
+        let extract::Path(value) = extract::Path::from_request_parts(parts, state).await?;
+        Ok(Path(value))
+    }
+}
+
+fn library_dir(cfg: &util::Configuration) -> Result<PathBuf, Error> {
+    cfg.get_library().map_err(Error::Library)
+}
+
+/// A response carrying the entity tag of a track
+type Tagged<T> = ([(header::HeaderName, String); 1], T);
+
+fn if_match(headers: &HeaderMap) -> Option<&str> {
+    headers.get(header::IF_MATCH).and_then(|v| v.to_str().ok())
+}
+
+/// Starts the journaled batch of the edits of a request by `user`
+async fn change(
+    cfg: &util::Configuration,
+    kind: history::Kind,
+    user: &auth::User,
+) -> Result<history::Change, Error> {
+    cfg.history
+        .change(kind, Some(user.name.clone()))
+        .await
+        .map_err(Error::History)
+}
+
 async fn trackadd(
     extract::State(cfg): extract::State<Arc<util::Configuration>>,
-    extract::Json(tracks): extract::Json<Vec<String>>,
-) -> axum::response::Result<()> {
+    extract::Extension(user): extract::Extension<auth::User>,
+    Json(tracks): Json<Vec<String>>,
+) -> Result<(), Error> {
     let Ok(tracks) = tracks
         .iter()
-        .map(|s| axum::http::Uri::try_from(s))
+        .map(axum::http::Uri::try_from)
         .collect::<Result<Vec<_>, _>>()
     else {
-        return Err((reqwest::StatusCode::BAD_REQUEST, "Invalid URL(s)").into());
+        return Err(Error::InvalidUrl);
     };
-    let library = cfg.get_library()?;
+    let library = library_dir(&cfg)?;
+    let change = change(&cfg, history::Kind::Download, &user).await?;
     for track in tracks {
-        sync::track_download(track, library.as_path()).await?
+        sync::track_download(track, library.as_path(), &cfg.history, &change).await?
     }
     Ok(())
 }
 
 async fn trackls(
     extract::State(cfg): extract::State<Arc<util::Configuration>>,
-) -> axum::response::Result<extract::Json<Vec<String>>> {
-    Ok(extract::Json(sync::track_list(
-        cfg.get_library()?.as_path(),
-    )?))
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<extract::Json<library::Page>, Error> {
+    let listing = library::Listing::parse(params)?;
+    let tracks = cfg.index.tracks(library_dir(&cfg)?.as_path()).await?;
+    Ok(extract::Json(listing.apply(tracks)?))
+}
+
+async fn search(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<extract::Json<library::Page>, Error> {
+    if !params.iter().any(|(key, _)| key == "q") {
+        return Err(Error::InvalidQuery("Parameter 'q' missing".to_string()));
+    }
+    trackls(extract::State(cfg), Query(params)).await
 }
 
 async fn trackrm(
     extract::State(cfg): extract::State<Arc<util::Configuration>>,
-    extract::Path(track): extract::Path<String>,
-) -> axum::response::Result<()> {
-    Ok(sync::track_delete(&track, cfg.get_library()?.as_path())?)
+    Path(track): Path<String>,
+) -> Result<(), Error> {
+    sync::track_delete(&track, library_dir(&cfg)?.as_path()).await
 }
 
 async fn trackinfo(
     extract::State(cfg): extract::State<Arc<util::Configuration>>,
-    extract::Path(track): extract::Path<String>,
-) -> axum::response::Result<extract::Json<util::Metadata>> {
-    Ok(extract::Json(
-        sync::track_info(&track, cfg.get_library()?.as_path())?.into(),
-    ))
+    Path(track): Path<String>,
+) -> Result<Tagged<extract::Json<util::Metadata>>, Error> {
+    let meta = sync::track_metadata(&track, library_dir(&cfg)?.as_path()).await?;
+    Ok(([(header::ETAG, meta.etag())], extract::Json(meta)))
 }
 
 async fn trackedit(
     extract::State(cfg): extract::State<Arc<util::Configuration>>,
-    extract::Path(track): extract::Path<String>,
-    extract::Json(meta): extract::Json<util::Metadata>,
-) -> axum::response::Result<()> {
-    Ok(sync::track_edit(
+    Path(track): Path<String>,
+    headers: HeaderMap,
+    extract::Extension(user): extract::Extension<auth::User>,
+    Json(meta): Json<util::Metadata>,
+) -> Result<Tagged<()>, Error> {
+    let library = library_dir(&cfg)?;
+    let change = change(&cfg, history::Kind::Manual, &user).await?;
+    let etag = sync::track_edit(
         &track,
-        cfg.get_library()?.as_path(),
+        library.as_path(),
         meta,
-        false,
-    )?)
+        sync::Edit::Replace,
+        if_match(&headers),
+        &cfg.history,
+        &change,
+    )
+    .await?;
+    Ok(([(header::ETAG, etag)], ()))
 }
+
 async fn trackpatch(
     extract::State(cfg): extract::State<Arc<util::Configuration>>,
-    extract::Path(track): extract::Path<String>,
-    extract::Json(meta): extract::Json<util::Metadata>,
-) -> axum::response::Result<()> {
-    Ok(sync::track_edit(
+    Path(track): Path<String>,
+    headers: HeaderMap,
+    extract::Extension(user): extract::Extension<auth::User>,
+    Json(meta): Json<util::Metadata>,
+) -> Result<Tagged<()>, Error> {
+    let library = library_dir(&cfg)?;
+    let change = change(&cfg, history::Kind::Patch, &user).await?;
+    let etag = sync::track_edit(
         &track,
-        cfg.get_library()?.as_path(),
+        library.as_path(),
         meta,
-        true,
-    )?)
+        sync::Edit::Patch,
+        if_match(&headers),
+        &cfg.history,
+        &change,
+    )
+    .await?;
+    Ok(([(header::ETAG, etag)], ()))
+}
+
+#[derive(serde::Deserialize)]
+struct BulkEdit {
+    tracks: Vec<String>,
+    /// Fields to patch into every track
+    meta: util::Metadata,
+}
+
+/// Patches several tracks as one batch, returning its ID
+async fn tracksedit(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    extract::Extension(user): extract::Extension<auth::User>,
+    Json(edit): Json<BulkEdit>,
+) -> Result<extract::Json<i64>, Error> {
+    let library = library_dir(&cfg)?;
+    let change = change(&cfg, history::Kind::Bulk, &user).await?;
+    for track in edit.tracks {
+        sync::track_edit(
+            &track,
+            library.as_path(),
+            edit.meta.clone(),
+            sync::Edit::Patch,
+            None,
+            &cfg.history,
+            &change,
+        )
+        .await?;
+    }
+    Ok(extract::Json(change.batch))
 }
 
 async fn trackautotag(
     extract::State(cfg): extract::State<Arc<util::Configuration>>,
-    extract::Path(track): extract::Path<String>,
-) -> axum::response::Result<extract::Json<Vec<util::Metadata>>> {
-    let meta = sync::track_info(&track, cfg.get_library()?.as_path())?.into();
+    Path(track): Path<String>,
+) -> Result<extract::Json<Vec<util::Metadata>>, Error> {
+    let library = library_dir(&cfg)?;
+    let meta = sync::track_info(&track, library.as_path())?.into();
+    Ok(extract::Json(
+        cfg.metadatasources
+            .get_file(&library.join(track).with_added_extension("m4a"), &meta)
+            .await
+            .map_err(Error::Sources)?,
+    ))
+}
+
+/// Patches the best match of every source into several tracks as one batch, returning its ID
+async fn tracksautotag(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    extract::Extension(user): extract::Extension<auth::User>,
+    Json(tracks): Json<Vec<String>>,
+) -> Result<extract::Json<i64>, Error> {
+    let library = library_dir(&cfg)?;
+    let change = change(&cfg, history::Kind::Autotag, &user).await?;
+    for track in tracks {
+        let meta = sync::track_info(&track, library.as_path())?.into();
+        let Some(best) = cfg
+            .metadatasources
+            .get_file(&library.join(&track).with_added_extension("m4a"), &meta)
+            .await
+            .map_err(Error::Sources)?
+            .into_iter()
+            .next()
+        else {
+            continue;
+        };
+        sync::track_edit(
+            &track,
+            library.as_path(),
+            best,
+            sync::Edit::Patch,
+            None,
+            &cfg.history,
+            &change,
+        )
+        .await?;
+    }
+    Ok(extract::Json(change.batch))
+}
+
+#[derive(serde::Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum LyricsFormat {
+    #[default]
+    Lrc,
+    Plain,
+    Json,
+}
+
+#[derive(serde::Deserialize)]
+struct LyricsQuery {
+    #[serde(default)]
+    format: LyricsFormat,
+    /// Milliseconds to move every line by
+    #[serde(default)]
+    shift: i64,
+}
+
+async fn tracklyrics(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    Path(track): Path<String>,
+    Query(query): Query<LyricsQuery>,
+) -> Result<axum::response::Response, Error> {
+    let mut lyrics = sync::track_lyrics(&track, library_dir(&cfg)?.as_path())?;
+    lyrics.shift(query.shift);
+    Ok(match query.format {
+        LyricsFormat::Lrc if !lyrics.synced() => {
+            return Err(Error::LyricsNotSynced);
+        }
+        LyricsFormat::Lrc => lyrics.to_string().into_response(),
+        LyricsFormat::Plain => lyrics.plain().into_response(),
+        LyricsFormat::Json => extract::Json(lyrics).into_response(),
+    })
+}
+
+#[derive(serde::Deserialize)]
+struct LyricsEdit {
+    /// Milliseconds to move every line by
+    #[serde(default)]
+    shift: i64,
+    /// Corrected text for the lines, keeping their timing
+    text: Option<String>,
+}
+
+async fn tracklyricsedit(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    Path(track): Path<String>,
+    headers: HeaderMap,
+    extract::Extension(user): extract::Extension<auth::User>,
+    Json(edit): Json<LyricsEdit>,
+) -> Result<Tagged<()>, Error> {
+    let library = library_dir(&cfg)?;
+    let mut lyrics = sync::track_lyrics(&track, library.as_path())?;
+    lyrics.shift(edit.shift);
+    if let Some(text) = edit.text {
+        lyrics.merge(&text).map_err(Error::InvalidLyrics)?;
+    }
+    let meta = util::Metadata {
+        lyrics: Some(lyrics.to_string()),
+        ..Default::default()
+    };
+    let change = change(&cfg, history::Kind::Patch, &user).await?;
+    let etag = sync::track_edit(
+        &track,
+        library.as_path(),
+        meta,
+        sync::Edit::Patch,
+        if_match(&headers),
+        &cfg.history,
+        &change,
+    )
+    .await?;
+    Ok(([(header::ETAG, etag)], ()))
+}
+
+async fn trackhistory(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    Path(track): Path<String>,
+) -> Result<extract::Json<Vec<history::Entry>>, Error> {
+    Ok(extract::Json(
+        cfg.history.track(&track).await.map_err(Error::History)?,
+    ))
+}
+
+/// Restores the tags of a track as they were before the given edit
+async fn trackrevert(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    Path((track, edit)): Path<(String, i64)>,
+    headers: HeaderMap,
+    extract::Extension(user): extract::Extension<auth::User>,
+) -> Result<Tagged<()>, Error> {
+    let library = library_dir(&cfg)?;
+    let entry = cfg
+        .history
+        .entry(edit)
+        .await
+        .map_err(Error::History)?
+        .filter(|e| e.track == track)
+        .ok_or(Error::EditNotFound)?;
+    let change = change(&cfg, history::Kind::Revert, &user).await?;
+    let etag = sync::track_edit(
+        &track,
+        library.as_path(),
+        entry.before,
+        sync::Edit::Revert(entry.artwork),
+        if_match(&headers),
+        &cfg.history,
+        &change,
+    )
+    .await?;
+    Ok(([(header::ETAG, etag)], ()))
+}
+
+async fn batchinfo(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    Path(batch): Path<i64>,
+) -> Result<extract::Json<Vec<history::Entry>>, Error> {
+    let entries = cfg.history.batch(batch).await.map_err(Error::History)?;
+    if entries.is_empty() {
+        return Err(Error::EditNotFound);
+    }
+    Ok(extract::Json(entries))
+}
+
+/// Restores every track of a batch as it was before it, returning the ID of the reverting batch
+async fn batchrevert(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    Path(batch): Path<i64>,
+    extract::Extension(user): extract::Extension<auth::User>,
+) -> Result<extract::Json<i64>, Error> {
+    let library = library_dir(&cfg)?;
+    let entries = cfg.history.batch(batch).await.map_err(Error::History)?;
+    if entries.is_empty() {
+        return Err(Error::EditNotFound);
+    }
+    let change = change(&cfg, history::Kind::Revert, &user).await?;
+    let mut reverted = Vec::new();
+    // the first edit of a track in the batch holds its state before it
+    for entry in entries {
+        if reverted.contains(&entry.track) {
+            continue;
+        }
+        sync::track_edit(
+            &entry.track,
+            library.as_path(),
+            entry.before,
+            sync::Edit::Revert(entry.artwork),
+            None,
+            &cfg.history,
+            &change,
+        )
+        .await?;
+        reverted.push(entry.track);
+    }
+    Ok(extract::Json(change.batch))
+}
+
+async fn trashls(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+) -> Result<extract::Json<Vec<sync::Trashed>>, Error> {
+    Ok(extract::Json(sync::trash_list(
+        library_dir(&cfg)?.as_path(),
+    )?))
+}
+
+async fn trashrestore(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    Path(name): Path<String>,
+) -> Result<(), Error> {
+    sync::trash_restore(&name, library_dir(&cfg)?.as_path()).await
+}
+
+async fn trashrm(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    Path(name): Path<String>,
+) -> Result<(), Error> {
+    sync::trash_delete(&name, library_dir(&cfg)?.as_path()).await
+}
+
+#[derive(serde::Deserialize)]
+struct TrashPurge {
+    /// Only the tracks past the retention period
+    #[serde(default)]
+    expired: bool,
+}
+
+async fn trashpurge(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    Query(purge): Query<TrashPurge>,
+) -> Result<extract::Json<usize>, Error> {
+    let retention = match purge.expired {
+        true => match cfg.trash_retention() {
+            Some(retention) => Some(retention),
+            None => return Ok(extract::Json(0)),
+        },
+        false => None,
+    };
+    Ok(extract::Json(
+        sync::trash_purge(library_dir(&cfg)?.as_path(), retention).await?,
+    ))
+}
+
+#[derive(serde::Deserialize)]
+struct Login {
+    name: String,
+    password: String,
+}
+
+/// Logs in, setting the session cookie
+async fn login(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    Json(login): Json<Login>,
+) -> Result<([(header::HeaderName, String); 1], extract::Json<auth::User>), Error> {
+    let session = cfg
+        .users
+        .login(&login.name, &login.password)
+        .await
+        .map_err(Error::Auth)?
+        .ok_or(Error::InvalidCredentials)?;
+    let user = cfg
+        .users
+        .session(&session)
+        .await
+        .map_err(Error::Auth)?
+        .ok_or(Error::InvalidCredentials)?;
+    let cookie = format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}",
+        SESSION,
+        session,
+        cfg.users.session_ttl().as_secs()
+    );
+    Ok(([(header::SET_COOKIE, cookie)], extract::Json(user)))
+}
+
+async fn session(
+    extract::Extension(user): extract::Extension<auth::User>,
+) -> extract::Json<auth::User> {
+    extract::Json(user)
+}
+
+async fn logout(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    headers: HeaderMap,
+) -> Result<[(header::HeaderName, String); 1], Error> {
+    if let Some(session) = session_cookie(&headers) {
+        cfg.users.logout(session).await.map_err(Error::Auth)?;
+    }
+    Ok([(
+        header::SET_COOKIE,
+        format!("{}=; Path=/; HttpOnly; SameSite=Strict; Max-Age=0", SESSION),
+    )])
+}
+
+async fn tokenls(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    extract::Extension(user): extract::Extension<auth::User>,
+) -> Result<extract::Json<Vec<auth::Token>>, Error> {
+    Ok(extract::Json(
+        cfg.users.tokens(&user.name).await.map_err(Error::Auth)?,
+    ))
+}
+
+#[derive(serde::Deserialize)]
+struct TokenAdd {
+    /// What the token is for
+    name: String,
+}
+
+#[derive(serde::Serialize)]
+struct TokenAdded {
+    id: i64,
+    token: String,
+}
+
+/// Issues an API token acting as the user, its secret shown only in this response
+async fn tokenadd(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    extract::Extension(user): extract::Extension<auth::User>,
+    Json(token): Json<TokenAdd>,
+) -> Result<extract::Json<TokenAdded>, Error> {
+    let (id, token) = cfg
+        .users
+        .token_create(&user.name, &token.name)
+        .await
+        .map_err(Error::Auth)?;
+    Ok(extract::Json(TokenAdded { id, token }))
+}
+
+async fn tokenrm(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    extract::Extension(user): extract::Extension<auth::User>,
+    Path(id): Path<i64>,
+) -> Result<(), Error> {
+    match cfg.users.token_delete(&user.name, id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(Error::TokenNotFound),
+        Err(e) => Err(Error::Auth(e)),
+    }
+}
+
+async fn userls(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+) -> Result<extract::Json<Vec<auth::User>>, Error> {
+    Ok(extract::Json(cfg.users.list().await.map_err(Error::Auth)?))
+}
+
+#[derive(serde::Deserialize)]
+struct UserAdd {
+    name: String,
+    password: String,
+    role: auth::Role,
+}
+
+async fn useradd(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    Json(user): Json<UserAdd>,
+) -> Result<(), Error> {
+    if user.name.is_empty() || user.password.is_empty() {
+        return Err(Error::InvalidRequest(
+            "Name and password required".to_string(),
+        ));
+    }
+    match cfg
+        .users
+        .create(&user.name, &user.password, user.role)
+        .await
+    {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(Error::UserExists),
+        Err(e) => Err(Error::Auth(e)),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct UserEdit {
+    password: Option<String>,
+    role: Option<auth::Role>,
+}
+
+/// Changes the password or role of a user, ending their sessions
+async fn useredit(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    Path(name): Path<String>,
+    Json(edit): Json<UserEdit>,
+) -> Result<(), Error> {
+    if edit.password.as_deref() == Some("") {
+        return Err(Error::InvalidRequest("Password empty".to_string()));
+    }
+    match cfg
+        .users
+        .update(&name, edit.password.as_deref(), edit.role)
+        .await
+    {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(Error::UserNotFound),
+        Err(e) => Err(Error::Auth(e)),
+    }
+}
+
+async fn userrm(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    Path(name): Path<String>,
+) -> Result<(), Error> {
+    match cfg.users.delete(&name).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(Error::UserNotFound),
+        Err(e) => Err(Error::Auth(e)),
+    }
+}
+
+async fn cachestats(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+) -> Result<extract::Json<Vec<autotag::CacheStats>>, Error> {
+    Ok(extract::Json(
+        cfg.metadatasources
+            .cache()
+            .stats()
+            .await
+            .map_err(Error::Cache)?,
+    ))
+}
+
+async fn cachels(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    Path(source): Path<String>,
+) -> Result<extract::Json<Vec<autotag::CacheEntry>>, Error> {
+    Ok(extract::Json(
+        cfg.metadatasources
+            .cache()
+            .entries(&source)
+            .await
+            .map_err(Error::Cache)?,
+    ))
+}
+
+#[derive(serde::Deserialize)]
+struct CachePurge {
+    source: Option<String>,
+    #[serde(default)]
+    expired: bool,
+}
+
+async fn cachepurge(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    Query(purge): Query<CachePurge>,
+) -> Result<extract::Json<usize>, Error> {
     Ok(extract::Json(
         cfg.metadatasources
-            .get_track(&meta, meta.isrc.is_none())
-            .await?,
+            .cache()
+            .purge(purge.source.as_deref(), purge.expired)
+            .await
+            .map_err(Error::Cache)?,
     ))
 }
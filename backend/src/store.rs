@@ -0,0 +1,30 @@
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+
+/// An on-disk SQLite database shared by requests, queried on the blocking thread pool
+pub(crate) struct Store {
+    client: Arc<Mutex<rusqlite::Connection>>,
+}
+
+impl Store {
+    /// Opens `dbfile`, creating whatever `schema` creates unless it exists
+    pub(crate) fn new(dbfile: String, schema: &str) -> Result<Self, String> {
+        let client = rusqlite::Connection::open(dbfile).map_err(|e| e.to_string())?;
+        client.execute_batch(schema).map_err(|e| e.to_string())?;
+        Ok(Self {
+            client: Arc::new(Mutex::new(client)),
+        })
+    }
+
+    /// Runs `query` off the runtime, once the queries before it are done
+    pub(crate) async fn run<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&mut rusqlite::Connection) -> Result<T, String> + Send + 'static,
+    ) -> Result<T, String> {
+        let mut client = self.client.clone().lock_owned().await;
+        tokio::task::spawn_blocking(move || query(&mut client))
+            .await
+            .map_err(|e| e.to_string())?
+    }
+}
@@ -0,0 +1,1100 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::Body,
+    extract::{self, rejection::FormRejection},
+    http::{HeaderMap, Method, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde_json::{Map, Value, json};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::{auth, error::Error, library, lyrics, playlist, sync, util};
+
+/// Version of the Subsonic API implemented
+const VERSION: &str = "1.16.1";
+
+/// Version reported to clients not using an `apiKey`, below 1.13 so that they send passwords
+/// rather than the salted tokens which cannot be checked against hashed passwords
+const LEGACY_VERSION: &str = "1.12.0";
+
+/// A failed method as reported to clients, with one of the error codes of the Subsonic API
+struct Failure(u16, String);
+
+impl Failure {
+    fn generic(message: impl ToString) -> Self {
+        Failure(0, message.to_string())
+    }
+
+    fn missing(param: &str) -> Self {
+        Failure(10, format!("Required parameter '{}' missing", param))
+    }
+
+    fn unauthorized() -> Self {
+        Failure(
+            50,
+            "User not authorized for the given operation".to_string(),
+        )
+    }
+
+    fn not_found(what: &str) -> Self {
+        Failure(70, format!("{} not found", what))
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::TrackNotFound => Failure::not_found("Song"),
+            err => Failure::generic(err.message()),
+        }
+    }
+}
+
+/// Parameters of a method, which may repeat as `songId` does
+struct Params(Vec<(String, String)>);
+
+impl Params {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn all(&self, key: &str) -> impl Iterator<Item = &str> {
+        self.0
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn required(&self, key: &str) -> Result<&str, Failure> {
+        self.get(key).ok_or_else(|| Failure::missing(key))
+    }
+
+    fn number<T: std::str::FromStr>(&self, key: &str, default: T) -> Result<T, Failure> {
+        match self.get(key) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| Failure::generic(format!("Invalid parameter '{}'", key))),
+            None => Ok(default),
+        }
+    }
+}
+
+/// What a method answers with, either inside the `subsonic-response` or as is
+enum Reply {
+    Body(Map<String, Value>),
+    Raw(Response),
+}
+
+fn body(key: &str, value: Value) -> Reply {
+    Reply::Body(Map::from_iter([(key.to_string(), value)]))
+}
+
+/// IDs of artists and albums, which have none of their own, from their names
+fn id(kind: &str, name: &str) -> String {
+    format!("{}-{:016x}", kind, util::fnv(name.as_bytes()))
+}
+
+fn artist_name(meta: &util::Metadata) -> &str {
+    meta.album_artists
+        .first()
+        .or(meta.artists.first())
+        .map_or("Unknown Artist", String::as_str)
+}
+
+fn album_name(meta: &util::Metadata) -> &str {
+    meta.album.as_deref().unwrap_or("Unknown Album")
+}
+
+/// `2001-03-07T08:00:00Z` of seconds since the Unix epoch
+pub(crate) fn timestamp(secs: i64) -> String {
+    // civil from days, after Howard Hinnant
+    let (days, time) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Sets `key` unless `value` is missing
+fn set(map: &mut Map<String, Value>, key: &str, value: Option<impl Into<Value>>) {
+    if let Some(value) = value {
+        map.insert(key.to_string(), value.into());
+    }
+}
+
+fn object(value: Value) -> Map<String, Value> {
+    match value {
+        Value::Object(map) => map,
+        _ => Map::new(),
+    }
+}
+
+struct Album {
+    id: String,
+    name: String,
+    artist: String,
+    /// Indices of its songs, in order
+    songs: Vec<usize>,
+}
+
+/// The library as artists, albums and songs, grouped by their tags
+struct Catalog {
+    dir: PathBuf,
+    songs: Vec<library::Summary>,
+    /// Index of the album of each song
+    album_of: Vec<usize>,
+    /// Sorted by artist and name
+    albums: Vec<Album>,
+}
+
+impl Catalog {
+    async fn open(cfg: &util::Configuration) -> Result<Self, Failure> {
+        let dir = cfg.get_library().map_err(Failure::generic)?;
+        let songs = cfg.index.tracks(&dir).await?;
+        let mut albums = Vec::<Album>::new();
+        let mut by_name = HashMap::new();
+        for (i, song) in songs.iter().enumerate() {
+            let (artist, name) = (artist_name(&song.meta), album_name(&song.meta));
+            let key = format!("{}\0{}", artist, name);
+            let album = *by_name.entry(key.clone()).or_insert_with(|| {
+                albums.push(Album {
+                    id: id("al", &key),
+                    name: name.to_string(),
+                    artist: artist.to_string(),
+                    songs: Vec::new(),
+                });
+                albums.len() - 1
+            });
+            albums[album].songs.push(i);
+        }
+        for album in &mut albums {
+            album.songs.sort_by_key(|&i| {
+                let meta = &songs[i].meta;
+                (meta.track.unwrap_or(u16::MAX), meta.title.clone())
+            });
+        }
+        albums.sort_by_key(|a| (a.artist.to_lowercase(), a.name.to_lowercase()));
+        let mut album_of = vec![0; songs.len()];
+        for (a, album) in albums.iter().enumerate() {
+            for &i in &album.songs {
+                album_of[i] = a;
+            }
+        }
+        Ok(Self {
+            dir,
+            songs,
+            album_of,
+            albums,
+        })
+    }
+
+    fn file(&self, song: &str) -> PathBuf {
+        self.dir.join(song).with_added_extension("m4a")
+    }
+
+    fn song_index(&self, id: &str) -> Result<usize, Failure> {
+        self.songs
+            .iter()
+            .position(|s| s.id == id)
+            .ok_or_else(|| Failure::not_found("Song"))
+    }
+
+    fn album(&self, id: &str) -> Result<&Album, Failure> {
+        self.albums
+            .iter()
+            .find(|a| a.id == id)
+            .ok_or_else(|| Failure::not_found("Album"))
+    }
+
+    /// Every artist by name with their albums, in order
+    fn artists(&self) -> Vec<(&str, Vec<&Album>)> {
+        let mut artists: Vec<(&str, Vec<&Album>)> = Vec::new();
+        for album in &self.albums {
+            match artists.last_mut() {
+                Some((artist, albums)) if *artist == album.artist => albums.push(album),
+                _ => artists.push((&album.artist, vec![album])),
+            }
+        }
+        artists
+    }
+
+    fn artist(&self, id: &str) -> Result<(&str, Vec<&Album>), Failure> {
+        self.artists()
+            .into_iter()
+            .find(|(name, _)| self::id("ar", name) == id)
+            .ok_or_else(|| Failure::not_found("Artist"))
+    }
+
+    fn modified(&self, song: &str) -> i64 {
+        fs::metadata(self.file(song))
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default()
+    }
+
+    fn song(&self, i: usize) -> Value {
+        let song = &self.songs[i];
+        let album = &self.albums[self.album_of[i]];
+        let meta = &song.meta;
+        let mut value = object(json!({
+            "id": song.id,
+            "parent": album.id,
+            "isDir": false,
+            "title": meta.title.as_deref().unwrap_or(&song.id),
+            "album": album.name,
+            "artist": match meta.artists.is_empty() {
+                true => album.artist.clone(),
+                false => meta.artists.join(", "),
+            },
+            "albumId": album.id,
+            "artistId": id("ar", &album.artist),
+            "coverArt": song.id,
+            "contentType": "audio/mp4",
+            "suffix": "m4a",
+            "type": "music",
+            "mediaType": "song",
+            "isVideo": false,
+            "created": timestamp(self.modified(&song.id)),
+            "genres": meta.genres.iter().map(|g| json!({ "name": g })).collect::<Vec<_>>(),
+        }));
+        set(&mut value, "track", meta.track);
+        set(&mut value, "year", meta.date.map(|d| d.year()));
+        set(&mut value, "genre", meta.genres.first().cloned());
+        set(&mut value, "duration", meta.duration);
+        set(&mut value, "bpm", meta.bpm);
+        set(&mut value, "musicBrainzId", meta.mbid.clone());
+        set(&mut value, "isrc", meta.isrc.clone().map(|i| vec![i]));
+        set(
+            &mut value,
+            "size",
+            fs::metadata(self.file(&song.id)).ok().map(|m| m.len()),
+        );
+        Value::Object(value)
+    }
+
+    fn album_value(&self, album: &Album, songs: bool) -> Value {
+        let metas = album.songs.iter().map(|&i| &self.songs[i].meta);
+        let mut value = object(json!({
+            "id": album.id,
+            "name": album.name,
+            "artist": album.artist,
+            "artistId": id("ar", &album.artist),
+            "coverArt": album.id,
+            "songCount": album.songs.len(),
+            "duration": metas.clone().filter_map(|m| m.duration).sum::<u32>(),
+            "created": timestamp(
+                album
+                    .songs
+                    .iter()
+                    .map(|&i| self.modified(&self.songs[i].id))
+                    .min()
+                    .unwrap_or_default()
+            ),
+        }));
+        set(
+            &mut value,
+            "year",
+            metas.clone().find_map(|m| m.date).map(|d| d.year()),
+        );
+        set(
+            &mut value,
+            "genre",
+            metas.clone().find_map(|m| m.genres.first().cloned()),
+        );
+        if songs {
+            value.insert(
+                "song".to_string(),
+                album.songs.iter().map(|&i| self.song(i)).collect(),
+            );
+        }
+        Value::Object(value)
+    }
+
+    fn artist_value(&self, name: &str, albums: &[&Album]) -> Map<String, Value> {
+        object(json!({
+            "id": id("ar", name),
+            "name": name,
+            "albumCount": albums.len(),
+            "coverArt": albums.first().map(|a| a.id.clone()),
+        }))
+    }
+
+    /// The first song with artwork of a song, album or artist ID, whose tags are read off the
+    /// runtime as they include the artwork
+    async fn cover(&self, id: &str) -> Result<mp4ameta::Tag, Failure> {
+        let songs = if id.starts_with("al-") {
+            self.album(id)?
+                .songs
+                .iter()
+                .map(|&i| self.songs[i].id.as_str())
+                .collect()
+        } else if id.starts_with("ar-") {
+            self.artist(id)?
+                .1
+                .iter()
+                .flat_map(|a| a.songs.iter().map(|&i| self.songs[i].id.as_str()))
+                .collect()
+        } else {
+            vec![self.songs[self.song_index(id)?].id.as_str()]
+        };
+        let files = songs.into_iter().map(|s| self.file(s)).collect::<Vec<_>>();
+        tokio::task::spawn_blocking(move || {
+            files
+                .into_iter()
+                .filter_map(|f| mp4ameta::Tag::read_from_path(f).ok())
+                .find(|t| t.artwork().is_some())
+        })
+        .await
+        .map_err(Failure::generic)?
+        .ok_or_else(|| Failure::not_found("Cover art"))
+    }
+
+    /// The positions in a playlist of the tracks still in the library, which are the only ones
+    /// listed, so that `songIndexToRemove` counts the entries clients see
+    fn listed(&self, playlist: &playlist::Playlist) -> Vec<(usize, usize)> {
+        playlist
+            .tracks
+            .iter()
+            .enumerate()
+            .filter_map(|(p, t)| Some((p, self.song_index(t).ok()?)))
+            .collect()
+    }
+
+    fn playlist(&self, playlist: &playlist::Playlist, entries: bool) -> Value {
+        let songs = self
+            .listed(playlist)
+            .into_iter()
+            .map(|(_, i)| i)
+            .collect::<Vec<_>>();
+        let mut value = object(json!({
+            "id": playlist.id.to_string(),
+            "name": playlist.name,
+            "owner": playlist.owner,
+            "public": playlist.public,
+            "songCount": songs.len(),
+            "duration": songs.iter().filter_map(|&i| self.songs[i].meta.duration).sum::<u32>(),
+            "created": timestamp(playlist.created),
+            "changed": timestamp(playlist.changed),
+        }));
+        set(&mut value, "comment", playlist.comment.clone());
+        set(
+            &mut value,
+            "coverArt",
+            songs.first().map(|&i| self.songs[i].id.clone()),
+        );
+        if entries {
+            value.insert(
+                "entry".to_string(),
+                songs.iter().map(|&i| self.song(i)).collect(),
+            );
+        }
+        Value::Object(value)
+    }
+}
+
+/// Whether every word of a search is in `text`, where an empty search matches anything
+fn matches(words: &[String], text: &str) -> bool {
+    let text = text.to_lowercase();
+    words.iter().all(|w| text.contains(w.as_str()))
+}
+
+/// The bytes of a single range such as `bytes=0-1023`, `bytes=1024-` or `bytes=-1024`, unless
+/// unsatisfiable
+fn range(header: &str, len: u64) -> Option<(u64, u64)> {
+    let (start, end) = header.strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => (len.checked_sub(suffix.parse().ok()?)?, len.checked_sub(1)?),
+        (start, "") => (start.parse().ok()?, len.checked_sub(1)?),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.min(len.checked_sub(1)?),
+        ),
+    };
+    (start <= end && end < len).then_some((start, end))
+}
+
+/// The file of a song as is, since tracks are not transcoded, in parts if requested
+async fn stream(file: &Path, headers: &HeaderMap) -> Result<Response, Failure> {
+    let mut data = tokio::fs::File::open(file).await.map_err(Error::io)?;
+    let len = data.metadata().await.map_err(Error::io)?.len();
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|r| r.to_str().ok())
+        .and_then(|r| range(r, len));
+    let (start, size) = match range {
+        Some((start, end)) => (start, end - start + 1),
+        None => (0, len),
+    };
+    data.seek(SeekFrom::Start(start)).await.map_err(Error::io)?;
+    // read in chunks rather than whole, as songs may be large and many clients only need the start
+    let chunks = futures_util::stream::unfold(Some(data.take(size)), |data| async move {
+        let mut data = data?;
+        let mut chunk = vec![0; 64 * 1024];
+        match data.read(&mut chunk).await {
+            Ok(0) => None,
+            Ok(n) => {
+                chunk.truncate(n);
+                Some((Ok(chunk), Some(data)))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    let content = [
+        (header::CONTENT_TYPE, "audio/mp4".to_string()),
+        (header::ACCEPT_RANGES, "bytes".to_string()),
+        (header::CONTENT_LENGTH, size.to_string()),
+    ];
+    let body = Body::from_stream(chunks);
+    Ok(match range {
+        Some((start, end)) => (
+            StatusCode::PARTIAL_CONTENT,
+            content,
+            [(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, len),
+            )],
+            body,
+        )
+            .into_response(),
+        None => (content, body).into_response(),
+    })
+}
+
+/// The user of the `apiKey`, or of `u` and the password `p`, which may be hex encoded after
+/// `enc:`; the salted tokens `t` and `s` require plain passwords, which are never stored
+async fn authenticate(cfg: &util::Configuration, params: &Params) -> Result<auth::User, Failure> {
+    if let Some(key) = params.get("apiKey") {
+        return cfg
+            .users
+            .token(key)
+            .await
+            .map_err(Failure::generic)?
+            .ok_or_else(|| Failure(44, "Invalid API key".to_string()));
+    }
+    let name = params.required("u")?;
+    if params.get("t").is_some() {
+        return Err(Failure(
+            41,
+            "Token authentication not supported, use a password or an API key".to_string(),
+        ));
+    }
+    let password = params.required("p")?;
+    let password = match password.strip_prefix("enc:") {
+        Some(hex) => (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<_>>>()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or_else(|| Failure::generic("Invalid parameter 'p'"))?,
+        None => password.to_string(),
+    };
+    cfg.users
+        .verify(name, &password)
+        .await
+        .map_err(Failure::generic)?
+        .ok_or_else(|| Failure(40, "Wrong username or password".to_string()))
+}
+
+async fn playlist_owned(
+    cfg: &util::Configuration,
+    user: &auth::User,
+    id: &str,
+) -> Result<playlist::Playlist, Failure> {
+    let playlist = cfg
+        .playlists
+        .get(id.parse().map_err(|_| Failure::not_found("Playlist"))?)
+        .await
+        .map_err(Failure::generic)?
+        .ok_or_else(|| Failure::not_found("Playlist"))?;
+    if playlist.owner != user.name && user.role != auth::Role::Admin {
+        return Err(Failure::unauthorized());
+    }
+    Ok(playlist)
+}
+
+async fn call(
+    cfg: &util::Configuration,
+    method: &str,
+    params: &Params,
+    headers: &HeaderMap,
+) -> Result<Reply, Failure> {
+    // listed before authenticating as required by OpenSubsonic
+    if method == "getOpenSubsonicExtensions" {
+        return Ok(body(
+            "openSubsonicExtensions",
+            json!([
+                { "name": "apiKeyAuthentication", "versions": [1] },
+                { "name": "songLyrics", "versions": [1] },
+            ]),
+        ));
+    }
+    let user = authenticate(cfg, params).await?;
+    Ok(match method {
+        "ping" => Reply::Body(Map::new()),
+        "getLicense" => body("license", json!({ "valid": true })),
+        "getUser" => body(
+            "user",
+            json!({
+                "username": user.name,
+                "scrobblingEnabled": false,
+                "adminRole": user.role == auth::Role::Admin,
+                "settingsRole": false,
+                "downloadRole": true,
+                "uploadRole": user.role >= auth::Role::Editor,
+                "playlistRole": true,
+                "coverArtRole": user.role >= auth::Role::Editor,
+                "commentRole": false,
+                "podcastRole": false,
+                "streamRole": true,
+                "jukeboxRole": false,
+                "shareRole": false,
+                "folder": [1],
+            }),
+        ),
+        "getMusicFolders" => body(
+            "musicFolders",
+            json!({ "musicFolder": [{ "id": 1, "name": "Library" }] }),
+        ),
+        "getArtists" => {
+            let catalog = Catalog::open(cfg).await?;
+            let mut index: Vec<(String, Vec<Value>)> = Vec::new();
+            let mut artists = catalog.artists();
+            let sort_name = |name: &str| name.trim_start_matches("The ").to_uppercase();
+            artists.sort_by_key(|(name, _)| sort_name(name));
+            for (name, albums) in artists {
+                let letter = sort_name(name)
+                    .chars()
+                    .next()
+                    .filter(|c| c.is_alphabetic())
+                    .map_or("#".to_string(), |c| c.to_string());
+                let artist = Value::Object(catalog.artist_value(name, &albums));
+                match index.last_mut() {
+                    Some((last, artists)) if *last == letter => artists.push(artist),
+                    _ => index.push((letter, vec![artist])),
+                }
+            }
+            body(
+                "artists",
+                json!({
+                    "ignoredArticles": "The",
+                    "index": index
+                        .into_iter()
+                        .map(|(name, artist)| json!({ "name": name, "artist": artist }))
+                        .collect::<Vec<_>>(),
+                }),
+            )
+        }
+        "getArtist" => {
+            let catalog = Catalog::open(cfg).await?;
+            let (name, albums) = catalog.artist(params.required("id")?)?;
+            let mut artist = catalog.artist_value(name, &albums);
+            artist.insert(
+                "album".to_string(),
+                albums
+                    .iter()
+                    .map(|a| catalog.album_value(a, false))
+                    .collect(),
+            );
+            body("artist", Value::Object(artist))
+        }
+        "getAlbum" => {
+            let catalog = Catalog::open(cfg).await?;
+            let album = catalog.album(params.required("id")?)?;
+            body("album", catalog.album_value(album, true))
+        }
+        "getAlbumList2" => {
+            let catalog = Catalog::open(cfg).await?;
+            let size = params.number("size", 10_usize)?.min(500);
+            let offset = params.number("offset", 0_usize)?;
+            let year = |a: &Album| {
+                a.songs
+                    .iter()
+                    .find_map(|&i| catalog.songs[i].meta.date)
+                    .map(|d| d.year())
+            };
+            let mut albums = catalog.albums.iter().collect::<Vec<_>>();
+            match params.required("type")? {
+                "alphabeticalByArtist" => {}
+                "alphabeticalByName" => albums.sort_by_key(|a| a.name.to_lowercase()),
+                "newest" => albums.sort_by_key(|a| {
+                    std::cmp::Reverse(
+                        a.songs
+                            .iter()
+                            .map(|&i| catalog.modified(&catalog.songs[i].id))
+                            .min(),
+                    )
+                }),
+                "random" => {
+                    let seed = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_nanos())
+                        .unwrap_or_default();
+                    albums.sort_by_key(|a| util::fnv(format!("{}{}", seed, a.id).as_bytes()));
+                }
+                "byYear" => {
+                    let from = params.number("fromYear", 0_u16)?;
+                    let to = params.number("toYear", u16::MAX)?;
+                    albums.retain(|a| {
+                        year(a).is_some_and(|y| (from.min(to)..=from.max(to)).contains(&y))
+                    });
+                    albums.sort_by_key(|a| year(a));
+                    if from > to {
+                        albums.reverse();
+                    }
+                }
+                "byGenre" => {
+                    let genre = params.required("genre")?;
+                    albums.retain(|a| {
+                        a.songs.iter().any(|&i| {
+                            catalog.songs[i]
+                                .meta
+                                .genres
+                                .iter()
+                                .any(|g| g.eq_ignore_ascii_case(genre))
+                        })
+                    });
+                }
+                // without play counts, ratings or stars
+                "frequent" | "recent" | "highest" | "starred" => albums.clear(),
+                kind => return Err(Failure::generic(format!("Unknown list type '{}'", kind))),
+            }
+            body(
+                "albumList2",
+                json!({
+                    "album": albums
+                        .into_iter()
+                        .skip(offset)
+                        .take(size)
+                        .map(|a| catalog.album_value(a, false))
+                        .collect::<Vec<_>>(),
+                }),
+            )
+        }
+        "getSong" => {
+            let catalog = Catalog::open(cfg).await?;
+            let song = catalog.song_index(params.required("id")?)?;
+            body("song", catalog.song(song))
+        }
+        "search3" => {
+            let catalog = Catalog::open(cfg).await?;
+            let query = params.get("query").unwrap_or_default().trim_matches('"');
+            let words = query
+                .split_whitespace()
+                .map(str::to_lowercase)
+                .collect::<Vec<_>>();
+            let page = |kind: &str| -> Result<(usize, usize), Failure> {
+                Ok((
+                    params.number(&format!("{}Offset", kind), 0)?,
+                    params.number(&format!("{}Count", kind), 20)?,
+                ))
+            };
+            let (artist_offset, artist_count) = page("artist")?;
+            let (album_offset, album_count) = page("album")?;
+            let (song_offset, song_count) = page("song")?;
+            let artists = catalog
+                .artists()
+                .into_iter()
+                .filter(|(name, _)| matches(&words, name))
+                .skip(artist_offset)
+                .take(artist_count)
+                .map(|(name, albums)| Value::Object(catalog.artist_value(name, &albums)))
+                .collect::<Vec<_>>();
+            let albums = catalog
+                .albums
+                .iter()
+                .filter(|a| matches(&words, &format!("{} {}", a.name, a.artist)))
+                .skip(album_offset)
+                .take(album_count)
+                .map(|a| catalog.album_value(a, false))
+                .collect::<Vec<_>>();
+            let songs = (0..catalog.songs.len())
+                .filter(|&i| {
+                    let meta = &catalog.songs[i].meta;
+                    let text = format!(
+                        "{} {} {}",
+                        meta.title.as_deref().unwrap_or_default(),
+                        meta.artists.join(" "),
+                        meta.album.as_deref().unwrap_or_default()
+                    );
+                    matches(&words, &text)
+                })
+                .skip(song_offset)
+                .take(song_count)
+                .map(|i| catalog.song(i))
+                .collect::<Vec<_>>();
+            body(
+                "searchResult3",
+                json!({ "artist": artists, "album": albums, "song": songs }),
+            )
+        }
+        "stream" | "download" => {
+            let dir = cfg.get_library().map_err(Failure::generic)?;
+            let id = params.required("id")?;
+            if sync::track_list(&dir)?.iter().all(|t| t != id) {
+                return Err(Failure::not_found("Song"));
+            }
+            Reply::Raw(stream(&dir.join(id).with_added_extension("m4a"), headers).await?)
+        }
+        "getCoverArt" => {
+            let catalog = Catalog::open(cfg).await?;
+            let tag = catalog.cover(params.required("id")?).await?;
+            let Some(artwork) = tag.artwork() else {
+                return Err(Failure::not_found("Cover art"));
+            };
+            let mime = match artwork.fmt {
+                mp4ameta::ImgFmt::Jpeg => "image/jpeg",
+                mp4ameta::ImgFmt::Png => "image/png",
+                mp4ameta::ImgFmt::Bmp => "image/bmp",
+            };
+            Reply::Raw(([(header::CONTENT_TYPE, mime)], artwork.data.to_vec()).into_response())
+        }
+        "getLyrics" => {
+            let catalog = Catalog::open(cfg).await?;
+            let (artist, title) = (params.get("artist"), params.get("title"));
+            let song = catalog.songs.iter().find(|s| {
+                title.is_some_and(|t| {
+                    s.meta
+                        .title
+                        .as_deref()
+                        .is_some_and(|x| x.eq_ignore_ascii_case(t))
+                }) && artist
+                    .is_none_or(|a| s.meta.artists.iter().any(|x| x.eq_ignore_ascii_case(a)))
+                    && s.meta.lyrics.is_some()
+            });
+            let mut lyrics = Map::new();
+            if let Some(song) = song {
+                set(&mut lyrics, "artist", song.meta.artists.first().cloned());
+                set(&mut lyrics, "title", song.meta.title.clone());
+                set(
+                    &mut lyrics,
+                    "value",
+                    song.meta
+                        .lyrics
+                        .as_deref()
+                        .and_then(|l| lyrics::Lyrics::parse(l).ok())
+                        .map(|l| l.plain()),
+                );
+            }
+            body("lyrics", Value::Object(lyrics))
+        }
+        "getLyricsBySongId" => {
+            let dir = cfg.get_library().map_err(Failure::generic)?;
+            let id = params.required("id")?;
+            if sync::track_list(&dir)?.iter().all(|t| t != id) {
+                return Err(Failure::not_found("Song"));
+            }
+            let meta: util::Metadata = sync::track_info(id, &dir)?.into();
+            let structured = match sync::track_lyrics(id, &dir) {
+                Ok(lyrics) => vec![json!({
+                    "displayArtist": meta.artists.join(", "),
+                    "displayTitle": meta.title,
+                    "lang": "und",
+                    "synced": lyrics.synced(),
+                    "line": lyrics
+                        .lines()
+                        .map(|(time, text)| {
+                            let mut line = object(json!({ "value": text }));
+                            set(&mut line, "start", time);
+                            Value::Object(line)
+                        })
+                        .collect::<Vec<_>>(),
+                })],
+                Err(Error::LyricsNotFound) => Vec::new(),
+                Err(e) => return Err(e.into()),
+            };
+            body("lyricsList", json!({ "structuredLyrics": structured }))
+        }
+        "getPlaylists" => {
+            let catalog = Catalog::open(cfg).await?;
+            let playlists = cfg
+                .playlists
+                .list(&user.name)
+                .await
+                .map_err(Failure::generic)?;
+            body(
+                "playlists",
+                json!({
+                    "playlist": playlists
+                        .iter()
+                        .map(|p| catalog.playlist(p, false))
+                        .collect::<Vec<_>>(),
+                }),
+            )
+        }
+        "getPlaylist" => {
+            let catalog = Catalog::open(cfg).await?;
+            let id = params.required("id")?;
+            let playlist = cfg
+                .playlists
+                .get(id.parse().map_err(|_| Failure::not_found("Playlist"))?)
+                .await
+                .map_err(Failure::generic)?
+                .filter(|p| p.public || p.owner == user.name)
+                .ok_or_else(|| Failure::not_found("Playlist"))?;
+            body("playlist", catalog.playlist(&playlist, true))
+        }
+        "createPlaylist" => {
+            let songs = params.all("songId").map(str::to_string).collect::<Vec<_>>();
+            let id = match params.get("playlistId") {
+                Some(id) => {
+                    let playlist = playlist_owned(cfg, &user, id).await?;
+                    cfg.playlists
+                        .replace(playlist.id, &songs)
+                        .await
+                        .map_err(Failure::generic)?;
+                    playlist.id
+                }
+                None => cfg
+                    .playlists
+                    .create(&user.name, params.required("name")?, &songs)
+                    .await
+                    .map_err(Failure::generic)?,
+            };
+            let catalog = Catalog::open(cfg).await?;
+            let playlist = cfg
+                .playlists
+                .get(id)
+                .await
+                .map_err(Failure::generic)?
+                .ok_or_else(|| Failure::not_found("Playlist"))?;
+            body("playlist", catalog.playlist(&playlist, true))
+        }
+        "updatePlaylist" => {
+            let playlist = playlist_owned(cfg, &user, params.required("playlistId")?).await?;
+            let remove = params
+                .all("songIndexToRemove")
+                .filter_map(|i| i.parse().ok())
+                .collect::<Vec<usize>>();
+            let remove = match remove.is_empty() {
+                true => remove,
+                false => {
+                    let listed = Catalog::open(cfg).await?.listed(&playlist);
+                    remove
+                        .into_iter()
+                        .filter_map(|i| listed.get(i).map(|&(p, _)| p))
+                        .collect()
+                }
+            };
+            let update = playlist::Update {
+                name: params.get("name").map(str::to_string),
+                comment: params.get("comment").map(str::to_string),
+                public: params.get("public").map(|p| p == "true"),
+                remove,
+                add: params.all("songIdToAdd").map(str::to_string).collect(),
+            };
+            cfg.playlists
+                .update(playlist.id, update)
+                .await
+                .map_err(Failure::generic)?;
+            Reply::Body(Map::new())
+        }
+        "deletePlaylist" => {
+            let playlist = playlist_owned(cfg, &user, params.required("id")?).await?;
+            cfg.playlists
+                .delete(playlist.id)
+                .await
+                .map_err(Failure::generic)?;
+            Reply::Body(Map::new())
+        }
+        method => return Err(Failure::generic(format!("Unknown method '{}'", method))),
+    })
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Writes `value` as the XML element `name`, the way the JSON of the API maps onto its XML:
+/// scalars as attributes, objects as elements, arrays as repeated elements and `value` as text
+fn xml(name: &str, value: &Value, out: &mut String) {
+    match value {
+        Value::Object(fields) => {
+            out.push('<');
+            out.push_str(name);
+            for (key, value) in fields {
+                if !matches!(value, Value::Object(_) | Value::Array(_) | Value::Null)
+                    && key != "value"
+                {
+                    out.push_str(&format!(" {}=\"{}\"", key, escape(&library::text(value))));
+                }
+            }
+            out.push('>');
+            for (key, value) in fields {
+                match value {
+                    Value::Object(_) | Value::Array(_) => xml(key, value, out),
+                    Value::Null => {}
+                    value if key == "value" => out.push_str(&escape(&library::text(value))),
+                    _ => {}
+                }
+            }
+            out.push_str(&format!("</{}>", name));
+        }
+        Value::Array(items) => {
+            for item in items {
+                xml(name, item, out);
+            }
+        }
+        Value::Null => {}
+        value => out.push_str(&format!(
+            "<{}>{}</{}>",
+            name,
+            escape(&library::text(value)),
+            name
+        )),
+    }
+}
+
+fn respond(params: &Params, result: Result<Reply, Failure>) -> Response {
+    let mut response = object(json!({
+        "status": "ok",
+        "version": match params.get("apiKey") {
+            Some(_) => VERSION,
+            None => LEGACY_VERSION,
+        },
+        "type": "recordbox",
+        "serverVersion": env!("CARGO_PKG_VERSION"),
+        "openSubsonic": true,
+    }));
+    match result {
+        Ok(Reply::Raw(raw)) => return raw,
+        Ok(Reply::Body(body)) => response.extend(body),
+        Err(Failure(code, message)) => {
+            response.insert("status".to_string(), json!("failed"));
+            response.insert(
+                "error".to_string(),
+                json!({ "code": code, "message": message }),
+            );
+        }
+    }
+    match params.get("f") {
+        Some("json") => axum::Json(json!({ "subsonic-response": response })).into_response(),
+        _ => {
+            response.insert("xmlns".to_string(), json!("http://subsonic.org/restapi"));
+            let mut out = r#"<?xml version="1.0" encoding="UTF-8"?>"#.to_string();
+            xml("subsonic-response", &Value::Object(response), &mut out);
+            ([(header::CONTENT_TYPE, "text/xml; charset=utf-8")], out).into_response()
+        }
+    }
+}
+
+/// Answers a method of the Subsonic API such as `getAlbum` (or `getAlbum.view`), with its
+/// parameters in the query or, when posted, a form
+pub(crate) async fn rest(
+    extract::State(cfg): extract::State<Arc<util::Configuration>>,
+    extract::Path(method): extract::Path<String>,
+    extract::Query(mut params): extract::Query<Vec<(String, String)>>,
+    request: Method,
+    headers: HeaderMap,
+    form: Result<extract::Form<Vec<(String, String)>>, FormRejection>,
+) -> Response {
+    if request == Method::POST
+        && let Ok(extract::Form(form)) = form
+    {
+        params.extend(form);
+    }
+    let params = Params(params);
+    let method = method.trim_end_matches(".view");
+    let result = call(&cfg, method, &params, &headers).await;
+    respond(&params, result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamp() {
+        assert_eq!(super::timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(super::timestamp(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(super::timestamp(984470400), "2001-03-13T08:00:00Z");
+    }
+
+    #[test]
+    fn range() {
+        assert_eq!(super::range("bytes=0-99", 1000), Some((0, 99)));
+        assert_eq!(super::range("bytes=900-", 1000), Some((900, 999)));
+        assert_eq!(super::range("bytes=-100", 1000), Some((900, 999)));
+        assert_eq!(super::range("bytes=0-5000", 1000), Some((0, 999)));
+        assert_eq!(super::range("bytes=1000-", 1000), None);
+        assert_eq!(super::range("items=0-1", 1000), None);
+        assert_eq!(super::range("bytes=0-0", 0), None);
+    }
+
+    #[tokio::test]
+    async fn response() {
+        let params = Params(vec![("f".to_string(), "xml".to_string())]);
+        let reply = body(
+            "lyrics",
+            json!({ "artist": "Daft Punk", "title": "Digital Love", "value": "Why don't you & I" }),
+        );
+        let response = respond(&params, Ok(reply));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(body.to_vec()).unwrap(),
+            format!(
+                concat!(
+                    r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+                    r#"<subsonic-response openSubsonic="true" serverVersion="{}" status="ok" "#,
+                    r#"type="recordbox" version="{}" xmlns="http://subsonic.org/restapi">"#,
+                    r#"<lyrics artist="Daft Punk" title="Digital Love">Why don't you &amp; I"#,
+                    r#"</lyrics></subsonic-response>"#
+                ),
+                env!("CARGO_PKG_VERSION"),
+                LEGACY_VERSION
+            )
+        );
+
+        let params = Params(vec![("f".to_string(), "json".to_string())]);
+        let response = respond(&params, Err(Failure::missing("id")));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = serde_json::from_slice::<Value>(&body).unwrap();
+        assert_eq!(body["subsonic-response"]["status"], "failed");
+        assert_eq!(body["subsonic-response"]["error"]["code"], 10);
+        assert_eq!(body["subsonic-response"]["version"], LEGACY_VERSION);
+
+        let params = Params(vec![
+            ("f".to_string(), "json".to_string()),
+            ("apiKey".to_string(), "key".to_string()),
+        ]);
+        let response = respond(&params, Ok(Reply::Body(Map::new())));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = serde_json::from_slice::<Value>(&body).unwrap();
+        assert_eq!(body["subsonic-response"]["version"], VERSION);
+    }
+}
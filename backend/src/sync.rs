@@ -1,12 +1,89 @@
-use crate::util;
+use crate::{credit::Credit, error::Error, history, lyrics, util};
 use axum::http::Uri;
 use mp4ameta::{ReadConfig, WriteConfig};
-use reqwest::StatusCode;
-use std::{fs, path::Path};
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, PoisonError, Weak},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{Mutex, OwnedMutexGuard};
 
-pub async fn track_download(url: Uri, dst_dir: &Path) -> Result<(), (StatusCode, String)> {
+/// Locks of the files being changed, so that edits, deletions and downloads of a track cannot
+/// interleave
+static LOCKS: LazyLock<std::sync::Mutex<HashMap<PathBuf, Weak<Mutex<()>>>>> =
+    LazyLock::new(Default::default);
+
+/// Waits until no one else changes `file`, holding it until the guard is dropped
+async fn lock(file: &Path) -> OwnedMutexGuard<()> {
+    let lock = {
+        let mut locks = LOCKS.lock().unwrap_or_else(PoisonError::into_inner);
+        locks.retain(|_, lock| lock.strong_count() > 0);
+        match locks.get(file).and_then(Weak::upgrade) {
+            Some(lock) => lock,
+            None => {
+                let lock = Arc::new(Mutex::new(()));
+                locks.insert(file.to_path_buf(), Arc::downgrade(&lock));
+                lock
+            }
+        }
+    };
+    lock.lock_owned().await
+}
+
+/// Downloads the tracks at `url` into a hidden directory of the library, and moves them into
+/// place one by one holding their locks, so that a download never touches a track being changed
+pub async fn track_download(
+    url: Uri,
+    dst_dir: &Path,
+    history: &history::History,
+    change: &history::Change,
+) -> Result<(), Error> {
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    // hidden from `track_list` by its leading dot, and on the same file system for renames
+    let temp = dst_dir.join(format!(".download-{}", started));
+    fs::create_dir(&temp).map_err(Error::io)?;
+    let moved = async {
+        let mut moved = Vec::new();
+        for file in track_fetch(&url, &temp).await?.lines() {
+            let Some(name) = Path::new(file).file_name() else {
+                continue;
+            };
+            let file = dst_dir.join(name);
+            let _lock = lock(&file).await;
+            // as with `--no-overwrites`, a track already in the library is kept
+            if file.exists() {
+                continue;
+            }
+            fs::rename(temp.join(name), &file).map_err(Error::io)?;
+            moved.push(file);
+        }
+        Ok::<_, Error>(moved)
+    }
+    .await;
+    let _ = fs::remove_dir_all(&temp);
+    for file in moved? {
+        // the track is downloaded either way, its credits can be fixed later
+        if let Err(e) = track_credit(&file, dst_dir, history, change).await {
+            eprintln!(
+                "Splitting the credits of {} failed: {:?}",
+                file.display(),
+                e
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Runs yt-dlp in `dir`, returning the paths of the files it downloaded, one per line
+async fn track_fetch(url: &Uri, dir: &Path) -> Result<String, Error> {
     let cmd = tokio::process::Command::new("yt-dlp")
-        .current_dir(dst_dir)
+        .current_dir(dir)
         .args([
             url.to_string().as_str(),
             "--quiet",
@@ -30,27 +107,58 @@ pub async fn track_download(url: Uri, dst_dir: &Path) -> Result<(), (StatusCode,
             "--embed-thumbnail",
             "--ppa",
             "ffmpeg: -c:v mjpeg -vf crop=\"'if(gt(ih,iw),iw,ih)':'if(gt(iw,ih),ih,iw)'\"",
+            "--print",
+            "after_move:filepath",
         ])
         .env_clear()
-        .stdout(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
         .spawn();
     match cmd {
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err((
-            StatusCode::METHOD_NOT_ALLOWED,
-            "Track Download requires yt-dlp".to_string(),
-        )),
-        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
-        Ok(mut child) => match child.wait().await {
-            Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
-            Ok(e) if !e.success() => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
-            Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(Error::DownloaderMissing),
+        Err(e) => Err(Error::Internal(e.to_string())),
+        Ok(child) => match child.wait_with_output().await {
+            Err(e) => Err(Error::Internal(e.to_string())),
+            Ok(o) if !o.status.success() => Err(Error::DownloadFailed(o.status.to_string())),
+            Ok(o) => Ok(String::from_utf8_lossy(&o.stdout).into_owned()),
         },
     }
 }
 
-pub fn track_list(dst_dir: &Path) -> Result<Vec<String>, (StatusCode, String)> {
-    Ok(fs::read_dir(dst_dir)
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+/// Splits the featured artists off the credits yt-dlp embeds as they were uploaded
+async fn track_credit(
+    file: &Path,
+    dst_dir: &Path,
+    history: &history::History,
+    change: &history::Change,
+) -> Result<(), Error> {
+    let Some(track) = file.file_prefix().and_then(|f| f.to_str()) else {
+        return Ok(());
+    };
+    let meta: util::Metadata = track_info(track, dst_dir)?.into();
+    let Some(mut title) = meta.title.as_deref() else {
+        return Ok(());
+    };
+    let mut artists = meta.artists.clone();
+    // videos without artist metadata carry it in their title
+    if artists.is_empty()
+        && let Some((artist, rest)) = title.split_once(" - ")
+    {
+        artists.push(artist.to_string());
+        title = rest;
+    }
+    let credit = Credit::featured(title, &artists);
+    let meta = util::Metadata {
+        title: Some(credit.title),
+        artists: credit.artists,
+        ..Default::default()
+    };
+    track_edit(track, dst_dir, meta, Edit::Patch, None, history, change).await?;
+    Ok(())
+}
+
+pub fn track_list(dst_dir: &Path) -> Result<Vec<String>, Error> {
+    fs::read_dir(dst_dir)
+        .map_err(|e| Error::Library(e.to_string()))?
         .filter_map(|f| {
             let Ok(file) = f else {
                 return None;
@@ -59,45 +167,181 @@ pub fn track_list(dst_dir: &Path) -> Result<Vec<String>, (StatusCode, String)> {
             if !file.is_file() {
                 return None;
             }
-            let Some(ext) = file.extension() else {
-                return None;
-            };
+            let ext = file.extension()?;
             if !ext.eq_ignore_ascii_case("m4a") {
                 return None;
             }
-            let Some(path) = file.file_prefix().map(|fp| fp.to_str()).flatten() else {
-                return Some(Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Track Path Invalid".to_string(),
-                )));
+            let Some(path) = file.file_prefix().and_then(|fp| fp.to_str()) else {
+                return Some(Err(Error::Internal("Track Path Invalid".to_string())));
             };
             if path.starts_with(".") {
                 return None;
             }
             Some(Ok(path.to_string()))
         })
-        .collect::<Result<Vec<_>, _>>()?)
+        .collect::<Result<Vec<_>, _>>()
 }
 
-pub fn track_delete(track: &str, dst_dir: &Path) -> Result<(), (StatusCode, String)> {
-    fs::remove_file(dst_dir.join(track).with_added_extension("m4a")).map_err(|e| match e.kind() {
-        std::io::ErrorKind::NotFound => (
-            StatusCode::NOT_MODIFIED,
-            "Track Already Deleted".to_string(),
-        ),
-        std::io::ErrorKind::PermissionDenied => (
-            StatusCode::FORBIDDEN,
-            "Filesystem Permission Denied".to_string(),
-        ),
-        std::io::ErrorKind::ReadOnlyFilesystem => (
-            StatusCode::FORBIDDEN,
-            "Filesystem Read-Only".to_string(), //
+/// Directory of the library holding deleted tracks, hidden from `track_list` by its leading dot
+const TRASH: &str = ".trash";
+
+/// A deleted track, restorable until purged
+#[derive(serde::Serialize)]
+pub(crate) struct Trashed {
+    /// Name in the trash, unique to each deletion
+    id: String,
+    /// ID of the track in the library, which it is restored as
+    track: String,
+    /// Seconds since the Unix epoch
+    deleted: i64,
+    #[serde(flatten)]
+    meta: util::Metadata,
+}
+
+/// Name of `track` in `trash` after its deletion at `deleted`, so that deleting a track again
+/// keeps the copy deleted before, even within the same millisecond
+fn trash_name(trash: &Path, track: &str, deleted: SystemTime) -> String {
+    let millis = deleted
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    (millis..)
+        .map(|millis| format!("{}@{}", track, millis))
+        .find(|name| !trash.join(name).with_added_extension("m4a").exists())
+        .unwrap_or_default()
+}
+
+/// The track and deletion time of `name` in the trash, where tracks trashed without the time in
+/// their name were deleted at their modification time
+fn trash_entry(trash: &Path, name: &str) -> (String, Option<SystemTime>) {
+    let entry = name.rsplit_once('@').and_then(|(track, millis)| {
+        let millis = millis.parse().ok()?;
+        Some((
+            track.to_string(),
+            UNIX_EPOCH + Duration::from_millis(millis),
+        ))
+    });
+    match entry {
+        Some((track, deleted)) => (track, Some(deleted)),
+        None => (
+            name.to_string(),
+            fs::metadata(trash.join(name).with_added_extension("m4a"))
+                .and_then(|m| m.modified())
+                .ok(),
         ),
-        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+/// The file of `name` in the trash, if it is there
+fn trash_file(dst_dir: &Path, name: &str) -> Result<PathBuf, Error> {
+    let trash = dst_dir.join(TRASH);
+    if !trash.is_dir() || !track_list(&trash)?.iter().any(|t| t == name) {
+        return Err(Error::TrackNotFound);
+    }
+    Ok(trash.join(name).with_added_extension("m4a"))
+}
+
+/// Moves `track` to the trash under a name with the time of its deletion
+pub async fn track_delete(track: &str, dst_dir: &Path) -> Result<(), Error> {
+    let file = dst_dir.join(track).with_added_extension("m4a");
+    let _lock = lock(&file).await;
+    if !file.is_file() {
+        return Err(Error::TrackNotFound);
+    }
+    let trash = dst_dir.join(TRASH);
+    fs::create_dir_all(&trash).map_err(Error::io)?;
+    let trashed = trash
+        .join(trash_name(&trash, track, SystemTime::now()))
+        .with_added_extension("m4a");
+    let _trash_lock = lock(&trashed).await;
+    fs::rename(file, &trashed).map_err(Error::io)
+}
+
+pub fn trash_list(dst_dir: &Path) -> Result<Vec<Trashed>, Error> {
+    let trash = dst_dir.join(TRASH);
+    if !trash.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut tracks = track_list(&trash)?
+        .into_iter()
+        .map(|id| {
+            // listed regardless, so that it can be restored or purged
+            let meta = track_info(&id, &trash)
+                .map(util::Metadata::from)
+                .unwrap_or_default();
+            let (track, deleted) = trash_entry(&trash, &id);
+            let deleted = deleted
+                .and_then(|d| d.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or_default();
+            Trashed {
+                id,
+                track,
+                deleted,
+                meta: util::Metadata {
+                    lyrics: None,
+                    ..meta
+                },
+            }
+        })
+        .collect::<Vec<_>>();
+    tracks.sort_by(|a, b| b.deleted.cmp(&a.deleted).then_with(|| a.id.cmp(&b.id)));
+    Ok(tracks)
+}
+
+/// Moves `name` back from the trash unless the library has another track by its ID
+pub async fn trash_restore(name: &str, dst_dir: &Path) -> Result<(), Error> {
+    let trashed = trash_file(dst_dir, name)?;
+    let (track, _) = trash_entry(&dst_dir.join(TRASH), name);
+    let file = dst_dir.join(track).with_added_extension("m4a");
+    let _lock = lock(&file).await;
+    let _trash_lock = lock(&trashed).await;
+    if file.exists() {
+        return Err(Error::TrackExists);
+    }
+    fs::rename(trashed, file).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => Error::TrackNotFound,
+        _ => Error::io(e),
+    })
+}
+
+/// Removes `name` from the trash for good
+pub async fn trash_delete(name: &str, dst_dir: &Path) -> Result<(), Error> {
+    let trashed = trash_file(dst_dir, name)?;
+    let _lock = lock(&trashed).await;
+    fs::remove_file(trashed).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => Error::TrackNotFound,
+        _ => Error::io(e),
     })
 }
 
-pub fn track_info(track: &str, dst_dir: &Path) -> Result<mp4ameta::Tag, (StatusCode, String)> {
+/// Removes the tracks deleted longer than `retention` ago (or all of them), returning how many
+/// were removed
+pub async fn trash_purge(dst_dir: &Path, retention: Option<Duration>) -> Result<usize, Error> {
+    let trash = dst_dir.join(TRASH);
+    if !trash.is_dir() {
+        return Ok(0);
+    }
+    let now = SystemTime::now();
+    let mut purged = 0;
+    for name in track_list(&trash)? {
+        let file = trash.join(&name).with_added_extension("m4a");
+        let _lock = lock(&file).await;
+        let expired = retention.is_none_or(|retention| {
+            trash_entry(&trash, &name)
+                .1
+                .and_then(|d| now.duration_since(d).ok())
+                .is_some_and(|age| age >= retention)
+        });
+        if expired {
+            fs::remove_file(&file).map_err(Error::io)?;
+            purged += 1;
+        }
+    }
+    Ok(purged)
+}
+
+pub fn track_info(track: &str, dst_dir: &Path) -> Result<mp4ameta::Tag, Error> {
     mp4ameta::Tag::read_with_path(
         dst_dir.join(track).with_added_extension("m4a"),
         &ReadConfig {
@@ -108,58 +352,573 @@ pub fn track_info(track: &str, dst_dir: &Path) -> Result<mp4ameta::Tag, (StatusC
             ..Default::default()
         },
     )
-    .map_err(|e| match e.kind {
-        mp4ameta::ErrorKind::Io(err) => match err.kind() {
-            std::io::ErrorKind::NotFound => (StatusCode::NOT_FOUND, "Track Not Found".to_string()),
-            std::io::ErrorKind::PermissionDenied => (
-                StatusCode::FORBIDDEN,
-                "Filesystem Permission Denied".to_string(),
+    .map_err(Error::tag)
+}
+
+/// The metadata of `track` with the digest of its artwork, as entity tags are computed
+pub async fn track_metadata(track: &str, dst_dir: &Path) -> Result<util::Metadata, Error> {
+    track_read(&dst_dir.join(track).with_added_extension("m4a"))
+        .await
+        .map(util::Metadata::from)
+}
+
+/// The complete tag of `file`, read off the runtime as the artwork may be large
+async fn track_read(file: &Path) -> Result<mp4ameta::Tag, Error> {
+    let file = file.to_path_buf();
+    tokio::task::spawn_blocking(move || track_tag(&file))
+        .await
+        .map_err(|e| Error::Internal(e.to_string()))?
+}
+
+/// The complete tag of `file` to be written back, including its artwork
+fn track_tag(file: &Path) -> Result<mp4ameta::Tag, Error> {
+    mp4ameta::Tag::read_with_path(
+        file,
+        &ReadConfig {
+            read_chapter_list: false,
+            read_chapter_track: false,
+            read_audio_info: true,
+            ..Default::default()
+        },
+    )
+    .map_err(Error::tag)
+}
+
+/// The contents of the top-level `mdat` boxes of an MP4 file, which hold its audio
+fn audio(data: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut boxes = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let size = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?);
+        let (header, size) = match size {
+            0 => (8, rest.len()),
+            1 => (
+                16,
+                usize::try_from(u64::from_be_bytes(rest.get(8..16)?.try_into().ok()?)).ok()?,
             ),
-            _ => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+            size => (8, size as usize),
+        };
+        if size < header || size > rest.len() {
+            return None;
+        }
+        if rest.get(4..8)? == b"mdat" {
+            boxes.push(&rest[header..size]);
+        }
+        rest = &rest[size..];
+    }
+    Some(boxes)
+}
+
+/// A tag written into a hidden copy of a track, which replaces it on `commit` and is removed
+/// if dropped before
+struct Staged {
+    file: PathBuf,
+    temp: Option<PathBuf>,
+}
+
+impl Staged {
+    async fn commit(mut self) -> Result<(), Error> {
+        let (Some(temp), file) = (self.temp.take(), self.file.clone()) else {
+            return Ok(());
+        };
+        tokio::task::spawn_blocking(move || {
+            let rename = || {
+                fs::rename(&temp, &file).map_err(Error::io)?;
+                // the rename itself is durable once the directory is
+                if let Some(dir) = file.parent() {
+                    fs::File::open(dir)
+                        .and_then(|d| d.sync_all())
+                        .map_err(Error::io)?;
+                }
+                Ok(())
+            };
+            let result = rename();
+            if result.is_err() {
+                let _ = fs::remove_file(&temp);
+            }
+            result
+        })
+        .await
+        .map_err(|e| Error::Internal(e.to_string()))?
+    }
+}
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        if let Some(temp) = self.temp.take() {
+            let _ = fs::remove_file(temp);
+        }
+    }
+}
+
+/// Writes `tag` into a copy of `file` to be committed once the copy reads back with the same
+/// audio, so that a failed write leaves the track as it was, handing the tag back
+async fn tag_write(tag: mp4ameta::Tag, file: &Path) -> Result<(mp4ameta::Tag, Staged), Error> {
+    let file = file.to_path_buf();
+    // copying, comparing and syncing a track takes a while, which the runtime should not wait for
+    tokio::task::spawn_blocking(move || {
+        let temp = tag_write_blocking(&tag, &file)?;
+        Ok((
+            tag,
+            Staged {
+                file,
+                temp: Some(temp),
+            },
+        ))
+    })
+    .await
+    .map_err(|e| Error::Internal(e.to_string()))?
+}
+
+fn tag_write_blocking(tag: &mp4ameta::Tag, file: &Path) -> Result<PathBuf, Error> {
+    let Some(name) = file.file_name().and_then(|f| f.to_str()) else {
+        return Err(Error::Internal("Track Path Invalid".to_string()));
+    };
+    // hidden from `track_list` by its leading dot
+    let temp = file.with_file_name(format!(".{}.tmp", name));
+    let write = || {
+        fs::copy(file, &temp).map_err(Error::io)?;
+        tag.write_with_path(
+            &temp,
+            &WriteConfig {
+                write_chapter_list: false,
+                write_chapter_track: false,
+                ..Default::default()
+            },
+        )
+        .map_err(Error::tag)?;
+        track_tag(&temp).map_err(|_| Error::WriteFailed("Written tag unreadable".to_string()))?;
+        let original = fs::read(file).map_err(Error::io)?;
+        let written = fs::read(&temp).map_err(Error::io)?;
+        if audio(&original).is_none_or(|a| audio(&written) != Some(a)) {
+            return Err(Error::WriteFailed("Audio changed".to_string()));
+        }
+        fs::File::open(&temp)
+            .and_then(|f| f.sync_all())
+            .map_err(Error::io)
+    };
+    match write() {
+        Ok(()) => Ok(temp),
+        Err(e) => {
+            let _ = fs::remove_file(&temp);
+            Err(e)
+        }
+    }
+}
+
+pub fn track_lyrics(track: &str, dst_dir: &Path) -> Result<lyrics::Lyrics, Error> {
+    let Some(lyrics) = track_info(track, dst_dir)?.take_lyrics() else {
+        return Err(Error::LyricsNotFound);
+    };
+    lyrics::Lyrics::parse(&lyrics).map_err(Error::InvalidLyrics)
+}
+
+/// Most bytes of artwork fetched for a track
+const ARTWORK_LIMIT: usize = 8 * 1024 * 1024;
+
+/// Whether `ip` is reachable from anywhere, rather than on the host or its own networks
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // shared address space of carrier-grade NAT
+                || (a == 100 && (64..128).contains(&b))
+                // benchmarking
+                || (a == 198 && (18..20).contains(&b))
+                // reserved
+                || a >= 240
+                || a == 0)
+        }
+        // IPv4-mapped and IPv4-compatible addresses
+        IpAddr::V6(ip) => match ip.to_ipv4() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let [first, second, ..] = ip.segments();
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // unique local and link-local
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80
+                    // NAT64 and 6to4, which reach IPv4 addresses through a gateway
+                    || (first == 0x64 && second == 0xff9b)
+                    || first == 0x2002)
+            }
         },
-        _ => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Corrupted File".to_string(),
-        ),
+    }
+}
+
+/// Fetches artwork over HTTP(S) from public addresses only, following redirects one at a time so
+/// that each is checked, and giving up on anything larger than `ARTWORK_LIMIT`
+async fn artwork_fetch(url: &str) -> Result<mp4ameta::ImgBuf, Error> {
+    let unavailable = |e: &dyn ToString| Error::ArtworkUnavailable(e.to_string());
+    let mut url = reqwest::Url::parse(url).map_err(|e| unavailable(&e))?;
+    let mut redirects = 0;
+    let mut response = loop {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(unavailable(&format!("Scheme of {} not allowed", url)));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| unavailable(&format!("Host of {} missing", url)))?
+            .trim_matches(['[', ']'])
+            .to_string();
+        let port = url.port_or_known_default().unwrap_or(443);
+        let addrs = tokio::net::lookup_host((host.as_str(), port))
+            .await
+            .map_err(|e| unavailable(&e))?
+            .collect::<Vec<_>>();
+        if addrs.is_empty() || addrs.iter().any(|a| !is_public(a.ip())) {
+            return Err(unavailable(&format!("Address of {} not allowed", host)));
+        }
+        // connects to the addresses checked, whatever the host resolves to by then
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(Duration::from_secs(30))
+            .resolve_to_addrs(&host, &addrs)
+            .build()
+            .map_err(|e| unavailable(&e))?;
+        let response = client
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|e| unavailable(&e))?;
+        if !response.status().is_redirection() {
+            break response.error_for_status().map_err(|e| unavailable(&e))?;
+        }
+        redirects += 1;
+        let location = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .filter(|_| redirects <= 5)
+            .ok_or_else(|| unavailable(&format!("Redirect of {} not followed", url)))?;
+        url = url.join(location).map_err(|e| unavailable(&e))?;
+    };
+    let mut data = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|e| unavailable(&e))? {
+        if data.len() + chunk.len() > ARTWORK_LIMIT {
+            return Err(unavailable(&format!(
+                "Artwork larger than {} bytes",
+                ARTWORK_LIMIT
+            )));
+        }
+        data.extend(chunk);
+    }
+    if data.starts_with(b"\x89PNG") {
+        Ok(mp4ameta::Img::png(data))
+    } else if data.starts_with(b"\xFF\xD8") {
+        Ok(mp4ameta::Img::jpeg(data))
+    } else if data.starts_with(b"BM") {
+        Ok(mp4ameta::Img::bmp(data))
+    } else {
+        Err(Error::ArtworkUnsupported)
+    }
+}
+
+/// Whether the entity tag of a track satisfies an `If-Match` header, which may list several,
+/// comparing strongly so that weak tags never match
+fn precondition(if_match: Option<&str>, etag: &str) -> bool {
+    if_match.is_none_or(|tags| {
+        tags.split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag == etag)
     })
 }
 
-pub fn track_edit(
+/// How an edit applies its metadata to a track
+pub enum Edit {
+    /// Sets only the fields given
+    Patch,
+    /// Replaces every field
+    Replace,
+    /// Replaces every field and, if the reverted edit changed it, the artwork with its journaled
+    /// digest, which is none if the track had none
+    Revert(Option<Option<u64>>),
+}
+
+/// Writes `meta` into the tag of `track` unless it changed from `if_match`, journaling the edit
+/// as part of `change` before it lands and returning its new entity tag
+pub async fn track_edit(
     track: &str,
     dst_dir: &Path,
-    meta: util::Metadata,
-    patch: bool,
-) -> Result<(), (StatusCode, String)> {
-    let mut tag = track_info(track, dst_dir)?;
-    if patch {
-        meta.apply(&mut tag);
-    } else {
-        meta.write(&mut tag);
+    mut meta: util::Metadata,
+    edit: Edit,
+    if_match: Option<&str>,
+    history: &history::History,
+    change: &history::Change,
+) -> Result<String, Error> {
+    let artwork = match meta.artwork.take() {
+        Some(url) => Some(artwork_fetch(&url).await?),
+        None => None,
+    };
+    let file = dst_dir.join(track).with_added_extension("m4a");
+    let _lock = lock(&file).await;
+    let mut tag = track_read(&file).await?;
+    let current = util::Metadata::from(tag.clone());
+    let etag = current.etag();
+    if !precondition(if_match, &etag) {
+        return Err(Error::Modified(Box::new(current)));
     }
-    tag.write_with_path(
-        dst_dir.join(track).with_added_extension("m4a"),
-        &WriteConfig {
-            write_chapter_list: false,
-            write_chapter_track: false,
-            ..Default::default()
-        },
-    )
-    .map_err(|e| match e.kind {
-        mp4ameta::ErrorKind::Io(err) => match err.kind() {
-            std::io::ErrorKind::NotFound => (StatusCode::NOT_FOUND, "Track Not Found".to_string()),
-            std::io::ErrorKind::PermissionDenied => (
-                StatusCode::FORBIDDEN,
-                "Filesystem Permission Denied".to_string(),
-            ),
-            std::io::ErrorKind::ReadOnlyFilesystem => (
-                StatusCode::FORBIDDEN,
-                "Filesystem Read-Only".to_string(), //
-            ),
-            _ => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
-        },
-        _ => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Corrupted File".to_string(),
+    if let Some(lyrics) = &meta.lyrics {
+        lyrics::Lyrics::parse(lyrics)
+            .and_then(|l| l.validate(tag.duration()))
+            .map_err(Error::InvalidLyrics)?;
+    }
+    let original = tag.clone();
+    match edit {
+        Edit::Patch => meta.apply(&mut tag),
+        Edit::Replace | Edit::Revert(None) => meta.write(&mut tag),
+        Edit::Revert(Some(digest)) => {
+            meta.write(&mut tag);
+            match digest {
+                Some(digest) => tag.set_artwork(
+                    history
+                        .artwork(digest)
+                        .await
+                        .map_err(Error::History)?
+                        .ok_or_else(|| Error::History("Artwork not journaled".to_string()))?,
+                ),
+                None => tag.remove_artworks(),
+            }
+        }
+    }
+    if let Some(artwork) = artwork {
+        tag.set_artwork(artwork);
+    }
+    let (tag, staged) = tag_write(tag, &file).await?;
+    let edited = util::Metadata::from(tag);
+    let new_etag = edited.etag();
+    // a failed journal drops the staged write, so that every edit made can be reverted, and a
+    // failed commit drops the journaled edit, so that only edits made are listed
+    let journaled = match new_etag != etag {
+        true => Some(
+            history
+                .record(track, change, &current, &edited, original.artwork())
+                .await
+                .map_err(Error::History)?,
         ),
-    })
+        false => None,
+    };
+    if let Err(e) = staged.commit().await {
+        if let Some(edit) = journaled {
+            history.discard(edit).await.map_err(Error::History)?;
+        }
+        return Err(e);
+    }
+    Ok(new_etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audio() {
+        let mdat = |data: &[u8]| {
+            let mut b = ((data.len() + 8) as u32).to_be_bytes().to_vec();
+            b.extend(b"mdat");
+            b.extend(data);
+            b
+        };
+        let file = [
+            b"\0\0\0\x0cftypM4A ".to_vec(),
+            mdat(b"audio"),
+            b"\0\0\0\x08moov".to_vec(),
+        ]
+        .concat();
+        assert_eq!(super::audio(&file), Some(vec![&b"audio"[..]]));
+        assert_eq!(super::audio(&file[..file.len() - 1]), None);
+        assert_eq!(super::audio(b""), Some(vec![]));
+    }
+
+    #[test]
+    fn is_public() {
+        for ip in ["93.184.215.14", "2606:2800:21f:cb07:6820:80da:af6b:8b2c"] {
+            assert!(super::is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::127.0.0.1",
+            "::10.1.2.3",
+            "64:ff9b::7f00:1",
+            "64:ff9b:1::a01:203",
+            "2002:7f00:1::1",
+            "198.18.0.1",
+            "198.19.255.254",
+            "240.0.0.1",
+        ] {
+            assert!(!super::is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn artwork_fetch() {
+        for url in [
+            "file:///etc/passwd",
+            "http://127.0.0.1:4000/health",
+            "http://[::1]/",
+            "http://169.254.169.254/latest/meta-data/",
+        ] {
+            assert!(
+                matches!(
+                    super::artwork_fetch(url).await,
+                    Err(Error::ArtworkUnavailable(_))
+                ),
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn lock() {
+        let file = Path::new("a.m4a");
+        let guard = super::lock(file).await;
+        let other = tokio::spawn(async { super::lock(Path::new("a.m4a")).await });
+        tokio::task::yield_now().await;
+        assert!(!other.is_finished());
+        drop(guard);
+        other.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn trash() {
+        let dir = std::env::temp_dir().join(format!("recordbox-trash-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.m4a"), b"").unwrap();
+        track_delete("a", &dir).await.unwrap();
+        assert!(matches!(
+            track_delete("a", &dir).await,
+            Err(Error::TrackNotFound)
+        ));
+        assert!(track_list(&dir).unwrap().is_empty());
+        let trashed = trash_list(&dir).unwrap();
+        assert_eq!(trashed.len(), 1);
+        assert_eq!(trashed[0].track, "a");
+        let first = trashed[0].id.clone();
+
+        // deleted again after downloading it again, keeping both
+        fs::write(dir.join("a.m4a"), b"").unwrap();
+        assert!(matches!(
+            trash_restore(&first, &dir).await,
+            Err(Error::TrackExists)
+        ));
+        track_delete("a", &dir).await.unwrap();
+        assert_eq!(trash_list(&dir).unwrap().len(), 2);
+        assert!(matches!(
+            trash_restore("../a", &dir).await,
+            Err(Error::TrackNotFound)
+        ));
+        trash_restore(&first, &dir).await.unwrap();
+        assert_eq!(track_list(&dir).unwrap(), ["a"]);
+        assert_eq!(trash_list(&dir).unwrap().len(), 1);
+
+        track_delete("a", &dir).await.unwrap();
+        assert_eq!(
+            trash_purge(&dir, Some(Duration::from_secs(60 * 60)))
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(trash_purge(&dir, None).await.unwrap(), 2);
+        assert!(trash_list(&dir).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// An MP4 file with a movie header and `audio` as its media data but no tag yet
+    fn mp4(audio: &[u8]) -> Vec<u8> {
+        let atom = |name: &[u8], data: &[u8]| {
+            let mut b = ((data.len() + 8) as u32).to_be_bytes().to_vec();
+            b.extend(name);
+            b.extend(data);
+            b
+        };
+        let mut mvhd = vec![0; 100];
+        // a timescale of 1000 and a duration of 5000
+        mvhd[12..16].copy_from_slice(&1000_u32.to_be_bytes());
+        mvhd[16..20].copy_from_slice(&5000_u32.to_be_bytes());
+        [
+            atom(b"ftyp", b"M4A \0\0\0\0M4A mp42isom"),
+            atom(b"moov", &atom(b"mvhd", &mvhd)),
+            atom(b"mdat", audio),
+        ]
+        .concat()
+    }
+
+    #[tokio::test]
+    async fn edit_artwork() {
+        let dir = std::env::temp_dir().join(format!("recordbox-edit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("a.m4a");
+        fs::write(&file, mp4(b"audio")).unwrap();
+        let mut tag = mp4ameta::Tag::default();
+        tag.set_title("One More Time");
+        tag.set_artwork(mp4ameta::Img::png(b"\x89PNG".to_vec()));
+        tag.write_to_path(&file).unwrap();
+
+        let history = history::History::new(":memory:".to_string()).unwrap();
+        let change = history.change(history::Kind::Manual, None).await.unwrap();
+        let mut meta = track_metadata("a", &dir).await.unwrap();
+        let etag = meta.etag();
+        meta.title = Some("one more time".to_string());
+        let edited = track_edit(
+            "a",
+            &dir,
+            meta,
+            Edit::Replace,
+            Some(&etag),
+            &history,
+            &change,
+        )
+        .await
+        .unwrap();
+        let meta = track_metadata("a", &dir).await.unwrap();
+        assert_eq!(meta.title.as_deref(), Some("one more time"));
+        assert_eq!(meta.etag(), edited);
+        assert!(matches!(
+            track_edit(
+                "a",
+                &dir,
+                meta,
+                Edit::Replace,
+                Some(&etag),
+                &history,
+                &change
+            )
+            .await,
+            Err(Error::Modified(_))
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn precondition() {
+        let etag = util::Metadata::default().etag();
+        assert!(super::precondition(None, &etag));
+        assert!(super::precondition(Some("*"), &etag));
+        assert!(super::precondition(
+            Some(&format!("\"0000000000000000\", {}", etag)),
+            &etag
+        ));
+        assert!(!super::precondition(Some(&format!("W/{}", etag)), &etag));
+        assert!(!super::precondition(Some("\"0000000000000000\""), &etag));
+    }
 }
@@ -1,10 +1,15 @@
-use crate::autotag;
+use crate::{auth, autotag, date::Date, history, library, playlist};
 use config::{Config, ConfigError};
-use std::fs;
+use mp4ameta::{FreeformIdent, ident};
+use std::{collections::HashMap, fs, time::Duration};
 
 pub(crate) struct Configuration {
     config: Config,
     pub(crate) metadatasources: autotag::MetadataSources,
+    pub(crate) index: library::Index,
+    pub(crate) history: history::History,
+    pub(crate) users: auth::Users,
+    pub(crate) playlists: playlist::Playlists,
 }
 
 impl Configuration {
@@ -16,16 +21,72 @@ impl Configuration {
             cfg = cfg.add_source(config::File::with_name("config"));
         }
         let cfg = cfg
-            .add_source(config::Environment::with_prefix("RECORDBOX"))
+            .add_source(
+                config::Environment::with_prefix("RECORDBOX")
+                    .prefix_separator("_")
+                    .separator("__"),
+            )
             .build()?;
+        let cache = autotag::Cache::new(
+            cfg.get_string("cache.file")
+                .unwrap_or("cache.sqlite".to_string()),
+        )
+        .map_err(ConfigError::Message)?;
+        let history = history::History::new(
+            cfg.get_string("history.file")
+                .unwrap_or("history.sqlite".to_string()),
+        )
+        .map_err(ConfigError::Message)?;
+        let users = auth::Users::new(
+            cfg.get_string("auth.file")
+                .unwrap_or("users.sqlite".to_string()),
+            Duration::from_secs(
+                cfg.get_int("auth.session")
+                    .unwrap_or(30 * 24 * 60 * 60)
+                    .max(0) as u64,
+            ),
+        )
+        .map_err(ConfigError::Message)?;
+        let playlists = playlist::Playlists::new(
+            cfg.get_string("playlists.file")
+                .unwrap_or("playlists.sqlite".to_string()),
+        )
+        .map_err(ConfigError::Message)?;
+        let ttl = |source: &str| {
+            let ttl = cfg
+                .get_int(format!("cache.ttl.{}", source).as_str())
+                .or(cfg.get_int("cache.ttl.default"))
+                .unwrap_or(7 * 24 * 60 * 60);
+            Duration::from_secs(ttl.max(0) as u64)
+        };
+        let mut sources = match cfg.get("sources") {
+            Err(ConfigError::NotFound(_)) => HashMap::new(),
+            sources => sources?,
+        };
+        if let Ok(file) = cfg.get_string("spotifydb") {
+            autotag::legacy_spotifydb(&mut sources, file);
+        }
+        let acoustid = match cfg.get("acoustid") {
+            Err(ConfigError::NotFound(_)) => None,
+            acoustid => Some(acoustid?),
+        };
+        let genres = match cfg.get("genres") {
+            Err(ConfigError::NotFound(_)) => autotag::GenreConfig::default(),
+            genres => genres?,
+        };
         Ok(Self {
-            metadatasources: autotag::MetadataSources::new(cfg.get_string("spotifydb").ok()),
+            metadatasources: autotag::MetadataSources::new(sources, acoustid, genres, cache, ttl)
+                .map_err(ConfigError::Message)?,
+            index: library::Index::default(),
+            history,
+            users,
+            playlists,
             config: cfg,
         })
     }
 
     pub(crate) fn get_library(&self) -> Result<std::path::PathBuf, String> {
-        const DIR: &'static str = "library";
+        const DIR: &str = "library";
         match self.config.get_string(DIR) {
             Err(_) => Err(format!("Directory '{}' unset", DIR)),
             Ok(path) => match fs::canonicalize(&path) {
@@ -36,24 +97,88 @@ impl Configuration {
         }
     }
 
+    /// How long deleted tracks are kept in the trash, unless forever
+    pub(crate) fn trash_retention(&self) -> Option<Duration> {
+        let retention = self
+            .config
+            .get_int("trash.retention")
+            .unwrap_or(30 * 24 * 60 * 60);
+        Some(Duration::from_secs(retention.max(0) as u64)).filter(|r| !r.is_zero())
+    }
+
     pub(crate) fn address(&self) -> Result<String, &'static str> {
         self.config
             .get_string("address")
             .or(Err("Address unset (required)"))
     }
+
+    /// Address to answer MPD clients on, unless disabled
+    pub(crate) fn mpd_address(&self) -> Option<String> {
+        self.config.get_string("mpd.address").ok()
+    }
+
+    /// Command and arguments of the player MPD clients play through, given the file last
+    pub(crate) fn mpd_output(&self) -> Option<Vec<String>> {
+        let output = self.config.get_string("mpd.output").ok()?;
+        let output = output
+            .split_whitespace()
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        (!output.is_empty()).then_some(output)
+    }
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Default, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Default, Clone, Debug)]
 pub struct Metadata {
     pub(crate) title: Option<String>,
     #[serde(default)]
     pub(crate) artists: Vec<String>,
     pub(crate) album: Option<String>,
-    pub(crate) date: Option<String>,
+    #[serde(default)]
+    pub(crate) album_artists: Vec<String>,
+    pub(crate) track: Option<u16>,
+    pub(crate) tracks: Option<u16>,
+    /// Release date of the album
+    pub(crate) date: Option<Date>,
+    /// Release date of the recording, earlier than `date` for reissues and compilations
+    pub(crate) original_date: Option<Date>,
     #[serde(default)]
     pub(crate) genres: Vec<String>,
     pub(crate) lyrics: Option<String>,
+    pub(crate) bpm: Option<u16>,
     pub(crate) isrc: Option<String>,
+    pub(crate) label: Option<String>,
+    pub(crate) catalog: Option<String>,
+    pub(crate) barcode: Option<String>,
+    /// MusicBrainz recording ID
+    pub(crate) mbid: Option<String>,
+    /// URL of cover art to embed, never read back from tags
+    pub(crate) artwork: Option<String>,
+    /// Length in whole seconds, read from the audio and never written
+    pub(crate) duration: Option<u32>,
+    /// Digest of the embedded cover art, so that entity tags change with it
+    #[serde(skip)]
+    pub(crate) artwork_digest: Option<u64>,
+}
+
+const MUSICBRAINZ_TRACK_ID: ident::FreeformIdentStatic =
+    FreeformIdent::new_static(ident::APPLE_ITUNES_MEAN, "MusicBrainz Track Id");
+const CATALOG_NUMBER: ident::FreeformIdentStatic =
+    FreeformIdent::new_static(ident::APPLE_ITUNES_MEAN, "CATALOGNUMBER");
+const BARCODE: ident::FreeformIdentStatic =
+    FreeformIdent::new_static(ident::APPLE_ITUNES_MEAN, "BARCODE");
+const ORIGINAL_DATE: ident::FreeformIdentStatic =
+    FreeformIdent::new_static(ident::APPLE_ITUNES_MEAN, "ORIGINALDATE");
+
+/// FNV-1a, which unlike `DefaultHasher` stays the same across builds
+pub(crate) fn fnv(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn freeform(tag: &mp4ameta::Tag, ident: &ident::FreeformIdentStatic) -> Option<String> {
+    tag.strings_of(ident).next().map(|a| a.to_string())
 }
 
 impl From<mp4ameta::Tag> for Metadata {
@@ -62,15 +187,36 @@ impl From<mp4ameta::Tag> for Metadata {
             title: value.title().map(|a| a.to_string()),
             artists: value.artists().map(|a| a.to_string()).collect(),
             album: value.album().map(|a| a.to_string()),
-            date: value.year().map(|a| a.to_string()),
+            album_artists: value.album_artists().map(|a| a.to_string()).collect(),
+            track: value.track_number(),
+            tracks: value.total_tracks(),
+            date: value.year().and_then(|a| a.parse().ok()),
+            original_date: freeform(&value, &ORIGINAL_DATE).and_then(|a| a.parse().ok()),
             genres: value.genres().map(|a| a.to_string()).collect(),
             lyrics: value.lyrics().map(|a| a.to_string()),
+            bpm: value.bpm(),
             isrc: value.isrc().map(|a| a.to_string()),
+            label: freeform(&value, &ident::LABEL),
+            catalog: freeform(&value, &CATALOG_NUMBER),
+            barcode: freeform(&value, &BARCODE),
+            mbid: freeform(&value, &MUSICBRAINZ_TRACK_ID),
+            artwork: None,
+            duration: Some(value.duration().as_secs_f64().round() as u32).filter(|d| *d > 0),
+            artwork_digest: value.artwork().map(|a| fnv(a.data)),
         }
     }
 }
 
 impl Metadata {
+    /// Identifies these contents, as an entity tag changing with any field or the artwork
+    pub(crate) fn etag(&self) -> String {
+        let mut contents = serde_json::to_vec(self).unwrap_or_default();
+        if let Some(digest) = self.artwork_digest {
+            contents.extend(digest.to_be_bytes());
+        }
+        format!("\"{:016x}\"", fnv(&contents))
+    }
+
     pub(crate) fn apply(self, tag: &mut mp4ameta::Tag) {
         if let Some(title) = self.title {
             tag.set_title(title);
@@ -81,8 +227,20 @@ impl Metadata {
         if let Some(album) = self.album {
             tag.set_album(album);
         }
+        if !self.album_artists.is_empty() {
+            tag.set_album_artists(self.album_artists);
+        }
+        if let Some(track) = self.track {
+            tag.set_track_number(track);
+        }
+        if let Some(tracks) = self.tracks {
+            tag.set_total_tracks(tracks);
+        }
         if let Some(date) = self.date {
-            tag.set_year(date.replace("-", ""));
+            tag.set_year(date.to_string());
+        }
+        if let Some(date) = self.original_date {
+            tag.set_data(ORIGINAL_DATE, mp4ameta::Data::Utf8(date.to_string()));
         }
         if !self.genres.is_empty() {
             tag.set_genres(self.genres);
@@ -90,9 +248,24 @@ impl Metadata {
         if let Some(lyrics) = self.lyrics {
             tag.set_lyrics(lyrics);
         }
+        if let Some(bpm) = self.bpm {
+            tag.set_bpm(bpm);
+        }
         if let Some(isrc) = self.isrc {
             tag.set_isrc(isrc);
         }
+        if let Some(label) = self.label {
+            tag.set_data(ident::LABEL, mp4ameta::Data::Utf8(label));
+        }
+        if let Some(catalog) = self.catalog {
+            tag.set_data(CATALOG_NUMBER, mp4ameta::Data::Utf8(catalog));
+        }
+        if let Some(barcode) = self.barcode {
+            tag.set_data(BARCODE, mp4ameta::Data::Utf8(barcode));
+        }
+        if let Some(mbid) = self.mbid {
+            tag.set_data(MUSICBRAINZ_TRACK_ID, mp4ameta::Data::Utf8(mbid));
+        }
     }
 
     pub(crate) fn write(self, tag: &mut mp4ameta::Tag) {
@@ -111,11 +284,32 @@ impl Metadata {
         } else {
             tag.remove_album();
         }
-        if let Some(date) = self.date {
-            tag.set_year(date.replace("-", ""));
+        if !self.album_artists.is_empty() {
+            tag.set_album_artists(self.album_artists);
+        } else {
+            tag.remove_album_artists();
+        }
+        if let Some(track) = self.track {
+            tag.set_track_number(track);
+        } else {
+            tag.remove_track_number();
+        }
+        if let Some(tracks) = self.tracks {
+            tag.set_total_tracks(tracks);
         } else {
+            tag.remove_total_tracks();
+        }
+        // dates which do not parse were never shown as set, so they are kept rather than lost
+        if let Some(date) = self.date {
+            tag.set_year(date.to_string());
+        } else if tag.year().is_none_or(|d| d.parse::<Date>().is_ok()) {
             tag.remove_year();
         }
+        if let Some(date) = self.original_date {
+            tag.set_data(ORIGINAL_DATE, mp4ameta::Data::Utf8(date.to_string()));
+        } else if freeform(tag, &ORIGINAL_DATE).is_none_or(|d| d.parse::<Date>().is_ok()) {
+            tag.remove_data_of(&ORIGINAL_DATE);
+        }
         if !self.genres.is_empty() {
             tag.set_genres(self.genres);
         } else {
@@ -126,11 +320,36 @@ impl Metadata {
         } else {
             tag.remove_lyrics();
         }
+        if let Some(bpm) = self.bpm {
+            tag.set_bpm(bpm);
+        } else {
+            tag.remove_bpm();
+        }
         if let Some(isrc) = self.isrc {
             tag.set_isrc(isrc);
         } else {
             tag.remove_isrc();
         }
+        if let Some(label) = self.label {
+            tag.set_data(ident::LABEL, mp4ameta::Data::Utf8(label));
+        } else {
+            tag.remove_data_of(&ident::LABEL);
+        }
+        if let Some(catalog) = self.catalog {
+            tag.set_data(CATALOG_NUMBER, mp4ameta::Data::Utf8(catalog));
+        } else {
+            tag.remove_data_of(&CATALOG_NUMBER);
+        }
+        if let Some(barcode) = self.barcode {
+            tag.set_data(BARCODE, mp4ameta::Data::Utf8(barcode));
+        } else {
+            tag.remove_data_of(&BARCODE);
+        }
+        if let Some(mbid) = self.mbid {
+            tag.set_data(MUSICBRAINZ_TRACK_ID, mp4ameta::Data::Utf8(mbid));
+        } else {
+            tag.remove_data_of(&MUSICBRAINZ_TRACK_ID);
+        }
     }
 }
 
@@ -145,17 +364,80 @@ impl std::ops::BitOrAssign for Metadata {
         if rhs.album.is_some() {
             self.album = rhs.album;
         }
+        if !rhs.album_artists.is_empty() {
+            self.album_artists = rhs.album_artists;
+        }
+        if rhs.track.is_some() {
+            self.track = rhs.track;
+        }
+        if rhs.tracks.is_some() {
+            self.tracks = rhs.tracks;
+        }
         if rhs.date.is_some() {
             self.date = rhs.date;
         }
+        if rhs.original_date.is_some() {
+            self.original_date = rhs.original_date;
+        }
         if !rhs.genres.is_empty() {
             self.genres = rhs.genres;
         }
         if rhs.lyrics.is_some() {
             self.lyrics = rhs.lyrics;
         }
+        if rhs.bpm.is_some() {
+            self.bpm = rhs.bpm;
+        }
         if rhs.isrc.is_some() {
             self.isrc = rhs.isrc;
         }
+        if rhs.label.is_some() {
+            self.label = rhs.label;
+        }
+        if rhs.catalog.is_some() {
+            self.catalog = rhs.catalog;
+        }
+        if rhs.barcode.is_some() {
+            self.barcode = rhs.barcode;
+        }
+        if rhs.mbid.is_some() {
+            self.mbid = rhs.mbid;
+        }
+        if rhs.artwork.is_some() {
+            self.artwork = rhs.artwork;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unparsed_date() {
+        let mut tag = mp4ameta::Tag::default();
+        tag.set_year("Spring 1997");
+        tag.set_data(ORIGINAL_DATE, mp4ameta::Data::Utf8("1997?".to_string()));
+        let meta = Metadata::from(tag.clone());
+        assert!(meta.date.is_none());
+        meta.write(&mut tag);
+        assert_eq!(tag.year(), Some("Spring 1997"));
+        assert_eq!(freeform(&tag, &ORIGINAL_DATE).as_deref(), Some("1997?"));
+
+        tag.set_year("1997-01-20");
+        Metadata::default().write(&mut tag);
+        assert_eq!(tag.year(), None);
+    }
+
+    #[test]
+    fn etag() {
+        let mut tag = mp4ameta::Tag::default();
+        tag.set_title("Digital Love");
+        let etag = Metadata::from(tag.clone()).etag();
+        tag.set_artwork(mp4ameta::Img::png(b"\x89PNG".to_vec()));
+        let artwork = Metadata::from(tag.clone()).etag();
+        assert_ne!(etag, artwork);
+        tag.set_artwork(mp4ameta::Img::png(b"\x89PNG!".to_vec()));
+        assert_ne!(Metadata::from(tag).etag(), artwork);
     }
 }
@@ -6,11 +6,11 @@ use std::{
         atomic::{AtomicBool, Ordering},
     },
 };
-use wasm_bindgen::JsCast;
+use wasm_bindgen::{JsCast, JsValue};
 use wasm_bindgen_futures::spawn_local;
 use web_sys::{
     console::{info_1, info_2},
-    js_sys::{Array, JsString},
+    js_sys::{Array, Reflect, encode_uri_component},
 };
 
 pub struct App {
@@ -58,11 +58,36 @@ impl App {
     fn get_tracks(&mut self) {
         let tracks = self.tracks.clone();
         spawn_local(async move {
-            match request("/tracks", "GET", None).await {
-                Ok(resp) => {
+            // every page of track summaries, each with its ID, following the cursor to the end
+            let mut pages = Vec::new();
+            let mut cursor: Option<String> = None;
+            let listing = loop {
+                let url = match &cursor {
+                    Some(cursor) => format!("/tracks?cursor={}", encode_uri_component(cursor)),
+                    None => "/tracks".to_string(),
+                };
+                let page = match request(&url, "GET", None).await {
+                    Ok(page) => page,
+                    Err(e) => break Err(e),
+                };
+                pages.push(page.clone());
+                cursor = Reflect::get(&page, &JsValue::from_str("cursor"))
+                    .ok()
+                    .and_then(|c| c.as_string());
+                if cursor.is_none() {
+                    break Ok(pages);
+                }
+            };
+            match listing {
+                Ok(pages) => {
                     let mut tracks = tracks.write().unwrap();
 
-                    let resp: Array<JsString> = resp.unchecked_into();
+                    let resp = pages.iter().flat_map(|page| {
+                        let page: Array<JsValue> = Reflect::get(page, &JsValue::from_str("tracks"))
+                            .unwrap_or_default()
+                            .unchecked_into();
+                        page.into_iter().collect::<Vec<_>>()
+                    });
 
                     let image = image::load_from_memory_with_format(
                         include_bytes!("assets/album.png"),
@@ -73,9 +98,15 @@ impl App {
                     .into_rgba8();
 
                     tracks.clear();
-                    for (i, id) in resp.into_iter().enumerate() {
+                    for (i, track) in resp.into_iter().enumerate() {
+                        let Some(id) = Reflect::get(&track, &JsValue::from_str("id"))
+                            .ok()
+                            .and_then(|id| id.as_string())
+                        else {
+                            continue;
+                        };
                         tracks.push(Track {
-                            id: String::from(id),
+                            id,
                             element: ui::Element {
                                 shape: ui::Trapezoid::from_square(
                                     -0.84375 + 0.15 * (i as f32),
@@ -17,6 +17,11 @@ pub async fn request(url: &str, method: &str, body: Option<&str>) -> Result<JsVa
     debug_assert!(resp_value.is_instance_of::<Response>());
     let resp: Response = resp_value.dyn_into().unwrap();
 
+    // the session expired or was never started
+    if resp.status() == 401 {
+        window.location().set_href("/login")?;
+    }
+
     // Convert this other `Promise` into a rust `Future`.
     let json = JsFuture::from(resp.json()?).await?;
 